@@ -0,0 +1,218 @@
+use crate::geom::*;
+
+// Skeletal animation, done on the CPU: a clip is sampled into a pose (one
+// local transform per joint), poses can be blended together, and the pose
+// is flattened into the joint matrices the skinning shader wants.
+
+/// Most joints one skinned draw can use; has to match MAX_JOINTS in
+/// shader.vert and shadow.vert
+pub const MAX_JOINTS: usize = 64;
+
+/// A joint's transform relative to its parent
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: Vec3::zero(),
+            rotation: Quat::one(),
+            scale: Vec3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+impl Transform {
+    pub fn to_mat4(&self) -> Mat4 {
+        Mat4::from_translation(self.translation)
+            * Mat4::from(self.rotation)
+            * Mat4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+    /// `t` of the way from `self` to `other`
+    pub fn blend(&self, other: &Transform, t: f32) -> Transform {
+        Transform {
+            translation: self.translation.lerp(other.translation, t),
+            rotation: self.rotation.slerp(other.rotation, t),
+            scale: self.scale.lerp(other.scale, t),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Joint {
+    pub name: String,
+    // Index into the skeleton's joints
+    pub parent: Option<usize>,
+    // Takes a vertex from model space into the joint's space, as it was
+    // when the mesh was bound to the skeleton
+    pub inverse_bind: Mat4,
+    pub rest: Transform,
+}
+
+#[derive(Clone, Debug)]
+pub struct Skeleton {
+    pub joints: Vec<Joint>,
+    // Whatever sits above the root joints, which isn't animated
+    pub root: Mat4,
+    // Joint indices with parents before children
+    order: Vec<usize>,
+}
+
+impl Skeleton {
+    pub fn new(joints: Vec<Joint>, root: Mat4) -> Self {
+        let mut order = Vec::with_capacity(joints.len());
+        let mut placed = vec![false; joints.len()];
+        // Each sweep places at least one more joint, unless the parents
+        // loop around, in which case the rest are treated as roots
+        while order.len() < joints.len() {
+            let before = order.len();
+            for (i, j) in joints.iter().enumerate() {
+                let ready = match j.parent {
+                    Some(p) => placed[p],
+                    None => true,
+                };
+                if !placed[i] && ready {
+                    placed[i] = true;
+                    order.push(i);
+                }
+            }
+            if order.len() == before {
+                order.extend((0..joints.len()).filter(|&i| !placed[i]));
+                break;
+            }
+        }
+        Self {
+            joints,
+            root,
+            order,
+        }
+    }
+    pub fn joint_named(&self, name: &str) -> Option<usize> {
+        self.joints.iter().position(|j| j.name == name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    Step,
+    Linear,
+}
+
+#[derive(Clone, Debug)]
+pub enum Keyframes {
+    Translation(Vec<Vec3>),
+    Rotation(Vec<Quat>),
+    Scale(Vec<Vec3>),
+}
+
+/// Keyframes for one property of one joint
+#[derive(Clone, Debug)]
+pub struct Channel {
+    pub joint: usize,
+    pub interpolation: Interpolation,
+    // Increasing, one per key
+    pub times: Vec<f32>,
+    pub keys: Keyframes,
+}
+
+impl Channel {
+    // The keys either side of `time`, and how far between them it is
+    fn locate(&self, time: f32) -> (usize, usize, f32) {
+        let last = self.times.len() - 1;
+        let next = self.times.partition_point(|&t| t <= time);
+        if next == 0 {
+            return (0, 0, 0.0);
+        }
+        if next > last {
+            return (last, last, 0.0);
+        }
+        let (t0, t1) = (self.times[next - 1], self.times[next]);
+        let t = match self.interpolation {
+            Interpolation::Step => 0.0,
+            Interpolation::Linear => (time - t0) / (t1 - t0),
+        };
+        (next - 1, next, t)
+    }
+
+    fn apply(&self, time: f32, to: &mut Transform) {
+        if self.times.is_empty() {
+            return;
+        }
+        let (a, b, t) = self.locate(time);
+        match &self.keys {
+            Keyframes::Translation(ks) => to.translation = ks[a].lerp(ks[b], t),
+            Keyframes::Rotation(ks) => to.rotation = ks[a].slerp(ks[b], t),
+            Keyframes::Scale(ks) => to.scale = ks[a].lerp(ks[b], t),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Clip {
+    pub name: String,
+    // Seconds; the time of the last key
+    pub duration: f32,
+    pub channels: Vec<Channel>,
+}
+
+/// Every joint's local transform at some moment
+#[derive(Clone, Debug)]
+pub struct Pose {
+    pub joints: Vec<Transform>,
+}
+
+impl Pose {
+    pub fn rest(skeleton: &Skeleton) -> Self {
+        Self {
+            joints: skeleton.joints.iter().map(|j| j.rest).collect(),
+        }
+    }
+    /// `clip` at `time` seconds in, wrapping around if `looping` and holding
+    /// the last frame otherwise.  Joints the clip doesn't move stay at rest.
+    pub fn sample(skeleton: &Skeleton, clip: &Clip, time: f32, looping: bool) -> Self {
+        let time = if looping && clip.duration > 0.0 {
+            time.rem_euclid(clip.duration)
+        } else {
+            time.min(clip.duration)
+        };
+        let mut pose = Self::rest(skeleton);
+        for ch in clip.channels.iter() {
+            if let Some(j) = pose.joints.get_mut(ch.joint) {
+                ch.apply(time, j);
+            }
+        }
+        pose
+    }
+    /// `t` of the way from `self` to `other`, joint by joint
+    pub fn blend(&self, other: &Pose, t: f32) -> Pose {
+        Pose {
+            joints: self
+                .joints
+                .iter()
+                .zip(other.joints.iter())
+                .map(|(a, b)| a.blend(b, t))
+                .collect(),
+        }
+    }
+    /// What to hand to `InstanceGroups::render_skinned`: for each joint,
+    /// bind pose model space to posed model space
+    pub fn joint_matrices(&self, skeleton: &Skeleton) -> Vec<Mat4> {
+        let mut world = vec![Mat4::identity(); skeleton.joints.len()];
+        for &i in skeleton.order.iter() {
+            let parent = match skeleton.joints[i].parent {
+                Some(p) => world[p],
+                None => skeleton.root,
+            };
+            world[i] = parent * self.joints[i].to_mat4();
+        }
+        world
+            .iter()
+            .zip(skeleton.joints.iter())
+            .map(|(w, j)| w * j.inverse_bind)
+            .collect()
+    }
+}
@@ -10,6 +10,9 @@ pub struct Assets {
     asset_root: PathBuf,
     models: HashMap<ModelRef, Model>,
     model_refs: HashMap<PathBuf, ModelRef>,
+    // The models built from each other file (buffers, MTL files, textures),
+    // relative to the asset root, so editing one reloads them
+    dependents: HashMap<PathBuf, Vec<ModelRef>>,
     rx: Receiver<notify::DebouncedEvent>,
 }
 impl Assets {
@@ -30,22 +33,70 @@ impl Assets {
             asset_root: asset_root.as_ref().to_owned(),
             models: HashMap::new(),
             model_refs: HashMap::new(),
+            dependents: HashMap::new(),
             rx,
         }
     }
+    pub fn asset_root(&self) -> &Path {
+        &self.asset_root
+    }
+    // Picks a loader by file extension
+    fn load_file(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        path: &Path,
+    ) -> anyhow::Result<Model> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gltf") | Some("glb") => Model::load_gltf(device, queue, layout, path),
+            _ => Model::load(device, queue, layout, path),
+        }
+    }
+    // Every other file the model at `path` is built from
+    fn dependencies(path: &Path) -> Vec<PathBuf> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gltf") | Some("glb") => Model::gltf_dependencies(path),
+            _ => Model::obj_dependencies(path),
+        }
+    }
+    // Where a watched file is relative to the asset root, or None if it's
+    // outside it.  Events come with absolute paths.
+    fn relative_path(&self, p: &Path) -> Option<PathBuf> {
+        let cwd = std::env::current_dir().ok()?;
+        let root = self
+            .asset_root
+            .strip_prefix(&cwd)
+            .unwrap_or(&self.asset_root);
+        let p = p.strip_prefix(&cwd).unwrap_or(p);
+        p.strip_prefix(root).ok().map(Path::to_path_buf)
+    }
+    fn track_dependencies(&mut self, mref: ModelRef, path: &Path) {
+        for dep in Self::dependencies(&self.asset_root.join(path)) {
+            if let Some(dep) = self.relative_path(&dep) {
+                let mrefs = self.dependents.entry(dep).or_default();
+                if !mrefs.contains(&mref) {
+                    mrefs.push(mref);
+                }
+            }
+        }
+    }
     fn update_model(
         &mut self,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         layout: &wgpu::BindGroupLayout,
-        p: PathBuf,
+        mref: ModelRef,
     ) {
-        let p = p.strip_prefix(std::env::current_dir().unwrap()).unwrap();
-        let p = p.strip_prefix(&self.asset_root).unwrap();
-        if let Some(mref) = self.model_refs.get(p) {
-            self.models
-                .insert(*mref, Model::load(device, queue, layout, &p).unwrap());
-        };
+        let path = self.path_for_model_ref(mref).to_owned();
+        // Models handed over with `insert_model` have no file to reload from
+        if !self.models.contains_key(&mref) || !self.asset_root.join(&path).is_file() {
+            return;
+        }
+        self.models.insert(
+            mref,
+            Self::load_file(device, queue, layout, &self.asset_root.join(&path)).unwrap(),
+        );
+        self.track_dependencies(mref, &path);
     }
     pub fn check_events(
         &mut self,
@@ -60,16 +111,19 @@ impl Assets {
                     DebouncedEvent::NoticeWrite(path)
                     | DebouncedEvent::Write(path)
                     | DebouncedEvent::Create(path) => {
-                        match path.extension().map(|s| s.to_str().unwrap()) {
-                            Some("obj") => self.update_model(device, queue, layout, path),
-                            Some("png") | Some("jpg") | Some("mtl") => self.update_model(
-                                device,
-                                queue,
-                                layout,
-                                Path::new(path.file_stem().unwrap().to_str().unwrap())
-                                    .with_extension("obj"),
-                            ),
-                            _ => {}
+                        let path = match self.relative_path(&path) {
+                            Some(path) => path,
+                            None => continue,
+                        };
+                        // The file itself if it's a model, and any models
+                        // that use it
+                        let mut mrefs: Vec<ModelRef> =
+                            self.model_refs.get(&path).copied().into_iter().collect();
+                        if let Some(dependents) = self.dependents.get(&path) {
+                            mrefs.extend(dependents.iter().copied());
+                        }
+                        for mref in mrefs {
+                            self.update_model(device, queue, layout, mref);
                         }
                     }
                     _ => {}
@@ -87,10 +141,19 @@ impl Assets {
         model: impl AsRef<Path>,
     ) -> ModelRef {
         let mref = self.model_ref_for(&model);
-        let ar = &self.asset_root;
-        self.models
-            .entry(mref)
-            .or_insert_with(|| Model::load(device, queue, layout, ar.join(&model)).unwrap());
+        if !self.models.contains_key(&mref) {
+            let path = self.asset_root.join(&model);
+            self.models
+                .insert(mref, Self::load_file(device, queue, layout, &path).unwrap());
+            self.track_dependencies(mref, model.as_ref());
+        }
+        mref
+    }
+    /// Hand over a model built in code.  `name` stands in for its path, so
+    /// it should be unique, and the model won't be hot reloaded.
+    pub fn insert_model(&mut self, name: impl AsRef<Path>, model: Model) -> ModelRef {
+        let mref = self.model_ref_for(name);
+        self.models.insert(mref, model);
         mref
     }
     pub fn model_ref_for(&mut self, p: impl AsRef<Path>) -> ModelRef {
@@ -107,4 +170,7 @@ impl Assets {
     pub fn get_model(&self, model: ModelRef) -> Option<&Model> {
         self.models.get(&model)
     }
+    pub fn get_model_mut(&mut self, model: ModelRef) -> Option<&mut Model> {
+        self.models.get_mut(&model)
+    }
 }
@@ -0,0 +1,53 @@
+#version 450
+
+// Halves the image with a 13 tap filter, which keeps bright specks from
+// flickering.  The first pass also cuts out everything below the threshold.
+
+layout(location=0) in vec2 v_uv;
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_source;
+layout(set=0, binding=1) uniform sampler s_source;
+layout(set=0, binding=2)
+uniform PostPass {
+    // threshold (0 for none), soft knee, unused, unused
+    vec4 u_params;
+    // source width, height, 1/width, 1/height
+    vec4 u_size;
+};
+
+vec3 tap(float x, float y) {
+    return texture(sampler2D(t_source, s_source), v_uv + vec2(x, y) * u_size.zw).rgb;
+}
+
+void main() {
+    vec3 a = tap(-2.0, -2.0);
+    vec3 b = tap(0.0, -2.0);
+    vec3 c = tap(2.0, -2.0);
+    vec3 d = tap(-1.0, -1.0);
+    vec3 e = tap(1.0, -1.0);
+    vec3 f = tap(-2.0, 0.0);
+    vec3 g = tap(0.0, 0.0);
+    vec3 h = tap(2.0, 0.0);
+    vec3 i = tap(-1.0, 1.0);
+    vec3 j = tap(1.0, 1.0);
+    vec3 k = tap(-2.0, 2.0);
+    vec3 l = tap(0.0, 2.0);
+    vec3 m = tap(2.0, 2.0);
+    // Five overlapping boxes: the middle one counts for half
+    vec3 color = (d + e + i + j) * 0.125
+        + (a + c + k + m) * 0.03125
+        + (b + f + h + l) * 0.0625
+        + g * 0.125;
+
+    float threshold = u_params.x;
+    if (threshold > 0.0) {
+        float brightness = max(color.r, max(color.g, color.b));
+        float knee = threshold * u_params.y;
+        float soft = clamp(brightness - threshold + knee, 0.0, 2.0 * knee);
+        soft = soft * soft / (4.0 * knee + 0.00001);
+        color *= max(soft, brightness - threshold) / max(brightness, 0.00001);
+    }
+    // Half floats top out around 65000, and one stray inf would spread
+    f_color = vec4(min(color, vec3(60000.0)), 1.0);
+}
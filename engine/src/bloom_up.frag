@@ -0,0 +1,29 @@
+#version 450
+
+// Doubles the image with a 3x3 tent filter; blended additively onto the
+// next level up.
+
+layout(location=0) in vec2 v_uv;
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_source;
+layout(set=0, binding=1) uniform sampler s_source;
+layout(set=0, binding=2)
+uniform PostPass {
+    // radius in source texels, unused x3
+    vec4 u_params;
+    // source width, height, 1/width, 1/height
+    vec4 u_size;
+};
+
+vec3 tap(float x, float y) {
+    vec2 offset = vec2(x, y) * u_params.x * u_size.zw;
+    return texture(sampler2D(t_source, s_source), v_uv + offset).rgb;
+}
+
+void main() {
+    vec3 color = tap(0.0, 0.0) * 4.0
+        + (tap(-1.0, 0.0) + tap(1.0, 0.0) + tap(0.0, -1.0) + tap(0.0, 1.0)) * 2.0
+        + tap(-1.0, -1.0) + tap(1.0, -1.0) + tap(-1.0, 1.0) + tap(1.0, 1.0);
+    f_color = vec4(color / 16.0, 1.0);
+}
@@ -14,4 +14,9 @@ impl Camera {
         let proj = cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar);
         (view, proj)
     }
+    /// What the camera can see, for culling
+    pub fn frustum(&self) -> crate::geom::Frustum {
+        let (view, proj) = self.build_view_projection_matrix();
+        crate::geom::Frustum::from_matrix(proj * view)
+    }
 }
@@ -0,0 +1,77 @@
+use std::path::{Path, PathBuf};
+
+// Frames waiting to be written out.  `Engine` asks whether the next frame
+// should be read back, then hands over the image.
+#[derive(Default)]
+pub(crate) struct Capture {
+    screenshot: Option<PathBuf>,
+    recording: Option<Recording>,
+}
+
+struct Recording {
+    dir: PathBuf,
+    every: usize,
+    // Frames rendered since we started, and PNGs written
+    frames: usize,
+    saved: usize,
+}
+
+impl Capture {
+    pub(crate) fn screenshot(&mut self, path: impl AsRef<Path>) {
+        self.screenshot = Some(path.as_ref().to_owned());
+    }
+
+    pub(crate) fn start_recording(&mut self, dir: impl AsRef<Path>, every: usize) {
+        self.recording = Some(Recording {
+            dir: dir.as_ref().to_owned(),
+            every: every.max(1),
+            frames: 0,
+            saved: 0,
+        });
+    }
+
+    pub(crate) fn stop_recording(&mut self) {
+        self.recording = None;
+    }
+
+    pub(crate) fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    // Call once per rendered frame, before rendering it
+    pub(crate) fn wants_frame(&mut self) -> bool {
+        let recording = match &mut self.recording {
+            Some(r) => {
+                let wanted = r.frames % r.every == 0;
+                r.frames += 1;
+                wanted
+            }
+            None => false,
+        };
+        recording || self.screenshot.is_some()
+    }
+
+    pub(crate) fn save(&mut self, img: &image::RgbaImage) {
+        if let Some(path) = self.screenshot.take() {
+            if let Err(e) = img.save(&path) {
+                eprintln!("Couldn't save screenshot to {:?}: {}", path, e);
+            }
+        }
+        // Only record frames we asked for, not ones that were read back
+        // just for a screenshot
+        if let Some(r) = &mut self.recording {
+            if (r.frames - 1) % r.every != 0 {
+                return;
+            }
+            if let Err(e) = std::fs::create_dir_all(&r.dir) {
+                eprintln!("Couldn't create {:?}: {}", r.dir, e);
+                return;
+            }
+            let path = r.dir.join(format!("frame_{:06}.png", r.saved));
+            match img.save(&path) {
+                Ok(()) => r.saved += 1,
+                Err(e) => eprintln!("Couldn't save frame to {:?}: {}", path, e),
+            }
+        }
+    }
+}
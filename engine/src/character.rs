@@ -0,0 +1,398 @@
+use crate::geom::*;
+use crate::DT;
+
+// How far a single substep may move, so fast characters can't tunnel
+const MAX_SUBSTEP: f32 = 0.1;
+// How many times per substep we'll pop the body out of something
+const MAX_PUSHES: usize = 4;
+// How far below the body we look for ground to stick to
+const GROUND_SNAP: f32 = 0.05;
+// Shape casts are done by bisection, to about step_height / 2^CAST_ITERS
+const CAST_ITERS: usize = 10;
+const CAST_EPSILON: f32 = 0.0001;
+
+/// Shapes the controller knows how to move around
+pub trait CharacterBody: Shape + Copy {
+    fn center(&self) -> Pos3;
+}
+
+impl CharacterBody for Sphere {
+    fn center(&self) -> Pos3 {
+        self.c
+    }
+}
+
+impl CharacterBody for Capsule {
+    fn center(&self) -> Pos3 {
+        self.a.midpoint(self.b)
+    }
+}
+
+/// Something the character can't move through
+pub trait Obstacle<B> {
+    /// How far `body` would need to move to get out of this obstacle
+    fn push_out(&self, body: &B) -> Option<Vec3>;
+}
+
+// Planes and terrain already report the push for the moving shape...
+impl Obstacle<Sphere> for Plane {
+    fn push_out(&self, body: &Sphere) -> Option<Vec3> {
+        body.disp(self)
+    }
+}
+impl Obstacle<Capsule> for Plane {
+    fn push_out(&self, body: &Capsule) -> Option<Vec3> {
+        body.disp(self)
+    }
+}
+
+impl Obstacle<Sphere> for crate::terrain::Heightfield {
+    fn push_out(&self, body: &Sphere) -> Option<Vec3> {
+        body.disp(self)
+    }
+}
+impl Obstacle<Capsule> for crate::terrain::Heightfield {
+    fn push_out(&self, body: &Capsule) -> Option<Vec3> {
+        body.disp(self)
+    }
+}
+
+// ...but everything else reports it for the other shape, so flip it.
+macro_rules! obstacle_via_disp {
+    ($($b:ty => $($s:ty),+);+ $(;)?) => {
+        $($(
+            impl Obstacle<$b> for $s {
+                fn push_out(&self, body: &$b) -> Option<Vec3> {
+                    body.disp(self).map(|v| -v)
+                }
+            }
+        )+)+
+    };
+}
+
+obstacle_via_disp! {
+    Sphere => Sphere, Box, AABB, Capsule, ConvexHull;
+    Capsule => Sphere, Box, AABB, Capsule, ConvexHull;
+}
+
+/// A collection of obstacles.  Slices and Vecs of one shape work, and a
+/// tuple mixes several, e.g. `(&planes[..], &hulls)`.
+pub trait Obstacles<B> {
+    fn push_outs(&self, body: &B, into: &mut Vec<Vec3>);
+}
+
+impl<B, S: Obstacle<B>> Obstacles<B> for [S] {
+    fn push_outs(&self, body: &B, into: &mut Vec<Vec3>) {
+        into.extend(self.iter().filter_map(|s| s.push_out(body)));
+    }
+}
+
+impl<B, S: Obstacle<B>> Obstacles<B> for Vec<S> {
+    fn push_outs(&self, body: &B, into: &mut Vec<Vec3>) {
+        self[..].push_outs(body, into);
+    }
+}
+
+// There's usually just the one terrain, so it can be passed on its own
+impl<B> Obstacles<B> for crate::terrain::Heightfield
+where
+    crate::terrain::Heightfield: Obstacle<B>,
+{
+    fn push_outs(&self, body: &B, into: &mut Vec<Vec3>) {
+        into.extend(self.push_out(body));
+    }
+}
+
+impl<B, O: Obstacles<B> + ?Sized> Obstacles<B> for &O {
+    fn push_outs(&self, body: &B, into: &mut Vec<Vec3>) {
+        (**self).push_outs(body, into);
+    }
+}
+
+impl<B, O1: Obstacles<B>, O2: Obstacles<B>> Obstacles<B> for (O1, O2) {
+    fn push_outs(&self, body: &B, into: &mut Vec<Vec3>) {
+        self.0.push_outs(body, into);
+        self.1.push_outs(body, into);
+    }
+}
+
+impl<B, O1: Obstacles<B>, O2: Obstacles<B>, O3: Obstacles<B>> Obstacles<B> for (O1, O2, O3) {
+    fn push_outs(&self, body: &B, into: &mut Vec<Vec3>) {
+        self.0.push_outs(body, into);
+        self.1.push_outs(body, into);
+        self.2.push_outs(body, into);
+    }
+}
+
+// What a move ran into
+#[derive(Default)]
+struct Hits {
+    ground: Option<Vec3>,
+    wall: bool,
+}
+
+/// Moves a sphere or capsule around without any physics: it slides along
+/// walls, walks up steps and gentle slopes, and sticks to the ground.
+/// Call `update` once per `Game::update`.
+pub struct CharacterController {
+    pub velocity: Vec3,
+    pub gravity: f32,
+    pub jump_speed: f32,
+    // Steepest walkable slope, in degrees
+    pub slope_limit: f32,
+    // Tallest ledge we'll walk up without jumping
+    pub step_height: f32,
+    // How many frames an early jump press is remembered for
+    pub jump_buffer: usize,
+    grounded: bool,
+    ground_normal: Vec3,
+    jump_frames: usize,
+    pushes: Vec<Vec3>,
+}
+
+impl CharacterController {
+    pub fn new(jump_speed: f32, gravity: f32) -> Self {
+        Self {
+            velocity: Vec3::zero(),
+            gravity,
+            jump_speed,
+            slope_limit: 45.0,
+            step_height: 0.3,
+            jump_buffer: 6,
+            grounded: false,
+            ground_normal: Vec3::unit_y(),
+            jump_frames: 0,
+            pushes: vec![],
+        }
+    }
+
+    pub fn grounded(&self) -> bool {
+        self.grounded
+    }
+
+    pub fn ground_normal(&self) -> Option<Vec3> {
+        if self.grounded {
+            Some(self.ground_normal)
+        } else {
+            None
+        }
+    }
+
+    /// Ask to jump.  If we're in the air, the jump happens on landing as
+    /// long as that's within `jump_buffer` frames.
+    pub fn jump(&mut self) {
+        self.jump_frames = self.jump_buffer.max(1);
+    }
+
+    fn walkable(&self, n: Vec3) -> bool {
+        n.y >= self.slope_limit.to_radians().cos()
+    }
+
+    // Pop the body out of whatever it's in, deepest first, trimming the
+    // velocity so we don't keep pushing into the same surface.
+    fn depenetrate<B: CharacterBody, O: Obstacles<B> + ?Sized>(
+        &mut self,
+        body: &mut B,
+        world: &O,
+        vel: &mut Vec3,
+        hits: &mut Hits,
+    ) {
+        for _ in 0..MAX_PUSHES {
+            self.pushes.clear();
+            world.push_outs(body, &mut self.pushes);
+            let push = self
+                .pushes
+                .iter()
+                .copied()
+                .filter(|p| p.magnitude2() > 0.0)
+                .max_by(|a, b| a.magnitude2().partial_cmp(&b.magnitude2()).unwrap());
+            let push = match push {
+                Some(push) => push,
+                None => break,
+            };
+            body.translate(push);
+            let n = push.normalize();
+            if self.walkable(n) {
+                hits.ground = Some(n);
+            } else {
+                hits.wall = true;
+            }
+            // Too steep to stand on means it's a wall, so don't let it
+            // shove us upwards either
+            let n = if n.y > 0.0 && !self.walkable(n) {
+                let flat = Vec3::new(n.x, 0.0, n.z);
+                if flat.magnitude2() > 0.0 {
+                    flat.normalize()
+                } else {
+                    n
+                }
+            } else {
+                n
+            };
+            let into = vel.dot(n);
+            if into < 0.0 {
+                *vel -= n * into;
+            }
+        }
+    }
+
+    fn sweep<B: CharacterBody, O: Obstacles<B> + ?Sized>(
+        &mut self,
+        body: &mut B,
+        world: &O,
+        motion: Vec3,
+        vel: &mut Vec3,
+    ) -> Hits {
+        let mut hits = Hits::default();
+        let steps = (motion.magnitude() / MAX_SUBSTEP).ceil().max(1.0) as usize;
+        let mut step = motion / steps as f32;
+        for _ in 0..steps {
+            body.translate(step);
+            let before = *vel;
+            self.depenetrate(body, world, vel, &mut hits);
+            // Keep the rest of this move consistent with any sliding we did
+            if *vel != before {
+                let scale = if before.magnitude2() > 0.0 {
+                    step.magnitude() / before.magnitude()
+                } else {
+                    0.0
+                };
+                step = *vel * scale;
+            }
+        }
+        hits
+    }
+
+    // Move straight up or down until something blocks the way, like a
+    // shape cast.  Returns the normal of whatever we stopped on.
+    fn cast_vertical<B: CharacterBody, O: Obstacles<B> + ?Sized>(
+        &mut self,
+        body: &mut B,
+        world: &O,
+        dy: f32,
+    ) -> Option<Vec3> {
+        // Only things facing against the move can stop it; walls we're
+        // brushing against don't count.
+        let blocker = |b: &B, pushes: &mut Vec<Vec3>| {
+            pushes.clear();
+            world.push_outs(b, pushes);
+            pushes
+                .iter()
+                .copied()
+                .filter(|p| p.magnitude2() > CAST_EPSILON * CAST_EPSILON && p.y * dy < 0.0)
+                .max_by(|a, b| a.magnitude2().partial_cmp(&b.magnitude2()).unwrap())
+        };
+        let moved = |t: f32| {
+            let mut b = *body;
+            b.translate(Vec3::unit_y() * (dy * t));
+            b
+        };
+        let hit = blocker(&moved(1.0), &mut self.pushes)?;
+        // Bisect for the furthest we can go without being blocked
+        let (mut lo, mut hi, mut normal) = (0.0, 1.0, hit.normalize());
+        for _ in 0..CAST_ITERS {
+            let mid = (lo + hi) / 2.0;
+            match blocker(&moved(mid), &mut self.pushes) {
+                Some(p) => {
+                    hi = mid;
+                    normal = p.normalize();
+                }
+                None => lo = mid,
+            }
+        }
+        *body = moved(lo);
+        Some(normal)
+    }
+
+    // Lift up, move across, and drop back down onto whatever is there.
+    // Only worth keeping if we land on something further along than
+    // before.
+    fn try_step_up<B: CharacterBody, O: Obstacles<B> + ?Sized>(
+        &mut self,
+        start: B,
+        world: &O,
+        motion: Vec3,
+    ) -> Option<B> {
+        let mut body = start;
+        let lifted = match self.cast_vertical(&mut body, world, self.step_height) {
+            Some(_) => body.center().y - start.center().y,
+            None => {
+                body.translate(Vec3::unit_y() * self.step_height);
+                self.step_height
+            }
+        };
+        let across_motion = Vec3::new(motion.x, 0.0, motion.z);
+        let mut vel = across_motion;
+        let across = self.sweep(&mut body, world, across_motion, &mut vel);
+        if across.wall {
+            return None;
+        }
+        // The edge of a step looks like a steep slope from up close, so
+        // anything facing upwards is fine to land on here.
+        self.cast_vertical(&mut body, world, -lifted)
+            .filter(|n| n.y > 0.0)
+            .map(|_| body)
+    }
+
+    /// Move `body` through `world`, trying to go at `wish` (only its
+    /// horizontal part is used).  Returns whether we jumped this frame.
+    pub fn update<B: CharacterBody, O: Obstacles<B> + ?Sized>(
+        &mut self,
+        body: &mut B,
+        wish: Vec3,
+        world: &O,
+    ) -> bool {
+        let mut jumped = false;
+        let was_grounded = self.grounded;
+        if self.jump_frames > 0 {
+            if self.grounded {
+                self.velocity.y = self.jump_speed;
+                self.grounded = false;
+                self.jump_frames = 0;
+                jumped = true;
+            } else {
+                self.jump_frames -= 1;
+            }
+        }
+
+        let wish = Vec3::new(wish.x, 0.0, wish.z);
+        let mut vel = if self.grounded {
+            // Walk along the ground rather than into or off of it
+            wish - self.ground_normal * wish.dot(self.ground_normal)
+        } else {
+            Vec3::new(wish.x, self.velocity.y - self.gravity * DT, wish.z)
+        };
+
+        let start = *body;
+        let motion = vel * DT;
+        let hits = self.sweep(body, world, motion, &mut vel);
+
+        if was_grounded && !jumped && hits.wall && self.step_height > 0.0 {
+            if let Some(stepped) = self.try_step_up(start, world, motion) {
+                let flat = |p: Pos3| Vec3::new(p.x, 0.0, p.z);
+                let progress =
+                    |b: &B| flat(b.center()).dot(motion) - flat(start.center()).dot(motion);
+                if progress(&stepped) > progress(body) {
+                    *body = stepped;
+                    vel = Vec3::new(wish.x, 0.0, wish.z);
+                }
+            }
+        }
+
+        // Stick to the ground unless we're on our way up
+        self.grounded = false;
+        if (was_grounded && !jumped) || vel.y <= 0.0 {
+            // Straight down, so standing on a slope doesn't slide us back
+            let mut probe = *body;
+            let ground = self.cast_vertical(&mut probe, world, -GROUND_SNAP);
+            if let Some(n) = ground.filter(|&n| self.walkable(n)) {
+                *body = probe;
+                self.grounded = true;
+                self.ground_normal = n;
+                vel.y = 0.0;
+            }
+        }
+        self.velocity = vel;
+        jumped
+    }
+}
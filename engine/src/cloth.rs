@@ -0,0 +1,342 @@
+use crate::geom::*;
+use crate::model::ModelVertex;
+use crate::DT;
+
+// Position-based dynamics with Verlet integration.  Based on:
+// https://matthias-research.github.io/pages/publications/posBasedDyn.pdf
+// http://web.archive.org/web/20080410171619/http://www.teknikus.dk/tj/gdc2001.htm
+
+// Sides of the tube we draw around a rope
+const ROPE_SIDES: usize = 6;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Particle {
+    pub pos: Pos3,
+    // Where it was last step; velocity is implied by pos - prev
+    pub prev: Pos3,
+    // 0 means pinned in place
+    pub inv_mass: f32,
+}
+
+/// Keeps two particles `rest` apart
+#[derive(Clone, Copy, Debug)]
+pub struct Stick {
+    pub a: usize,
+    pub b: usize,
+    pub rest: f32,
+    // 1 is rigid; lower lets it stretch a bit each iteration
+    pub stiffness: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    Rope,
+    // Particles are laid out row by row, `w` to a row
+    Cloth { w: usize, h: usize },
+}
+
+/// A rope or a sheet of cloth.  Call `update` once per `Game::update`,
+/// then upload `mesh_vertices` (see `Engine::update_soft_body`).
+pub struct SoftBody {
+    pub particles: Vec<Particle>,
+    pub sticks: Vec<Stick>,
+    pub gravity: Vec3,
+    // Fraction of velocity kept each step
+    pub damping: f32,
+    // More iterations make the sticks stiffer, at some cost
+    pub iterations: usize,
+    // How thick the rope or cloth is for collision and for drawing ropes
+    pub radius: f32,
+    // How much sliding velocity survives a collision, 0 to 1
+    pub friction: f32,
+    kind: Kind,
+}
+
+impl SoftBody {
+    fn new(particles: Vec<Particle>, sticks: Vec<Stick>, kind: Kind) -> Self {
+        Self {
+            particles,
+            sticks,
+            gravity: Vec3::new(0.0, -9.8, 0.0),
+            damping: 0.99,
+            iterations: 8,
+            radius: 0.05,
+            friction: 0.5,
+            kind,
+        }
+    }
+
+    fn particle(pos: Pos3, mass: f32) -> Particle {
+        Particle {
+            pos,
+            prev: pos,
+            inv_mass: 1.0 / mass,
+        }
+    }
+
+    fn stick(particles: &[Particle], a: usize, b: usize, stiffness: f32) -> Stick {
+        Stick {
+            a,
+            b,
+            rest: particles[a].pos.distance(particles[b].pos),
+            stiffness,
+        }
+    }
+
+    /// A rope of `segments` links from `start` to `end`, with both ends
+    /// pinned.  Unpin one to let it dangle.
+    pub fn rope(start: Pos3, end: Pos3, segments: usize, mass: f32) -> Self {
+        let segments = segments.max(1);
+        let per = mass / (segments + 1) as f32;
+        let particles: Vec<Particle> = (0..=segments)
+            .map(|i| Self::particle(start + (end - start) * (i as f32 / segments as f32), per))
+            .collect();
+        let sticks = (0..segments)
+            .map(|i| Self::stick(&particles, i, i + 1, 1.0))
+            .collect();
+        let mut rope = Self::new(particles, sticks, Kind::Rope);
+        rope.pin(0);
+        rope.pin(segments);
+        rope
+    }
+
+    /// A `w` by `h` grid of particles starting at `origin`, with rows going
+    /// along `across` and columns along `down` (both the full size of the
+    /// sheet).  The top row is pinned, like a banner.
+    pub fn cloth(origin: Pos3, across: Vec3, down: Vec3, w: usize, h: usize, mass: f32) -> Self {
+        let (w, h) = (w.max(2), h.max(2));
+        let per = mass / (w * h) as f32;
+        let mut particles = vec![];
+        for j in 0..h {
+            for i in 0..w {
+                let u = i as f32 / (w - 1) as f32;
+                let v = j as f32 / (h - 1) as f32;
+                particles.push(Self::particle(origin + across * u + down * v, per));
+            }
+        }
+        let idx = |i: usize, j: usize| j * w + i;
+        let mut sticks = vec![];
+        for j in 0..h {
+            for i in 0..w {
+                // Structural
+                if i + 1 < w {
+                    sticks.push(Self::stick(&particles, idx(i, j), idx(i + 1, j), 1.0));
+                }
+                if j + 1 < h {
+                    sticks.push(Self::stick(&particles, idx(i, j), idx(i, j + 1), 1.0));
+                }
+                // Shear
+                if i + 1 < w && j + 1 < h {
+                    sticks.push(Self::stick(&particles, idx(i, j), idx(i + 1, j + 1), 0.5));
+                    sticks.push(Self::stick(&particles, idx(i + 1, j), idx(i, j + 1), 0.5));
+                }
+                // Bending, kept soft so it can still fold
+                if i + 2 < w {
+                    sticks.push(Self::stick(&particles, idx(i, j), idx(i + 2, j), 0.2));
+                }
+                if j + 2 < h {
+                    sticks.push(Self::stick(&particles, idx(i, j), idx(i, j + 2), 0.2));
+                }
+            }
+        }
+        let mut cloth = Self::new(particles, sticks, Kind::Cloth { w, h });
+        for i in 0..w {
+            cloth.pin(i);
+        }
+        cloth
+    }
+
+    pub fn pin(&mut self, i: usize) {
+        self.particles[i].inv_mass = 0.0;
+    }
+
+    pub fn unpin(&mut self, i: usize, mass: f32) {
+        self.particles[i].inv_mass = 1.0 / mass;
+    }
+
+    /// Move a pinned particle, e.g. to carry a rope's end around
+    pub fn move_pin(&mut self, i: usize, pos: Pos3) {
+        let p = &mut self.particles[i];
+        p.prev = p.pos;
+        p.pos = pos;
+    }
+
+    /// One fixed step of `DT`.  Particles are kept out of `spheres` and
+    /// `planes`; pass empty slices if there's nothing to hit.
+    pub fn update(&mut self, spheres: &[Sphere], planes: &[Plane]) {
+        for p in self.particles.iter_mut() {
+            if p.inv_mass == 0.0 {
+                continue;
+            }
+            let vel = (p.pos - p.prev) * self.damping;
+            p.prev = p.pos;
+            p.pos += vel + self.gravity * (DT * DT);
+        }
+        for _ in 0..self.iterations {
+            for s in self.sticks.iter() {
+                let (pa, pb) = (self.particles[s.a], self.particles[s.b]);
+                let w = pa.inv_mass + pb.inv_mass;
+                if w == 0.0 {
+                    continue;
+                }
+                let delta = pb.pos - pa.pos;
+                let len = delta.magnitude();
+                if len == 0.0 {
+                    continue;
+                }
+                let correction = delta * ((len - s.rest) / (len * w) * s.stiffness);
+                self.particles[s.a].pos += correction * pa.inv_mass;
+                self.particles[s.b].pos -= correction * pb.inv_mass;
+            }
+            self.collide(spheres, planes);
+        }
+    }
+
+    fn collide(&mut self, spheres: &[Sphere], planes: &[Plane]) {
+        let (r, friction) = (self.radius, self.friction);
+        for p in self.particles.iter_mut() {
+            if p.inv_mass == 0.0 {
+                continue;
+            }
+            // Treat each particle as a little sphere so we can reuse the
+            // existing collision code.  Sphere-Sphere gives the push for
+            // the second sphere, and Sphere-Plane for the sphere itself.
+            let body = Sphere { c: p.pos, r };
+            let pushes = spheres
+                .iter()
+                .filter_map(|s| s.disp(&body))
+                .chain(planes.iter().filter_map(|pl| body.disp(pl)))
+                // Particles sitting exactly on a surface get a zero push,
+                // which has no direction to normalize
+                .filter(|push| push.magnitude2() > 0.0);
+            for push in pushes {
+                p.pos += push;
+                // Take away some of the sliding motion, so things resting
+                // on surfaces don't skate around
+                let n = push.normalize();
+                let vel = p.pos - p.prev;
+                let tangent = vel - n * vel.dot(n);
+                p.prev += tangent * (1.0 - friction);
+            }
+        }
+    }
+
+    /// Triangle indices to go with `mesh_vertices`.  These never change,
+    /// so only the vertices need uploading each frame.
+    pub fn mesh_indices(&self) -> Vec<u32> {
+        let mut indices = vec![];
+        match self.kind {
+            Kind::Rope => {
+                let sides = ROPE_SIDES as u32;
+                for seg in 0..(self.particles.len() - 1) as u32 {
+                    for k in 0..sides {
+                        let a = seg * sides + k;
+                        let b = seg * sides + (k + 1) % sides;
+                        let (c, d) = (a + sides, b + sides);
+                        indices.extend_from_slice(&[a, b, d, a, d, c]);
+                    }
+                }
+            }
+            Kind::Cloth { w, h } => {
+                // Two layers, one facing each way, so both sides show up
+                // with back face culling on
+                let back = (w * h) as u32;
+                let w32 = w as u32;
+                for j in 0..(h - 1) as u32 {
+                    for i in 0..(w - 1) as u32 {
+                        let a = j * w32 + i;
+                        let (b, c, d) = (a + 1, a + w32, a + w32 + 1);
+                        indices.extend_from_slice(&[a, c, d, a, d, b]);
+                        indices.extend_from_slice(&[
+                            back + a,
+                            back + d,
+                            back + c,
+                            back + a,
+                            back + b,
+                            back + d,
+                        ]);
+                    }
+                }
+            }
+        }
+        indices
+    }
+
+    /// Where the particles are now, as vertices with smooth normals
+    pub fn mesh_vertices(&self) -> Vec<ModelVertex> {
+        match self.kind {
+            Kind::Rope => self.rope_vertices(),
+            Kind::Cloth { w, h } => self.cloth_vertices(w, h),
+        }
+    }
+
+    fn rope_vertices(&self) -> Vec<ModelVertex> {
+        let n = self.particles.len();
+        let mut vertices = Vec::with_capacity(n * ROPE_SIDES);
+        // Carry one side vector down the rope so the tube doesn't twist
+        let mut side: Option<Vec3> = None;
+        for i in 0..n {
+            let prev = self.particles[i.saturating_sub(1)].pos;
+            let next = self.particles[(i + 1).min(n - 1)].pos;
+            let tangent = next - prev;
+            let tangent = if tangent.magnitude2() > 0.0 {
+                tangent.normalize()
+            } else {
+                Vec3::unit_y()
+            };
+            let guess = side.unwrap_or_else(|| {
+                if tangent.x.abs() < 0.9 {
+                    Vec3::unit_x()
+                } else {
+                    Vec3::unit_z()
+                }
+            });
+            let mut s = guess - tangent * guess.dot(tangent);
+            if s.magnitude2() < f32::EPSILON {
+                s = tangent.cross(Vec3::unit_x());
+            }
+            let s = s.normalize();
+            side = Some(s);
+            let up = tangent.cross(s);
+            for k in 0..ROPE_SIDES {
+                let angle = 2.0 * PI * k as f32 / ROPE_SIDES as f32;
+                let normal = s * angle.cos() + up * angle.sin();
+                vertices.push(ModelVertex::new(
+                    (self.particles[i].pos + normal * self.radius).into(),
+                    [k as f32 / ROPE_SIDES as f32, i as f32 / (n - 1) as f32],
+                    normal.into(),
+                ));
+            }
+        }
+        vertices
+    }
+
+    fn cloth_vertices(&self, w: usize, h: usize) -> Vec<ModelVertex> {
+        let pos = |i: usize, j: usize| self.particles[j * w + i].pos;
+        let mut front = Vec::with_capacity(w * h);
+        for j in 0..h {
+            for i in 0..w {
+                // Central differences across the grid, like the terrain
+                let dx = pos((i + 1).min(w - 1), j) - pos(i.saturating_sub(1), j);
+                let dy = pos(i, (j + 1).min(h - 1)) - pos(i, j.saturating_sub(1));
+                let n = dy.cross(dx);
+                let n = if n.magnitude2() > 0.0 {
+                    n.normalize()
+                } else {
+                    Vec3::unit_z()
+                };
+                let uv = [i as f32 / (w - 1) as f32, j as f32 / (h - 1) as f32];
+                front.push((pos(i, j), uv, n));
+            }
+        }
+        front
+            .iter()
+            .map(|&(p, uv, n)| ModelVertex::new(p.into(), uv, n.into()))
+            .chain(
+                front
+                    .iter()
+                    .map(|&(p, uv, n)| ModelVertex::new(p.into(), uv, (-n).into())),
+            )
+            .collect()
+    }
+}
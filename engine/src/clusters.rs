@@ -0,0 +1,166 @@
+use crate::camera::Camera;
+use crate::geom::*;
+use crate::lights::Light;
+
+// The view frustum is cut into a grid of clusters: tiles across the screen,
+// and slices along depth that get exponentially thicker further out.  Each
+// cluster gets a list of the point and spot lights that can reach it, so the
+// fragment shader only loops over those.  See:
+// http://www.aortiz.me/2018/12/21/CG.html
+// These have to match u_cluster_dims as used in shader.frag.
+pub(crate) const CLUSTERS_X: u32 = 16;
+pub(crate) const CLUSTERS_Y: u32 = 9;
+pub(crate) const CLUSTERS_Z: u32 = 24;
+pub(crate) const CLUSTER_COUNT: usize = (CLUSTERS_X * CLUSTERS_Y * CLUSTERS_Z) as usize;
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct ClusterUniforms {
+    // Clusters across, down, and deep, then how many directed lights there
+    // are at the start of the index list
+    dims: [u32; 4],
+    // Near and far planes, and ln(far / near)
+    depth: [f32; 4],
+    // Width and height of the frame in pixels
+    screen: [f32; 4],
+}
+
+pub(crate) struct Clusters {
+    pub(crate) uniforms: ClusterUniforms,
+    // Offset into `indices` and count, per cluster
+    pub(crate) ranges: Vec<[u32; 2]>,
+    // Directed lights first, then each cluster's lights in turn
+    pub(crate) indices: Vec<u32>,
+    // Scratch space for building `indices`, kept to save allocating
+    lists: Vec<Vec<u32>>,
+}
+
+impl Clusters {
+    pub(crate) fn new() -> Self {
+        Self {
+            uniforms: ClusterUniforms {
+                dims: [CLUSTERS_X, CLUSTERS_Y, CLUSTERS_Z, 0],
+                depth: [0.0; 4],
+                screen: [0.0; 4],
+            },
+            ranges: vec![[0, 0]; CLUSTER_COUNT],
+            indices: vec![],
+            lists: vec![vec![]; CLUSTER_COUNT],
+        }
+    }
+
+    fn index(x: u32, y: u32, z: u32) -> usize {
+        ((z * CLUSTERS_Y + y) * CLUSTERS_X + x) as usize
+    }
+
+    // Depth where slice `z` starts
+    fn slice_depth(near: f32, far: f32, z: u32) -> f32 {
+        near * (far / near).powf(z as f32 / CLUSTERS_Z as f32)
+    }
+
+    fn slice(near: f32, far: f32, depth: f32) -> u32 {
+        if depth <= near {
+            return 0;
+        }
+        let s = (depth / near).ln() / (far / near).ln() * CLUSTERS_Z as f32;
+        (s as u32).min(CLUSTERS_Z - 1)
+    }
+
+    /// Sort `lights` into clusters for a `width` by `height` frame seen from
+    /// `camera`
+    pub(crate) fn assign(&mut self, lights: &[Light], camera: &Camera, width: u32, height: u32) {
+        let (near, far) = (camera.znear, camera.zfar);
+        let (view, _proj) = camera.build_view_projection_matrix();
+        // View space extent of the frustum at a depth of 1
+        let ty = (camera.fovy.to_radians() / 2.0).tan();
+        let tx = ty * camera.aspect;
+
+        for list in self.lists.iter_mut() {
+            list.clear();
+        }
+        self.indices.clear();
+        for (i, l) in lights.iter().enumerate() {
+            if l.pos[3] == 0.0 {
+                self.indices.push(i as u32);
+                continue;
+            }
+            let r = l.range();
+            if l.intensity() == 0.0 || r <= 0.0 {
+                continue;
+            }
+            let c = (view * l.position().to_homogeneous()).truncate();
+            let depth = -c.z;
+            if depth + r < near || depth - r > far {
+                continue;
+            }
+            let z0 = Self::slice(near, far, depth - r);
+            let z1 = Self::slice(near, far, depth + r);
+            // Screen tiles the sphere's bounding box could cover.  Anything
+            // poking in front of the near plane might be anywhere on screen.
+            let (x0, x1, y0, y1) = if depth - r <= near {
+                (0, CLUSTERS_X - 1, 0, CLUSTERS_Y - 1)
+            } else {
+                let d = depth - r;
+                let tile = |ndc: f32, n: u32| -> u32 {
+                    (((ndc + 1.0) / 2.0 * n as f32).max(0.0) as u32).min(n - 1)
+                };
+                let ndc_x0 = ((c.x - r) / (d * tx)).min((c.x - r) / ((depth + r) * tx));
+                let ndc_x1 = ((c.x + r) / (d * tx)).max((c.x + r) / ((depth + r) * tx));
+                let ndc_y0 = ((c.y - r) / (d * ty)).min((c.y - r) / ((depth + r) * ty));
+                let ndc_y1 = ((c.y + r) / (d * ty)).max((c.y + r) / ((depth + r) * ty));
+                if ndc_x0 > 1.0 || ndc_x1 < -1.0 || ndc_y0 > 1.0 || ndc_y1 < -1.0 {
+                    continue;
+                }
+                // Tile rows count down from the top of the screen
+                (
+                    tile(ndc_x0, CLUSTERS_X),
+                    tile(ndc_x1, CLUSTERS_X),
+                    tile(-ndc_y1, CLUSTERS_Y),
+                    tile(-ndc_y0, CLUSTERS_Y),
+                )
+            };
+            for z in z0..=z1 {
+                let dn = Self::slice_depth(near, far, z);
+                let df = Self::slice_depth(near, far, z + 1);
+                for y in y0..=y1 {
+                    // NDC is +1 at the top, tile rows start there
+                    let ny0 = 1.0 - 2.0 * (y + 1) as f32 / CLUSTERS_Y as f32;
+                    let ny1 = 1.0 - 2.0 * y as f32 / CLUSTERS_Y as f32;
+                    for x in x0..=x1 {
+                        let nx0 = 2.0 * x as f32 / CLUSTERS_X as f32 - 1.0;
+                        let nx1 = 2.0 * (x + 1) as f32 / CLUSTERS_X as f32 - 1.0;
+                        // View space box around the cluster
+                        let xs = [nx0 * dn * tx, nx1 * dn * tx, nx0 * df * tx, nx1 * df * tx];
+                        let ys = [ny0 * dn * ty, ny1 * dn * ty, ny0 * df * ty, ny1 * df * ty];
+                        let min = Vec3::new(
+                            xs.iter().cloned().fold(f32::INFINITY, f32::min),
+                            ys.iter().cloned().fold(f32::INFINITY, f32::min),
+                            -df,
+                        );
+                        let max = Vec3::new(
+                            xs.iter().cloned().fold(f32::NEG_INFINITY, f32::max),
+                            ys.iter().cloned().fold(f32::NEG_INFINITY, f32::max),
+                            -dn,
+                        );
+                        let closest = Vec3::new(
+                            c.x.clamp(min.x, max.x),
+                            c.y.clamp(min.y, max.y),
+                            c.z.clamp(min.z, max.z),
+                        );
+                        if (closest - c).magnitude2() <= r * r {
+                            self.lists[Self::index(x, y, z)].push(i as u32);
+                        }
+                    }
+                }
+            }
+        }
+
+        self.uniforms.dims[3] = self.indices.len() as u32;
+        for (range, list) in self.ranges.iter_mut().zip(self.lists.iter()) {
+            *range = [self.indices.len() as u32, list.len() as u32];
+            self.indices.extend_from_slice(list);
+        }
+        self.uniforms.depth = [near, far, (far / near).ln(), 0.0];
+        self.uniforms.screen = [width as f32, height as f32, 0.0, 0.0];
+    }
+}
@@ -9,6 +9,19 @@ pub struct Contact<T: Copy> {
     pub mtv: Vec3,
 }
 
+// Deepest first.  Ties are broken by index so the order (and so the result)
+// is the same every run.
+fn sort_contacts(contacts: &mut [Contact<usize>]) {
+    contacts.sort_by(|a, b| {
+        b.mtv
+            .magnitude2()
+            .partial_cmp(&a.mtv.magnitude2())
+            .unwrap()
+            .then(a.a.cmp(&b.a))
+            .then(a.b.cmp(&b.b))
+    });
+}
+
 pub fn restitute_dyn_stat<S1: Shape, S2: Shape>(
     ashapes: &mut [S1],
     avels: &[Vec3],
@@ -19,7 +32,7 @@ pub fn restitute_dyn_stat<S1: Shape, S2: Shape>(
 ) where
     S1: Collide<S2>,
 {
-    contacts.sort_unstable_by(|a, b| b.mtv.magnitude2().partial_cmp(&a.mtv.magnitude2()).unwrap());
+    sort_contacts(contacts);
     for c in contacts.iter() {
         let a = c.a;
         let b = c.b;
@@ -48,7 +61,7 @@ pub fn restitute_dyn_dyn<S1: Shape, S2: Shape>(
 ) where
     S1: Collide<S2>,
 {
-    contacts.sort_unstable_by(|a, b| b.mtv.magnitude2().partial_cmp(&a.mtv.magnitude2()).unwrap());
+    sort_contacts(contacts);
     // That can bump into each other in perfectly elastic collisions!
     for c in contacts.iter() {
         let a = c.a;
@@ -72,7 +85,7 @@ pub fn restitute_dyns<S1: Shape>(
 ) where
     S1: Collide<S1>,
 {
-    contacts.sort_unstable_by(|a, b| b.mtv.magnitude2().partial_cmp(&a.mtv.magnitude2()).unwrap());
+    sort_contacts(contacts);
     // That can bump into each other in perfectly elastic collisions!
     for c in contacts.iter() {
         let a = c.a;
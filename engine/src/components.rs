@@ -1,18 +1,24 @@
-use std::{cell::RefCell, collections::HashMap};
+use std::{cell::RefCell, collections::BTreeMap, hash::Hasher};
 
-// Components can be stored in vecs or hashmaps
+use crate::determinism::StateHasher;
+
+// Components can be stored in vecs or maps
 // All components will know if they are sparse or not
 pub trait Component {
     fn is_sparse(&self) -> bool;
+    // Components that matter for the simulation should feed themselves in
+    // here (see `determinism::StateHash`); the rest can leave it alone.
+    fn hash_state(&self, _h: &mut StateHasher) {}
 }
 
 pub trait ComponentStorage {
     fn as_any(&self) -> &dyn std::any::Any;
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any;
     fn push_none(&mut self);
+    fn hash_state(&self, h: &mut StateHasher);
 }
 
-impl<T: 'static> ComponentStorage for RefCell<Vec<Option<T>>> {
+impl<T: 'static + Component> ComponentStorage for RefCell<Vec<Option<T>>> {
     fn as_any(&self) -> &dyn std::any::Any {
         self as &dyn std::any::Any
     }
@@ -23,9 +29,24 @@ impl<T: 'static> ComponentStorage for RefCell<Vec<Option<T>>> {
     fn push_none(&mut self) {
         self.get_mut().push(None)
     }
+    fn hash_state(&self, h: &mut StateHasher) {
+        let components = self.borrow();
+        h.write_usize(components.len());
+        for c in components.iter() {
+            match c {
+                Some(c) => {
+                    h.write_u8(1);
+                    c.hash_state(h);
+                }
+                None => h.write_u8(0),
+            }
+        }
+    }
 }
 
-impl<T: 'static> ComponentStorage for RefCell<HashMap<usize, T>> {
+// A BTreeMap rather than a HashMap so iteration order doesn't change from
+// run to run
+impl<T: 'static + Component> ComponentStorage for RefCell<BTreeMap<usize, T>> {
     fn as_any(&self) -> &dyn std::any::Any {
         self as &dyn std::any::Any
     }
@@ -33,6 +54,14 @@ impl<T: 'static> ComponentStorage for RefCell<HashMap<usize, T>> {
         self as &mut dyn std::any::Any
     }
     fn push_none(&mut self) {
-        // does nothing; defeats the point of using a Hashmap
+        // does nothing; defeats the point of using a map
+    }
+    fn hash_state(&self, h: &mut StateHasher) {
+        let components = self.borrow();
+        h.write_usize(components.len());
+        for (id, c) in components.iter() {
+            h.write_usize(*id);
+            c.hash_state(h);
+        }
     }
 }
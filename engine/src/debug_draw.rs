@@ -0,0 +1,432 @@
+use crate::collision::Contact;
+use crate::geom::*;
+
+// Immediate-mode debug drawing: anything can push lines in here, and each
+// frame they're all drawn in one go as a line list over the finished frame,
+// ignoring depth.  Each call takes a lifetime in seconds; 0 means just until
+// the next update.
+
+/// Something `DebugDraw::shape` can draw a wireframe of
+pub trait DebugShape {
+    fn draw_wire(&self, dd: &mut DebugDraw, color: [f32; 4], lifetime: f32);
+}
+
+struct Line {
+    a: Pos3,
+    b: Pos3,
+    color: [f32; 4],
+    remaining: f32,
+}
+
+struct Text {
+    at: Pos3,
+    text: String,
+    height: f32,
+    color: [f32; 4],
+    remaining: f32,
+}
+
+#[derive(Default)]
+pub struct DebugDraw {
+    lines: Vec<Line>,
+    texts: Vec<Text>,
+}
+
+// Segments around a full circle
+const CIRCLE_SEGMENTS: usize = 24;
+
+impl DebugDraw {
+    pub fn line(&mut self, a: Pos3, b: Pos3, color: [f32; 4], lifetime: f32) {
+        self.lines.push(Line {
+            a,
+            b,
+            color,
+            remaining: lifetime,
+        });
+    }
+    /// A line with a head at `to`
+    pub fn arrow(&mut self, from: Pos3, to: Pos3, color: [f32; 4], lifetime: f32) {
+        self.line(from, to, color, lifetime);
+        let d = to - from;
+        let len = d.magnitude();
+        if len < 1e-6 {
+            return;
+        }
+        let dir = d / len;
+        let head = len * 0.2;
+        let (u, v) = basis(dir);
+        let back = to - dir * head;
+        for side in [u, -u, v, -v].iter() {
+            self.line(to, back + side * head * 0.4, color, lifetime);
+        }
+    }
+    pub fn wire_sphere(&mut self, s: &Sphere, color: [f32; 4], lifetime: f32) {
+        let (x, y, z) = (Vec3::unit_x(), Vec3::unit_y(), Vec3::unit_z());
+        self.arc(s.c, x, y, s.r, 0.0, 2.0 * PI, color, lifetime);
+        self.arc(s.c, y, z, s.r, 0.0, 2.0 * PI, color, lifetime);
+        self.arc(s.c, z, x, s.r, 0.0, 2.0 * PI, color, lifetime);
+    }
+    pub fn wire_box(&mut self, b: &Box, color: [f32; 4], lifetime: f32) {
+        let axes = [
+            b.axes.x * b.half_sizes.x,
+            b.axes.y * b.half_sizes.y,
+            b.axes.z * b.half_sizes.z,
+        ];
+        self.cuboid(b.c, axes, color, lifetime);
+    }
+    pub fn wire_aabb(&mut self, b: &AABB, color: [f32; 4], lifetime: f32) {
+        let axes = [
+            Vec3::unit_x() * b.half_sizes.x,
+            Vec3::unit_y() * b.half_sizes.y,
+            Vec3::unit_z() * b.half_sizes.z,
+        ];
+        self.cuboid(b.c, axes, color, lifetime);
+    }
+    /// A square `size` across, centered on the point of the plane nearest
+    /// the origin, with its normal sticking out
+    pub fn wire_plane(&mut self, p: &Plane, size: f32, color: [f32; 4], lifetime: f32) {
+        const CELLS: usize = 4;
+        let n = p.n.normalize();
+        let c = Pos3::from_vec(n * p.d);
+        let (u, v) = basis(n);
+        let half = size / 2.0;
+        for i in 0..=CELLS {
+            let t = i as f32 / CELLS as f32 * size - half;
+            self.line(c + u * t - v * half, c + u * t + v * half, color, lifetime);
+            self.line(c + v * t - u * half, c + v * t + u * half, color, lifetime);
+        }
+        self.arrow(c, c + n * half * 0.5, color, lifetime);
+    }
+    pub fn wire_capsule(&mut self, cap: &Capsule, color: [f32; 4], lifetime: f32) {
+        let d = cap.b - cap.a;
+        let axis = if d.magnitude2() > 1e-12 {
+            d.normalize()
+        } else {
+            Vec3::unit_y()
+        };
+        let (u, v) = basis(axis);
+        self.arc(cap.a, u, v, cap.r, 0.0, 2.0 * PI, color, lifetime);
+        self.arc(cap.b, u, v, cap.r, 0.0, 2.0 * PI, color, lifetime);
+        for side in [u, -u, v, -v].iter() {
+            self.line(cap.a + side * cap.r, cap.b + side * cap.r, color, lifetime);
+        }
+        // Half circles over each end
+        for side in [u, v].iter() {
+            self.arc(cap.b, *side, axis, cap.r, 0.0, PI, color, lifetime);
+            self.arc(cap.a, *side, -axis, cap.r, 0.0, PI, color, lifetime);
+        }
+    }
+    /// Every edge of every face.  Faces with more than three corners get
+    /// their diagonals drawn too.
+    pub fn wire_hull(&mut self, h: &ConvexHull, color: [f32; 4], lifetime: f32) {
+        let pts: Vec<Pos3> = h.points.iter().map(|p| h.c + h.axes * *p).collect();
+        let n = pts.len();
+        let mut edges = std::collections::BTreeSet::new();
+        // A triangle is a face if nothing is in front of it
+        for i in 0..n {
+            for j in i + 1..n {
+                for k in j + 1..n {
+                    let normal = (pts[j] - pts[i]).cross(pts[k] - pts[i]);
+                    if normal.magnitude2() < 1e-12 {
+                        continue;
+                    }
+                    let eps = 1e-4 * normal.magnitude();
+                    let sides = pts.iter().map(|p| normal.dot(p - pts[i]));
+                    let (mut front, mut back) = (false, false);
+                    for s in sides {
+                        front |= s > eps;
+                        back |= s < -eps;
+                    }
+                    if !(front && back) {
+                        edges.insert((i, j));
+                        edges.insert((j, k));
+                        edges.insert((i, k));
+                    }
+                }
+            }
+        }
+        for (i, j) in edges {
+            self.line(pts[i], pts[j], color, lifetime);
+        }
+    }
+    /// From the ray's start to `dir` away
+    pub fn ray(&mut self, r: &Ray, color: [f32; 4], lifetime: f32) {
+        self.arrow(r.p, r.p + r.dir, color, lifetime);
+    }
+    pub fn shape(&mut self, s: &impl DebugShape, color: [f32; 4], lifetime: f32) {
+        s.draw_wire(self, color, lifetime);
+    }
+    /// Which way and how far a contact pushes, starting from `at`
+    pub fn contact<T: Copy>(&mut self, at: Pos3, c: &Contact<T>, color: [f32; 4], lifetime: f32) {
+        self.arrow(at, at + c.mtv, color, lifetime);
+    }
+    /// Red, green and blue arrows along x, y and z, `size` long
+    pub fn axes(&mut self, at: Pos3, rot: Quat, size: f32, lifetime: f32) {
+        self.arrow(
+            at,
+            at + rot * Vec3::unit_x() * size,
+            [1.0, 0.0, 0.0, 1.0],
+            lifetime,
+        );
+        self.arrow(
+            at,
+            at + rot * Vec3::unit_y() * size,
+            [0.0, 1.0, 0.0, 1.0],
+            lifetime,
+        );
+        self.arrow(
+            at,
+            at + rot * Vec3::unit_z() * size,
+            [0.0, 0.0, 1.0, 1.0],
+            lifetime,
+        );
+    }
+    /// A label centered above `at`, always facing the camera.  Only knows
+    /// letters (drawn as capitals), digits and a little punctuation.
+    pub fn text_3d(&mut self, at: Pos3, text: &str, height: f32, color: [f32; 4], lifetime: f32) {
+        self.texts.push(Text {
+            at,
+            text: text.to_string(),
+            height,
+            color,
+            remaining: lifetime,
+        });
+    }
+    /// Forget everything, however long it had left
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.texts.clear();
+    }
+
+    // Age everything by a step, dropping what's run out
+    pub(crate) fn tick(&mut self, dt: f32) {
+        for l in self.lines.iter_mut() {
+            l.remaining -= dt;
+        }
+        for t in self.texts.iter_mut() {
+            t.remaining -= dt;
+        }
+        self.lines.retain(|l| l.remaining >= 0.0);
+        self.texts.retain(|t| t.remaining >= 0.0);
+    }
+
+    // Two vertices per line.  `right` and `up` are the camera's, for
+    // turning text to face it.
+    pub(crate) fn vertices(&self, right: Vec3, up: Vec3) -> Vec<DebugVertex> {
+        let mut out = Vec::with_capacity(self.lines.len() * 2);
+        let mut push = |a: Pos3, b: Pos3, color: [f32; 4]| {
+            out.push(DebugVertex {
+                position: a.into(),
+                color,
+            });
+            out.push(DebugVertex {
+                position: b.into(),
+                color,
+            });
+        };
+        for l in self.lines.iter() {
+            push(l.a, l.b, l.color);
+        }
+        for t in self.texts.iter() {
+            // Glyphs are 2 by 4 units with 1 between them
+            let unit = t.height / 4.0;
+            let count = t.text.chars().count() as f32;
+            let width = (count * 3.0 - 1.0).max(0.0) * unit;
+            let origin = t.at - right * (width / 2.0);
+            for (i, ch) in t.text.chars().enumerate() {
+                let left = origin + right * (i as f32 * 3.0 * unit);
+                let at = |x: u8, y: u8| left + right * (x as f32 * unit) + up * (y as f32 * unit);
+                for stroke in glyph(ch) {
+                    let pts: Vec<_> = stroke
+                        .split(' ')
+                        .map(|p| {
+                            let p = p.as_bytes();
+                            at(p[0] - b'0', p[1] - b'0')
+                        })
+                        .collect();
+                    for w in pts.windows(2) {
+                        push(w[0], w[1], t.color);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+impl DebugDraw {
+    // Part of a circle around `c` in the plane of unit vectors `u` and `v`,
+    // starting from `u` and turning towards `v`
+    #[allow(clippy::too_many_arguments)]
+    fn arc(
+        &mut self,
+        c: Pos3,
+        u: Vec3,
+        v: Vec3,
+        r: f32,
+        from: f32,
+        to: f32,
+        color: [f32; 4],
+        lifetime: f32,
+    ) {
+        let steps = ((to - from).abs() / (2.0 * PI) * CIRCLE_SEGMENTS as f32).ceil() as usize;
+        let steps = steps.max(1);
+        let point = |t: f32| c + (u * t.cos() + v * t.sin()) * r;
+        for i in 0..steps {
+            let t0 = from + (to - from) * i as f32 / steps as f32;
+            let t1 = from + (to - from) * (i + 1) as f32 / steps as f32;
+            self.line(point(t0), point(t1), color, lifetime);
+        }
+    }
+    // The 12 edges of a box with these (scaled) half axes
+    fn cuboid(&mut self, c: Pos3, axes: [Vec3; 3], color: [f32; 4], lifetime: f32) {
+        let corner = |i: usize| {
+            let s = |bit: usize| if i & (1 << bit) != 0 { 1.0 } else { -1.0 };
+            c + axes[0] * s(0) + axes[1] * s(1) + axes[2] * s(2)
+        };
+        for i in 0..8 {
+            for bit in 0..3 {
+                // Each edge once, from its corner on the negative side
+                if i & (1 << bit) == 0 {
+                    self.line(corner(i), corner(i | (1 << bit)), color, lifetime);
+                }
+            }
+        }
+    }
+}
+
+// Two unit vectors perpendicular to `n` and each other
+fn basis(n: Vec3) -> (Vec3, Vec3) {
+    let other = if n.x.abs() < 0.9 {
+        Vec3::unit_x()
+    } else {
+        Vec3::unit_y()
+    };
+    let u = n.cross(other).normalize();
+    (u, n.cross(u))
+}
+
+// Strokes for one character on a grid 3 points across (x 0 to 2) and 5 up
+// (y 0 to 4), each a run of "xy" points.  Unknown characters are blank.
+fn glyph(ch: char) -> &'static [&'static str] {
+    match ch.to_ascii_uppercase() {
+        '0' => &["00 04 24 20 00", "00 24"],
+        '1' => &["03 14 10", "00 20"],
+        '2' => &["04 24 22 02 00 20"],
+        '3' => &["04 24 20 00", "02 22"],
+        '4' => &["04 02 22", "24 20"],
+        '5' | 'S' => &["24 04 02 22 20 00"],
+        '6' => &["24 04 00 20 22 02"],
+        '7' => &["04 24 10"],
+        '8' => &["00 04 24 20 00", "02 22"],
+        '9' => &["20 24 04 02 22"],
+        'A' => &["00 03 14 23 20", "02 22"],
+        'B' => &["00 04 14 23 12 21 10 00", "02 12"],
+        'C' => &["24 04 00 20"],
+        'D' => &["00 04 14 23 21 10 00"],
+        'E' => &["24 04 00 20", "02 12"],
+        'F' => &["24 04 00", "02 12"],
+        'G' => &["24 04 00 20 22 12"],
+        'H' => &["00 04", "20 24", "02 22"],
+        'I' => &["04 24", "14 10", "00 20"],
+        'J' => &["24 20 00 01"],
+        'K' => &["00 04", "24 02 20"],
+        'L' => &["04 00 20"],
+        'M' => &["00 04 12 24 20"],
+        'N' => &["00 04 20 24"],
+        'O' => &["00 04 24 20 00"],
+        'P' => &["00 04 24 22 02"],
+        'Q' => &["00 04 24 20 00", "11 20"],
+        'R' => &["00 04 24 22 02 20"],
+        'T' => &["04 24", "14 10"],
+        'U' => &["04 00 20 24"],
+        'V' => &["04 10 24"],
+        'W' => &["04 00 12 20 24"],
+        'X' => &["04 20", "00 24"],
+        'Y' => &["04 12 24", "12 10"],
+        'Z' => &["04 24 00 20"],
+        '-' => &["02 22"],
+        '+' => &["02 22", "13 11"],
+        '=' => &["01 21", "03 23"],
+        '_' => &["00 20"],
+        '.' => &["10 11"],
+        ',' => &["11 00"],
+        ':' => &["10 11", "13 14"],
+        '/' => &["00 24"],
+        '(' => &["14 03 01 10"],
+        ')' => &["14 23 21 10"],
+        '!' => &["14 12", "10 11"],
+        '?' => &["03 14 23 12 11"],
+        _ => &[],
+    }
+}
+
+impl DebugShape for Sphere {
+    fn draw_wire(&self, dd: &mut DebugDraw, color: [f32; 4], lifetime: f32) {
+        dd.wire_sphere(self, color, lifetime);
+    }
+}
+
+impl DebugShape for Plane {
+    fn draw_wire(&self, dd: &mut DebugDraw, color: [f32; 4], lifetime: f32) {
+        dd.wire_plane(self, 10.0, color, lifetime);
+    }
+}
+
+impl DebugShape for Box {
+    fn draw_wire(&self, dd: &mut DebugDraw, color: [f32; 4], lifetime: f32) {
+        dd.wire_box(self, color, lifetime);
+    }
+}
+
+impl DebugShape for AABB {
+    fn draw_wire(&self, dd: &mut DebugDraw, color: [f32; 4], lifetime: f32) {
+        dd.wire_aabb(self, color, lifetime);
+    }
+}
+
+impl DebugShape for Capsule {
+    fn draw_wire(&self, dd: &mut DebugDraw, color: [f32; 4], lifetime: f32) {
+        dd.wire_capsule(self, color, lifetime);
+    }
+}
+
+impl DebugShape for ConvexHull {
+    fn draw_wire(&self, dd: &mut DebugDraw, color: [f32; 4], lifetime: f32) {
+        dd.wire_hull(self, color, lifetime);
+    }
+}
+
+impl DebugShape for Ray {
+    fn draw_wire(&self, dd: &mut DebugDraw, color: [f32; 4], lifetime: f32) {
+        dd.ray(self, color, lifetime);
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct DebugVertex {
+    position: [f32; 3],
+    color: [f32; 4],
+}
+
+impl DebugVertex {
+    pub(crate) fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<DebugVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float3,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float4,
+                },
+            ],
+        }
+    }
+}
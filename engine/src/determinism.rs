@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::hash::Hasher;
+
+use crate::geom::*;
+
+// Bits we feed in for Option's None and Some, so that e.g. [None, Some(x)]
+// and [Some(x), None] don't hash the same
+const NONE_TAG: u8 = 0;
+const SOME_TAG: u8 = 1;
+
+/// 64-bit FNV-1a.  Unlike std's `DefaultHasher`, the output is pinned down
+/// and doesn't depend on the Rust version, so hashes can be written to disk
+/// or sent over the network and compared later.
+pub struct StateHasher(u64);
+
+impl StateHasher {
+    pub fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Default for StateHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hasher for StateHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Game state that can be folded into a per-tick hash.  Floats are hashed
+/// by their bits, so two runs only match if they're bit-for-bit identical.
+pub trait StateHash {
+    fn hash_state(&self, h: &mut StateHasher);
+}
+
+impl StateHash for f32 {
+    fn hash_state(&self, h: &mut StateHasher) {
+        // -0.0 and 0.0 compare equal, so treat them the same here too
+        let v = if *self == 0.0 { 0.0_f32 } else { *self };
+        h.write_u32(v.to_bits());
+    }
+}
+
+macro_rules! state_hash_via_hash {
+    ($($t:ty),+) => {
+        $(
+            impl StateHash for $t {
+                fn hash_state(&self, h: &mut StateHasher) {
+                    std::hash::Hash::hash(self, h);
+                }
+            }
+        )+
+    };
+}
+
+state_hash_via_hash!(bool, u8, i8, u16, i16, u32, i32, u64, i64, usize, isize);
+
+impl StateHash for Vec3 {
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.x.hash_state(h);
+        self.y.hash_state(h);
+        self.z.hash_state(h);
+    }
+}
+
+impl StateHash for Pos3 {
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.to_vec().hash_state(h);
+    }
+}
+
+impl StateHash for Quat {
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.s.hash_state(h);
+        self.v.hash_state(h);
+    }
+}
+
+impl StateHash for Mat3 {
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.x.hash_state(h);
+        self.y.hash_state(h);
+        self.z.hash_state(h);
+    }
+}
+
+impl StateHash for Sphere {
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.c.hash_state(h);
+        self.r.hash_state(h);
+    }
+}
+
+impl StateHash for Plane {
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.n.hash_state(h);
+        self.d.hash_state(h);
+    }
+}
+
+impl StateHash for Box {
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.c.hash_state(h);
+        self.axes.hash_state(h);
+        self.half_sizes.hash_state(h);
+    }
+}
+
+impl StateHash for AABB {
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.c.hash_state(h);
+        self.half_sizes.hash_state(h);
+    }
+}
+
+impl StateHash for Capsule {
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.a.hash_state(h);
+        self.b.hash_state(h);
+        self.r.hash_state(h);
+    }
+}
+
+impl<T: StateHash> StateHash for Option<T> {
+    fn hash_state(&self, h: &mut StateHasher) {
+        match self {
+            Some(t) => {
+                h.write_u8(SOME_TAG);
+                t.hash_state(h);
+            }
+            None => h.write_u8(NONE_TAG),
+        }
+    }
+}
+
+impl<T: StateHash> StateHash for [T] {
+    fn hash_state(&self, h: &mut StateHasher) {
+        h.write_usize(self.len());
+        for t in self {
+            t.hash_state(h);
+        }
+    }
+}
+
+impl<T: StateHash> StateHash for Vec<T> {
+    fn hash_state(&self, h: &mut StateHasher) {
+        self[..].hash_state(h);
+    }
+}
+
+impl<K: StateHash, V: StateHash> StateHash for BTreeMap<K, V> {
+    fn hash_state(&self, h: &mut StateHasher) {
+        h.write_usize(self.len());
+        for (k, v) in self {
+            k.hash_state(h);
+            v.hash_state(h);
+        }
+    }
+}
+
+impl<A: StateHash, B: StateHash> StateHash for (A, B) {
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.0.hash_state(h);
+        self.1.hash_state(h);
+    }
+}
+
+impl<T: StateHash + ?Sized> StateHash for &T {
+    fn hash_state(&self, h: &mut StateHasher) {
+        (**self).hash_state(h);
+    }
+}
@@ -0,0 +1,11 @@
+#version 450
+
+// One triangle that covers the whole target, for post-processing passes.
+// Draw it with three vertices and no vertex buffers.
+
+layout(location=0) out vec2 v_uv;
+
+void main() {
+    v_uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+    gl_Position = vec4(v_uv * vec2(2.0, -2.0) + vec2(-1.0, 1.0), 0.0, 1.0);
+}
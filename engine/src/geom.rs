@@ -72,6 +72,42 @@ impl Shape for AABB {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Capsule {
+    // The two ends of the capsule's inner segment
+    pub a: Pos3,
+    pub b: Pos3,
+    pub r: f32,
+}
+
+impl Shape for Capsule {
+    fn translate(&mut self, v: Vec3) {
+        self.a += v;
+        self.b += v;
+    }
+    fn type_of(&self) -> &'static str {
+        "Capsule"
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ConvexHull {
+    pub c: Pos3,
+    pub axes: Mat3,
+    // Hull vertices, relative to `c` in the space spanned by `axes`.
+    // Unlike a Box's, these axes may be scaled.
+    pub points: Vec<Vec3>,
+}
+
+impl Shape for ConvexHull {
+    fn translate(&mut self, v: Vec3) {
+        self.c += v;
+    }
+    fn type_of(&self) -> &'static str {
+        "ConvexHull"
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub struct Ray {
     pub p: Pos3,
@@ -87,6 +123,54 @@ impl Shape for Ray {
     }
 }
 
+/// The six planes bounding what a camera can see, with normals facing in
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Frustum {
+    // Left, right, bottom, top, near, far
+    pub planes: [Plane; 6],
+}
+
+impl Frustum {
+    /// Pull the planes out of a `proj * view` matrix (OpenGL depth range)
+    pub fn from_matrix(m: Mat4) -> Self {
+        let row = |i: usize| cgmath::Vector4::new(m.x[i], m.y[i], m.z[i], m.w[i]);
+        // A point p is inside a plane row (a, b, c, w) when a*p.x + b*p.y +
+        // c*p.z + w >= 0, i.e. n.p >= -w, so d is -w once normalized
+        let plane = |v: cgmath::Vector4<f32>| {
+            let len = v.truncate().magnitude();
+            Plane {
+                n: v.truncate() / len,
+                d: -v.w / len,
+            }
+        };
+        let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+        Self {
+            planes: [
+                plane(r3 + r0),
+                plane(r3 - r0),
+                plane(r3 + r1),
+                plane(r3 - r1),
+                plane(r3 + r2),
+                plane(r3 - r2),
+            ],
+        }
+    }
+    /// True unless the sphere is entirely outside some plane.  Spheres near
+    /// the frustum's corners can give false positives, which is fine for culling.
+    pub fn touching_sphere(&self, s: &Sphere) -> bool {
+        self.planes.iter().all(|p| s.c.dot(p.n) - p.d >= -s.r)
+    }
+    pub fn touching_aabb(&self, b: &AABB) -> bool {
+        self.planes.iter().all(|p| {
+            // How far the box reaches along the plane normal
+            let reach = b.half_sizes.x * p.n.x.abs()
+                + b.half_sizes.y * p.n.y.abs()
+                + b.half_sizes.z * p.n.z.abs();
+            b.c.dot(p.n) - p.d >= -reach
+        })
+    }
+}
+
 pub trait Collide<S: Shape>: Shape {
     fn touching(&self, s2: &S) -> bool {
         self.disp(s2).is_some()
@@ -136,9 +220,50 @@ impl Collide<Plane> for Sphere {
     }
 }
 
-type CastHit = Option<(Pos3, f32)>;
+impl Collide<Plane> for Capsule {
+    fn disp(&self, p: &Plane) -> Option<Vec3> {
+        // Same as a sphere, using whichever end is deeper
+        let da = self.a.dot(p.n) - p.d;
+        let db = self.b.dot(p.n) - p.d;
+        let (dist, other) = if da < db { (da, db) } else { (db, da) };
+        // The far end can be above the plane while the near one is
+        // well below it, so check the whole span against the radius.
+        if dist <= self.r && other >= -self.r {
+            Some(p.n * (self.r - dist))
+        } else {
+            None
+        }
+    }
+}
+
+// Every other pair goes through GJK/EPA; the sphere impls above are
+// cheaper, so they stay as fast paths.
+macro_rules! collide_via_gjk {
+    ($($s1:ty => $($s2:ty),+);+ $(;)?) => {
+        $($(
+            impl Collide<$s2> for $s1 {
+                fn touching(&self, s2: &$s2) -> bool {
+                    crate::gjk::intersect(self, s2)
+                }
+                fn disp(&self, s2: &$s2) -> Option<Vec3> {
+                    crate::gjk::penetration(self, s2)
+                }
+            }
+        )+)+
+    };
+}
+
+collide_via_gjk! {
+    Sphere => Box, AABB, Capsule, ConvexHull;
+    Box => Sphere, Box, AABB, Capsule, ConvexHull;
+    AABB => Sphere, Box, AABB, Capsule, ConvexHull;
+    Capsule => Sphere, Box, AABB, Capsule, ConvexHull;
+    ConvexHull => Sphere, Box, AABB, Capsule, ConvexHull;
+}
+
+pub type CastHit = Option<(Pos3, f32)>;
 
-trait Cast<S: Shape> {
+pub trait Cast<S: Shape> {
     fn cast(&self, s: &S) -> CastHit;
 }
 
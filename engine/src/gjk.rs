@@ -0,0 +1,315 @@
+use crate::geom::*;
+
+// GJK + EPA narrowphase for any pair of convex shapes.
+// Based on:
+// https://blog.winter.dev/2020/gjk-algorithm/
+// https://blog.winter.dev/2020/epa-algorithm/
+
+const GJK_MAX_ITERS: usize = 64;
+const EPA_MAX_ITERS: usize = 64;
+const EPA_TOLERANCE: f32 = 0.0001;
+
+pub trait Support {
+    /// The point of the shape furthest along `d`
+    fn support(&self, d: Vec3) -> Pos3;
+}
+
+// A zero direction has no furthest point, so just pick one
+fn dir_or_x(d: Vec3) -> Vec3 {
+    if d != Vec3::zero() {
+        d.normalize()
+    } else {
+        Vec3::unit_x()
+    }
+}
+
+impl Support for Sphere {
+    fn support(&self, d: Vec3) -> Pos3 {
+        self.c + dir_or_x(d) * self.r
+    }
+}
+
+impl Support for Box {
+    fn support(&self, d: Vec3) -> Pos3 {
+        let mut p = self.c;
+        for i in 0..3 {
+            let axis = self.axes[i];
+            p += axis * self.half_sizes[i].copysign(axis.dot(d));
+        }
+        p
+    }
+}
+
+impl Support for AABB {
+    fn support(&self, d: Vec3) -> Pos3 {
+        self.c
+            + Vec3::new(
+                self.half_sizes.x.copysign(d.x),
+                self.half_sizes.y.copysign(d.y),
+                self.half_sizes.z.copysign(d.z),
+            )
+    }
+}
+
+impl Support for Capsule {
+    fn support(&self, d: Vec3) -> Pos3 {
+        let end = if self.a.to_vec().dot(d) >= self.b.to_vec().dot(d) {
+            self.a
+        } else {
+            self.b
+        };
+        end + dir_or_x(d) * self.r
+    }
+}
+
+impl Support for ConvexHull {
+    fn support(&self, d: Vec3) -> Pos3 {
+        // Bring d into the hull's frame instead of moving every point out of it
+        let local_d = self.axes.transpose() * d;
+        let best = self
+            .points
+            .iter()
+            .copied()
+            .max_by(|p, q| p.dot(local_d).partial_cmp(&q.dot(local_d)).unwrap())
+            .unwrap_or_else(Vec3::zero);
+        self.c + self.axes * best
+    }
+}
+
+// Support point of the Minkowski difference a - b
+fn support<A: Support, B: Support>(a: &A, b: &B, d: Vec3) -> Vec3 {
+    a.support(d) - b.support(-d)
+}
+
+// Newest point is always at index 0
+struct Simplex {
+    pts: [Vec3; 4],
+    len: usize,
+}
+
+impl Simplex {
+    fn push_front(&mut self, p: Vec3) {
+        self.pts = [p, self.pts[0], self.pts[1], self.pts[2]];
+        self.len = (self.len + 1).min(4);
+    }
+    fn set(&mut self, pts: &[Vec3]) {
+        self.pts[..pts.len()].copy_from_slice(pts);
+        self.len = pts.len();
+    }
+}
+
+fn same_direction(d: Vec3, ao: Vec3) -> bool {
+    d.dot(ao) > 0.0
+}
+
+// Any vector perpendicular to v
+fn perpendicular(v: Vec3) -> Vec3 {
+    let other = if v.x.abs() < 0.57 {
+        Vec3::unit_x()
+    } else {
+        Vec3::unit_y()
+    };
+    v.cross(other)
+}
+
+fn line(s: &mut Simplex, d: &mut Vec3) -> bool {
+    let (a, b) = (s.pts[0], s.pts[1]);
+    let ab = b - a;
+    let ao = -a;
+    if same_direction(ab, ao) {
+        *d = ab.cross(ao).cross(ab);
+        if d.magnitude2() < f32::EPSILON {
+            // The origin is on the segment; any sideways direction will
+            // let us keep building up a full tetrahedron for EPA.
+            *d = perpendicular(ab);
+        }
+    } else {
+        s.set(&[a]);
+        *d = ao;
+    }
+    false
+}
+
+fn triangle(s: &mut Simplex, d: &mut Vec3) -> bool {
+    let (a, b, c) = (s.pts[0], s.pts[1], s.pts[2]);
+    let ab = b - a;
+    let ac = c - a;
+    let ao = -a;
+    let abc = ab.cross(ac);
+    if same_direction(abc.cross(ac), ao) {
+        if same_direction(ac, ao) {
+            s.set(&[a, c]);
+            *d = ac.cross(ao).cross(ac);
+            false
+        } else {
+            s.set(&[a, b]);
+            line(s, d)
+        }
+    } else if same_direction(ab.cross(abc), ao) {
+        s.set(&[a, b]);
+        line(s, d)
+    } else if same_direction(abc, ao) {
+        *d = abc;
+        false
+    } else {
+        s.set(&[a, c, b]);
+        *d = -abc;
+        false
+    }
+}
+
+fn tetrahedron(s: &mut Simplex, d: &mut Vec3) -> bool {
+    let (a, b, c, dd) = (s.pts[0], s.pts[1], s.pts[2], s.pts[3]);
+    let ab = b - a;
+    let ac = c - a;
+    let ad = dd - a;
+    let ao = -a;
+    let abc = ab.cross(ac);
+    let acd = ac.cross(ad);
+    let adb = ad.cross(ab);
+    if same_direction(abc, ao) {
+        s.set(&[a, b, c]);
+        return triangle(s, d);
+    }
+    if same_direction(acd, ao) {
+        s.set(&[a, c, dd]);
+        return triangle(s, d);
+    }
+    if same_direction(adb, ao) {
+        s.set(&[a, dd, b]);
+        return triangle(s, d);
+    }
+    true
+}
+
+fn next_simplex(s: &mut Simplex, d: &mut Vec3) -> bool {
+    match s.len {
+        2 => line(s, d),
+        3 => triangle(s, d),
+        4 => tetrahedron(s, d),
+        _ => unreachable!(),
+    }
+}
+
+// Returns a tetrahedron around the origin if a and b overlap
+fn gjk<A: Support, B: Support>(a: &A, b: &B) -> Option<[Vec3; 4]> {
+    let first = support(a, b, Vec3::unit_x());
+    let mut s = Simplex {
+        pts: [first, Vec3::zero(), Vec3::zero(), Vec3::zero()],
+        len: 1,
+    };
+    let mut d = -first;
+    if d.magnitude2() < f32::EPSILON {
+        d = Vec3::unit_y();
+    }
+    for _ in 0..GJK_MAX_ITERS {
+        let p = support(a, b, d);
+        if p.dot(d) < 0.0 {
+            // We couldn't get past the origin, so it isn't in a - b
+            return None;
+        }
+        s.push_front(p);
+        if next_simplex(&mut s, &mut d) {
+            return Some(s.pts);
+        }
+    }
+    None
+}
+
+/// Do the two convex shapes overlap?
+pub fn intersect<A: Support, B: Support>(a: &A, b: &B) -> bool {
+    gjk(a, b).is_some()
+}
+
+// Unit normal and distance from the origin for a polytope face, going by
+// its winding
+fn face_normal(polytope: &[Vec3], face: [usize; 3]) -> (Vec3, f32) {
+    let (a, b, c) = (polytope[face[0]], polytope[face[1]], polytope[face[2]]);
+    let n = (b - a).cross(c - a);
+    if n.magnitude2() < f32::EPSILON * f32::EPSILON {
+        // Degenerate face; never pick it as the closest one
+        return (Vec3::zero(), f32::MAX);
+    }
+    let n = n.normalize();
+    (n, n.dot(a))
+}
+
+fn add_unique_edge(edges: &mut Vec<(usize, usize)>, a: usize, b: usize) {
+    // An edge shared by two removed faces is interior to the hole
+    if let Some(i) = edges.iter().position(|&e| e == (b, a)) {
+        edges.swap_remove(i);
+    } else {
+        edges.push((a, b));
+    }
+}
+
+/// How far, and in which direction, would `b` have to move to stop
+/// overlapping `a`?  Same convention as `Collide::disp` between spheres.
+pub fn penetration<A: Support, B: Support>(a: &A, b: &B) -> Option<Vec3> {
+    let simplex = gjk(a, b)?;
+    let mut polytope = simplex.to_vec();
+    let mut faces = vec![[0, 1, 2], [0, 3, 1], [0, 2, 3], [1, 3, 2]];
+    // Wind every face away from the vertex it doesn't use.  Going by the
+    // origin instead isn't safe, since it can sit right on a face.
+    for (face, other) in faces.iter_mut().zip([3, 2, 1, 0].iter()) {
+        let (a, b, c) = (polytope[face[0]], polytope[face[1]], polytope[face[2]]);
+        if (b - a).cross(c - a).dot(polytope[*other] - a) > 0.0 {
+            face.swap(1, 2);
+        }
+    }
+    let mut normals: Vec<(Vec3, f32)> = faces.iter().map(|&f| face_normal(&polytope, f)).collect();
+    let mut edges = vec![];
+    for _ in 0..EPA_MAX_ITERS {
+        let &(n, dist) = normals
+            .iter()
+            .min_by(|(_, d1), (_, d2)| d1.partial_cmp(d2).unwrap())?;
+        if dist == f32::MAX {
+            // Nothing but flat faces: the shapes are just touching
+            return None;
+        }
+        let p = support(a, b, n);
+        if p.dot(n) - dist < EPA_TOLERANCE {
+            return if dist > EPA_TOLERANCE {
+                Some(n * dist)
+            } else {
+                None
+            };
+        }
+        // Cut out every face the new point can see and stitch the hole
+        // closed with faces that include the new point.
+        edges.clear();
+        let mut i = 0;
+        while i < faces.len() {
+            let (fnorm, _) = normals[i];
+            if fnorm.dot(p - polytope[faces[i][0]]) > 0.0 {
+                let f = faces[i];
+                add_unique_edge(&mut edges, f[0], f[1]);
+                add_unique_edge(&mut edges, f[1], f[2]);
+                add_unique_edge(&mut edges, f[2], f[0]);
+                faces.swap_remove(i);
+                normals.swap_remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        if edges.is_empty() {
+            // Numerical trouble; the closest face is as good as it gets
+            return Some(n * dist);
+        }
+        polytope.push(p);
+        let pi = polytope.len() - 1;
+        // Edges keep the winding of the faces they came from, so the new
+        // faces face outwards too.
+        for &(e0, e1) in edges.iter() {
+            let face = [e0, e1, pi];
+            normals.push(face_normal(&polytope, face));
+            faces.push(face);
+        }
+    }
+    // Out of iterations; report the best guess so far
+    normals
+        .iter()
+        .filter(|(_, d)| *d != f32::MAX)
+        .min_by(|(_, d1), (_, d2)| d1.partial_cmp(d2).unwrap())
+        .map(|&(n, d)| n * d)
+}
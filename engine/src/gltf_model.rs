@@ -0,0 +1,491 @@
+use anyhow::*;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::animation::{
+    Channel, Clip, Interpolation, Joint, Keyframes, Skeleton, Transform, MAX_JOINTS,
+};
+use crate::geom::*;
+use crate::model::*;
+use crate::texture;
+
+// glTF models come in as the same Model/Mesh/Material as OBJ files do.
+// Every primitive becomes a Mesh, with its node's transform baked into the
+// vertices, so a multi-part model still draws as one instance.  Skinned
+// primitives are left in the bind pose, and the skin and its animations
+// come along as the model's skeleton and clips.
+
+impl Model {
+    /// Load a `.gltf` (with its `.bin` and textures alongside) or a `.glb`.
+    /// Skins can have at most MAX_JOINTS joints; bigger ones are an error.
+    pub fn load_gltf<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        path: P,
+    ) -> Result<Self> {
+        let (document, buffers, images) = gltf::import(path.as_ref())?;
+
+        // Textures are converted as materials ask for them, since the same
+        // image can be a color in one place and a normal map in another
+        let load_texture = |info: Option<gltf::texture::Texture>, linear: bool| {
+            info.map(|t| -> Result<Rc<texture::Texture>> {
+                let index = t.source().index();
+                let img = to_image(&images[index])?;
+                let label = format!("{} image {}", path.as_ref().display(), index);
+                let format = if linear {
+                    wgpu::TextureFormat::Rgba8Unorm
+                } else {
+                    wgpu::TextureFormat::Rgba8UnormSrgb
+                };
+                Ok(Rc::new(
+                    texture::Texture::from_image_with_format(
+                        device,
+                        queue,
+                        &img,
+                        Some(&label),
+                        format,
+                    )?
+                    .with_sampler(device, &sampler_settings(&t.sampler())),
+                ))
+            })
+            .transpose()
+        };
+
+        let mut materials = Vec::new();
+        for mat in document.materials() {
+            let pbr = mat.pbr_metallic_roughness();
+            let maps = MaterialMaps {
+                diffuse: load_texture(pbr.base_color_texture().map(|i| i.texture()), false)?,
+                normal: load_texture(mat.normal_texture().map(|i| i.texture()), true)?,
+                specular: load_texture(
+                    pbr.metallic_roughness_texture().map(|i| i.texture()),
+                    true,
+                )?,
+                emissive: load_texture(mat.emissive_texture().map(|i| i.texture()), false)?,
+            };
+            let name = mat
+                .name()
+                .map(String::from)
+                .unwrap_or_else(|| format!("material {}", materials.len()));
+            materials.push(Material::with_params(
+                device,
+                queue,
+                layout,
+                name,
+                pbr_params(&mat),
+                maps,
+            ));
+        }
+        // For primitives without a material
+        let default_material = materials.len();
+        materials.push(Material::with_params(
+            device,
+            queue,
+            layout,
+            "default".to_string(),
+            MaterialParams::default(),
+            MaterialMaps::default(),
+        ));
+
+        let scene = document
+            .default_scene()
+            .or_else(|| document.scenes().next())
+            .context("glTF file has no scenes")?;
+        // Depth first, carrying each node's transform down to its children
+        let mut globals = vec![Mat4::identity(); document.nodes().len()];
+        let mut parents = vec![None; document.nodes().len()];
+        let mut mesh_nodes = vec![];
+        let mut stack: Vec<(gltf::Node, Mat4)> =
+            scene.nodes().map(|n| (n, Mat4::identity())).collect();
+        while let Some((node, parent)) = stack.pop() {
+            let transform = parent * Mat4::from(node.transform().matrix());
+            globals[node.index()] = transform;
+            for c in node.children() {
+                parents[c.index()] = Some(node.index());
+                stack.push((c, transform));
+            }
+            if node.mesh().is_some() {
+                mesh_nodes.push(node);
+            }
+        }
+
+        // Only one skin per model; meshes bound to any others are drawn as
+        // they sit in the bind pose
+        let skin = mesh_nodes
+            .iter()
+            .find_map(|n| n.skin())
+            .or_else(|| document.skins().next());
+        // The shaders only have room for MAX_JOINTS matrices, and vertices
+        // pointing past them would come out mangled
+        if let Some(skin) = &skin {
+            let joints = skin.joints().count();
+            ensure!(
+                joints <= MAX_JOINTS,
+                "{}: skin has {} joints, but at most {} are supported",
+                path.as_ref().display(),
+                joints,
+                MAX_JOINTS
+            );
+        }
+        let skeleton = skin
+            .as_ref()
+            .map(|skin| load_skeleton(skin, &buffers, &globals, &parents));
+        let clips = match &skin {
+            Some(skin) => document
+                .animations()
+                .map(|anim| load_clip(&anim, skin, &buffers))
+                .collect(),
+            None => vec![],
+        };
+
+        let mut meshes = Vec::new();
+        for node in mesh_nodes {
+            let mesh = node.mesh().unwrap();
+            let skinned = skin.is_some()
+                && node.skin().map(|s| s.index()) == skin.as_ref().map(|s| s.index());
+            // Skinned vertices get put in place by their joints, so the
+            // node's own transform doesn't apply
+            let transform = if skinned {
+                Mat4::identity()
+            } else {
+                globals[node.index()]
+            };
+            let normal_matrix = {
+                let m = Mat3::from_cols(
+                    transform.x.truncate(),
+                    transform.y.truncate(),
+                    transform.z.truncate(),
+                );
+                m.invert().unwrap_or(m).transpose()
+            };
+            for prim in mesh.primitives() {
+                if prim.mode() != gltf::mesh::Mode::Triangles {
+                    continue;
+                }
+                let reader = prim.reader(|b| Some(&buffers[b.index()].0[..]));
+                let positions: Vec<Vec3> = match reader.read_positions() {
+                    Some(ps) => ps
+                        .map(|p| (transform * Vec3::from(p).extend(1.0)).truncate())
+                        .collect(),
+                    None => continue,
+                };
+                let indices: Vec<u32> = match reader.read_indices() {
+                    Some(is) => is.into_u32().collect(),
+                    None => (0..positions.len() as u32).collect(),
+                };
+                let normals: Vec<Vec3> = match reader.read_normals() {
+                    Some(ns) => ns
+                        .map(|n| (normal_matrix * Vec3::from(n)).normalize())
+                        .collect(),
+                    None => smooth_normals(&positions, &indices),
+                };
+                let tex_coords: Vec<[f32; 2]> = match reader.read_tex_coords(0) {
+                    Some(uvs) => uvs.into_f32().collect(),
+                    None => vec![[0.0, 0.0]; positions.len()],
+                };
+                let mut vertices: Vec<ModelVertex> = positions
+                    .iter()
+                    .zip(normals.iter())
+                    .zip(tex_coords.iter())
+                    .map(|((p, n), uv)| ModelVertex::new((*p).into(), *uv, (*n).into()))
+                    .collect();
+                if skinned {
+                    if let (Some(joints), Some(weights)) =
+                        (reader.read_joints(0), reader.read_weights(0))
+                    {
+                        for (v, (j, w)) in vertices
+                            .iter_mut()
+                            .zip(joints.into_u16().zip(weights.into_f32()))
+                        {
+                            let j = [j[0] as u32, j[1] as u32, j[2] as u32, j[3] as u32];
+                            *v = v.with_skin(j, normalize_weights(w));
+                        }
+                    }
+                }
+                compute_tangents(&mut vertices, &indices);
+                let name = match mesh.name() {
+                    Some(name) => format!("{} {}", name, prim.index()),
+                    None => format!("mesh {} {}", mesh.index(), prim.index()),
+                };
+                meshes.push(Mesh::new(
+                    device,
+                    name,
+                    &vertices,
+                    &indices,
+                    prim.material().index().unwrap_or(default_material),
+                ));
+            }
+        }
+
+        Ok(Self {
+            skeleton,
+            clips,
+            ..Self::new(meshes, materials)
+        })
+    }
+
+    // The external buffers and images a glTF file points to, for hot
+    // reloading.  Embedded data doesn't count.
+    pub(crate) fn gltf_dependencies(path: &Path) -> Vec<std::path::PathBuf> {
+        let folder = path.parent().unwrap_or_else(|| Path::new(""));
+        let gltf = match gltf::Gltf::open(path).ok() {
+            Some(gltf) => gltf,
+            None => return vec![],
+        };
+        let buffers = gltf.buffers().filter_map(|b| match b.source() {
+            gltf::buffer::Source::Uri(uri) => Some(uri),
+            gltf::buffer::Source::Bin => None,
+        });
+        let images = gltf.images().filter_map(|i| match i.source() {
+            gltf::image::Source::Uri { uri, .. } => Some(uri),
+            gltf::image::Source::View { .. } => None,
+        });
+        buffers
+            .chain(images)
+            .filter(|uri| !uri.starts_with("data:"))
+            .map(|uri| folder.join(uri))
+            .collect()
+    }
+}
+
+fn load_skeleton(
+    skin: &gltf::Skin,
+    buffers: &[gltf::buffer::Data],
+    globals: &[Mat4],
+    parents: &[Option<usize>],
+) -> Skeleton {
+    let nodes: Vec<usize> = skin.joints().map(|n| n.index()).collect();
+    let inverse_binds: Vec<Mat4> = skin
+        .reader(|b| Some(&buffers[b.index()].0[..]))
+        .read_inverse_bind_matrices()
+        .map(|ms| ms.map(Mat4::from).collect())
+        .unwrap_or_default();
+    let joints: Vec<Joint> = skin
+        .joints()
+        .enumerate()
+        .map(|(i, node)| {
+            let (t, r, s) = node.transform().decomposed();
+            Joint {
+                name: node
+                    .name()
+                    .map(String::from)
+                    .unwrap_or_else(|| format!("joint {}", i)),
+                parent: parents[node.index()].and_then(|p| nodes.iter().position(|&n| n == p)),
+                inverse_bind: inverse_binds.get(i).copied().unwrap_or_else(Mat4::identity),
+                rest: Transform {
+                    translation: t.into(),
+                    rotation: Quat::new(r[3], r[0], r[1], r[2]),
+                    scale: s.into(),
+                },
+            }
+        })
+        .collect();
+    // Whatever's above the first root joint, which isn't a joint itself
+    let root = joints
+        .iter()
+        .zip(nodes.iter())
+        .find(|(j, _)| j.parent.is_none())
+        .and_then(|(_, &n)| parents[n])
+        .map_or_else(Mat4::identity, |p| globals[p]);
+    Skeleton::new(joints, root)
+}
+
+// Keeps the channels that move the skin's joints; morph target weights
+// aren't supported
+fn load_clip(anim: &gltf::Animation, skin: &gltf::Skin, buffers: &[gltf::buffer::Data]) -> Clip {
+    use gltf::animation::util::ReadOutputs;
+    use gltf::animation::Interpolation as I;
+    let nodes: Vec<usize> = skin.joints().map(|n| n.index()).collect();
+    let mut channels = vec![];
+    for ch in anim.channels() {
+        let joint = match nodes.iter().position(|&n| n == ch.target().node().index()) {
+            Some(j) => j,
+            None => continue,
+        };
+        let reader = ch.reader(|b| Some(&buffers[b.index()].0[..]));
+        let times: Vec<f32> = match reader.read_inputs() {
+            Some(ts) => ts.collect(),
+            None => continue,
+        };
+        let interpolation = ch.sampler().interpolation();
+        // Cubic splines store an in tangent, the value, and an out tangent
+        // for each key; use the values and go between them in straight lines
+        let values = |n: usize| -> Vec<usize> {
+            match interpolation {
+                I::CubicSpline => (0..n / 3).map(|i| i * 3 + 1).collect(),
+                _ => (0..n).collect(),
+            }
+        };
+        let keys = match reader.read_outputs() {
+            Some(ReadOutputs::Translations(ts)) => {
+                let ts: Vec<Vec3> = ts.map(Vec3::from).collect();
+                Keyframes::Translation(values(ts.len()).into_iter().map(|i| ts[i]).collect())
+            }
+            Some(ReadOutputs::Rotations(rs)) => {
+                let rs: Vec<Quat> = rs
+                    .into_f32()
+                    .map(|r| Quat::new(r[3], r[0], r[1], r[2]))
+                    .collect();
+                Keyframes::Rotation(values(rs.len()).into_iter().map(|i| rs[i]).collect())
+            }
+            Some(ReadOutputs::Scales(ss)) => {
+                let ss: Vec<Vec3> = ss.map(Vec3::from).collect();
+                Keyframes::Scale(values(ss.len()).into_iter().map(|i| ss[i]).collect())
+            }
+            _ => continue,
+        };
+        let key_count = match &keys {
+            Keyframes::Translation(ks) | Keyframes::Scale(ks) => ks.len(),
+            Keyframes::Rotation(ks) => ks.len(),
+        };
+        if key_count != times.len() {
+            continue;
+        }
+        channels.push(Channel {
+            joint,
+            interpolation: match interpolation {
+                I::Step => Interpolation::Step,
+                _ => Interpolation::Linear,
+            },
+            times,
+            keys,
+        });
+    }
+    let duration = channels
+        .iter()
+        .filter_map(|c| c.times.last().copied())
+        .fold(0.0, f32::max);
+    Clip {
+        name: anim
+            .name()
+            .map(String::from)
+            .unwrap_or_else(|| format!("animation {}", anim.index())),
+        duration,
+        channels,
+    }
+}
+
+// Weights are meant to add up to one already, but exporters round them
+fn normalize_weights(w: [f32; 4]) -> [f32; 4] {
+    let sum: f32 = w.iter().sum();
+    if sum > 0.0 {
+        [w[0] / sum, w[1] / sum, w[2] / sum, w[3] / sum]
+    } else {
+        w
+    }
+}
+
+fn pbr_params(mat: &gltf::Material) -> MaterialParams {
+    let pbr = mat.pbr_metallic_roughness();
+    let [r, g, b, a] = pbr.base_color_factor();
+    let base = Vec3::new(r, g, b);
+    let metallic = pbr.metallic_factor();
+    let roughness = pbr.roughness_factor();
+    // The Phong params are only used if someone switches the shading back,
+    // so they just need to be about right.  Non-metals reflect about 4%
+    // head on; metals reflect their color.
+    let dielectric = Vec3::new(0.04, 0.04, 0.04);
+    let alpha = (roughness * roughness).max(0.01);
+    MaterialParams {
+        shading: Shading::Pbr,
+        ambient: Vec3::new(1.0, 1.0, 1.0),
+        diffuse: base,
+        specular: dielectric + (base - dielectric) * metallic,
+        shininess: (2.0 / (alpha * alpha) - 2.0).clamp(1.0, 1024.0),
+        dissolve: match mat.alpha_mode() {
+            gltf::material::AlphaMode::Opaque => 1.0,
+            _ => a,
+        },
+        transparent: mat.alpha_mode() == gltf::material::AlphaMode::Blend,
+        emissive: mat.emissive_factor().into(),
+        metallic,
+        roughness,
+    }
+}
+
+fn sampler_settings(sampler: &gltf::texture::Sampler) -> texture::SamplerSettings {
+    use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+    use wgpu::{AddressMode, FilterMode};
+    let wrap = |mode| match mode {
+        WrappingMode::ClampToEdge => AddressMode::ClampToEdge,
+        WrappingMode::MirroredRepeat => AddressMode::MirrorRepeat,
+        WrappingMode::Repeat => AddressMode::Repeat,
+    };
+    // Textures always get mips here, so the plain filters just pick a
+    // matching filter between mips
+    let (min_filter, mipmap_filter) = match sampler.min_filter() {
+        Some(MinFilter::Nearest) | Some(MinFilter::NearestMipmapNearest) => {
+            (FilterMode::Nearest, FilterMode::Nearest)
+        }
+        Some(MinFilter::NearestMipmapLinear) => (FilterMode::Nearest, FilterMode::Linear),
+        Some(MinFilter::LinearMipmapNearest) => (FilterMode::Linear, FilterMode::Nearest),
+        _ => (FilterMode::Linear, FilterMode::Linear),
+    };
+    texture::SamplerSettings {
+        address_mode_u: wrap(sampler.wrap_s()),
+        address_mode_v: wrap(sampler.wrap_t()),
+        mag_filter: match sampler.mag_filter() {
+            Some(MagFilter::Nearest) => FilterMode::Nearest,
+            _ => FilterMode::Linear,
+        },
+        min_filter,
+        mipmap_filter,
+        ..texture::SamplerSettings::default()
+    }
+}
+
+// Area weighted vertex normals, for primitives that don't come with any
+fn smooth_normals(positions: &[Vec3], indices: &[u32]) -> Vec<Vec3> {
+    let mut normals = vec![Vec3::zero(); positions.len()];
+    for tri in indices.chunks_exact(3) {
+        let [a, b, c] = [tri[0] as usize, tri[1] as usize, tri[2] as usize];
+        let n = (positions[b] - positions[a]).cross(positions[c] - positions[a]);
+        normals[a] += n;
+        normals[b] += n;
+        normals[c] += n;
+    }
+    normals
+        .into_iter()
+        .map(|n| {
+            if n.magnitude2() > 0.0 {
+                n.normalize()
+            } else {
+                Vec3::unit_y()
+            }
+        })
+        .collect()
+}
+
+// Decoded glTF images can have 1 to 4 channels of 8 or 16 bits
+fn to_image(data: &gltf::image::Data) -> Result<image::DynamicImage> {
+    use gltf::image::Format;
+    let (channels, bytes) = match data.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 | Format::B8G8R8 => (3, 1),
+        Format::R8G8B8A8 | Format::B8G8R8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+    };
+    let mut rgba = Vec::with_capacity((data.width * data.height * 4) as usize);
+    for px in data.pixels.chunks_exact(channels * bytes) {
+        // Keep the high byte of 16 bit channels, which are little endian
+        let c = |i: usize| px[i * bytes + bytes - 1];
+        let mut out = match channels {
+            1 => [c(0), c(0), c(0), 255],
+            2 => [c(0), c(1), 0, 255],
+            3 => [c(0), c(1), c(2), 255],
+            _ => [c(0), c(1), c(2), c(3)],
+        };
+        if let Format::B8G8R8 | Format::B8G8R8A8 = data.format {
+            out.swap(0, 2);
+        }
+        rgba.extend_from_slice(&out);
+    }
+    image::RgbaImage::from_raw(data.width, data.height, rgba)
+        .map(image::DynamicImage::ImageRgba8)
+        .context("glTF image was the wrong size")
+}
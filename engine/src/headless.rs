@@ -0,0 +1,95 @@
+use anyhow::Result;
+use std::path::Path;
+
+use crate::assets::{Assets, ModelRef};
+use crate::debug_draw::DebugDraw;
+use crate::render::{InstanceGroups, Render};
+
+/// Renders without a window or a `Game`, e.g. for golden-image tests in CI.
+/// Draw a frame with `render`, then grab it with `read_pixels`.
+pub struct Headless {
+    render: Render,
+    pub assets: Assets,
+    // Nothing here expires on its own, since there's no update loop; call
+    // `clear` between frames
+    pub debug: DebugDraw,
+}
+
+impl Headless {
+    pub fn new(width: u32, height: u32, asset_root: impl AsRef<Path>) -> Result<Self> {
+        let render = futures::executor::block_on(Render::headless(width, height))?;
+        Ok(Self {
+            render,
+            assets: Assets::new(asset_root),
+            debug: DebugDraw::default(),
+        })
+    }
+    pub fn load_model(&mut self, model: impl AsRef<Path>) -> ModelRef {
+        self.assets.load_model(
+            &self.render.device,
+            &self.render.queue,
+            &self.render.texture_layout,
+            model,
+        )
+    }
+    pub fn camera_mut(&mut self) -> &mut crate::camera::Camera {
+        &mut self.render.camera
+    }
+    pub fn set_ambient(&mut self, amb: f32) {
+        self.render.set_ambient(amb);
+    }
+    pub fn set_lights(&mut self, lights: impl IntoIterator<Item = crate::lights::Light>) {
+        self.render.set_lights(lights.into_iter().collect());
+    }
+    pub fn set_shadow_extent(&mut self, extent: f32) {
+        self.render.shadow_extent = extent;
+    }
+    pub fn set_render_settings(&mut self, settings: crate::render::RenderSettings) {
+        self.render.set_settings(settings);
+    }
+    pub fn set_environment(&mut self, faces: [impl AsRef<Path>; 6]) -> Result<()> {
+        let root = self.assets.asset_root();
+        let faces: Vec<_> = faces.iter().map(|f| root.join(f)).collect();
+        self.render.load_environment(&faces)
+    }
+    pub fn set_environment_equirect(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = self.assets.asset_root().join(path);
+        self.render.load_environment_equirect(path)
+    }
+    pub fn set_sky_gradient(&mut self, sky: [u8; 3], horizon: [u8; 3], ground: [u8; 3]) {
+        self.render.set_sky_gradient(sky, horizon, ground);
+    }
+    pub fn set_background(&mut self, background: crate::render::Background) {
+        self.render.set_background(background);
+    }
+    pub fn set_post_settings(&mut self, settings: crate::post::PostSettings) {
+        self.render.set_post_settings(settings);
+    }
+    pub fn add_post_pass(&mut self, label: &str, spirv: &[u8]) -> Result<crate::post::PostPassId> {
+        self.render.add_post_pass(label, spirv)
+    }
+    pub fn set_post_pass_params(&mut self, id: crate::post::PostPassId, params: [f32; 4]) {
+        self.render.set_post_pass_params(id, params);
+    }
+    pub fn remove_post_pass(&mut self, id: crate::post::PostPassId) -> bool {
+        self.render.remove_post_pass(id)
+    }
+    pub fn culling_stats(&self) -> crate::render::CullingStats {
+        self.render.culling_stats()
+    }
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.render
+            .resize(winit::dpi::PhysicalSize::new(width, height));
+    }
+    /// Draw one frame, with `f` filling in the instances the way
+    /// `Game::render` would
+    pub fn render(&mut self, f: impl FnOnce(&mut InstanceGroups)) -> Result<()> {
+        self.render
+            .render_instances(&mut self.assets, &self.debug, f)
+            .map_err(|e| anyhow::anyhow!("{:?}", e))
+    }
+    /// The last frame drawn, as RGBA
+    pub fn read_pixels(&self) -> Result<image::RgbaImage> {
+        self.render.read_pixels()
+    }
+}
@@ -0,0 +1,255 @@
+use crate::geom::*;
+
+// Quickhull, stopped early once the hull has enough vertices.
+// Based on:
+// http://media.steampowered.com/apps/valve/2014/DirkGregorius_ImplementingQuickHull.pdf
+
+const HULL_EPSILON: f32 = 0.0001;
+
+struct Face {
+    verts: [usize; 3],
+    n: Vec3,
+    d: f32,
+    // Indices of points still above this face
+    outside: Vec<usize>,
+}
+
+impl Face {
+    fn new(points: &[Vec3], verts: [usize; 3]) -> Self {
+        let (a, b, c) = (points[verts[0]], points[verts[1]], points[verts[2]]);
+        let n = (b - a).cross(c - a);
+        let n = if n != Vec3::zero() { n.normalize() } else { n };
+        Self {
+            verts,
+            n,
+            d: n.dot(a),
+            outside: vec![],
+        }
+    }
+    fn dist(&self, p: Vec3) -> f32 {
+        self.n.dot(p) - self.d
+    }
+}
+
+// Index of the point furthest along d
+fn extreme(points: &[Vec3], d: Vec3) -> usize {
+    (0..points.len())
+        .max_by(|&i, &j| points[i].dot(d).partial_cmp(&points[j].dot(d)).unwrap())
+        .unwrap()
+}
+
+// Four points spanning as much volume as we can find, or None if
+// everything is (nearly) flat.
+fn initial_tetrahedron(points: &[Vec3]) -> Option<[usize; 4]> {
+    // The widest pair among the axis extremes
+    let mut best = (0, 0, 0.0);
+    for axis in [Vec3::unit_x(), Vec3::unit_y(), Vec3::unit_z()].iter() {
+        let (lo, hi) = (extreme(points, -*axis), extreme(points, *axis));
+        let dist = (points[hi] - points[lo]).magnitude2();
+        if dist > best.2 {
+            best = (lo, hi, dist);
+        }
+    }
+    let (a, b, _) = best;
+    let ab = points[b] - points[a];
+    if ab.magnitude2() < HULL_EPSILON * HULL_EPSILON {
+        return None;
+    }
+    // Furthest from the line ab
+    let c = (0..points.len()).max_by(|&i, &j| {
+        let di = ab.cross(points[i] - points[a]).magnitude2();
+        let dj = ab.cross(points[j] - points[a]).magnitude2();
+        di.partial_cmp(&dj).unwrap()
+    })?;
+    let n = ab.cross(points[c] - points[a]);
+    if n.magnitude() < HULL_EPSILON * ab.magnitude() {
+        return None;
+    }
+    // Furthest from the plane abc
+    let d = (0..points.len()).max_by(|&i, &j| {
+        let di = n.dot(points[i] - points[a]).abs();
+        let dj = n.dot(points[j] - points[a]).abs();
+        di.partial_cmp(&dj).unwrap()
+    })?;
+    if n.normalize().dot(points[d] - points[a]).abs() < HULL_EPSILON {
+        return None;
+    }
+    Some([a, b, c, d])
+}
+
+// Hand each point to the first face it's above, if any
+fn assign_outside(
+    points: &[Vec3],
+    faces: &mut [Face],
+    candidates: impl IntoIterator<Item = usize>,
+) {
+    for i in candidates {
+        if let Some(f) = faces.iter_mut().find(|f| f.dist(points[i]) > HULL_EPSILON) {
+            f.outside.push(i);
+        }
+    }
+}
+
+// Monotone chain hull for points that all lie in one plane (or on a line)
+fn planar_hull(points: &[Vec3], max_verts: usize) -> Vec<Vec3> {
+    let o = points[extreme(points, -Vec3::unit_x())];
+    let far = points[extreme(points, Vec3::unit_x())];
+    let mut u = far - o;
+    if u.magnitude2() < HULL_EPSILON * HULL_EPSILON {
+        u = points[extreme(points, Vec3::unit_y())] - points[extreme(points, -Vec3::unit_y())];
+    }
+    if u.magnitude2() < HULL_EPSILON * HULL_EPSILON {
+        u = points[extreme(points, Vec3::unit_z())] - points[extreme(points, -Vec3::unit_z())];
+    }
+    if u.magnitude2() < HULL_EPSILON * HULL_EPSILON {
+        // Everything is in the same spot
+        return vec![o];
+    }
+    let u = u.normalize();
+    // Any direction in the plane that isn't along u will do for v
+    let off = points
+        .iter()
+        .map(|p| *p - o - u * u.dot(*p - o))
+        .max_by(|a, b| a.magnitude2().partial_cmp(&b.magnitude2()).unwrap())
+        .unwrap();
+    let v = if off.magnitude2() > HULL_EPSILON * HULL_EPSILON {
+        off.normalize()
+    } else {
+        // Collinear
+        Vec3::zero()
+    };
+    let mut flat: Vec<(f32, f32, Vec3)> = points
+        .iter()
+        .map(|p| (u.dot(*p - o), v.dot(*p - o), *p))
+        .collect();
+    flat.sort_by(|a, b| (a.0, a.1).partial_cmp(&(b.0, b.1)).unwrap());
+    let cross = |o: &(f32, f32, Vec3), a: &(f32, f32, Vec3), b: &(f32, f32, Vec3)| {
+        (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0)
+    };
+    let mut hull: Vec<(f32, f32, Vec3)> = vec![];
+    for pass in 0..2 {
+        let start = hull.len();
+        let pts: Vec<_> = if pass == 0 {
+            flat.iter().collect()
+        } else {
+            flat.iter().rev().collect()
+        };
+        for p in pts {
+            while hull.len() >= start + 2
+                && cross(&hull[hull.len() - 2], &hull[hull.len() - 1], p) <= HULL_EPSILON
+            {
+                hull.pop();
+            }
+            hull.push(*p);
+        }
+        // The last point of each chain starts the other one
+        hull.pop();
+    }
+    let hull: Vec<Vec3> = hull.into_iter().map(|(_, _, p)| p).collect();
+    if hull.len() <= max_verts {
+        hull
+    } else {
+        // Thin it out evenly
+        (0..max_verts)
+            .map(|i| hull[i * hull.len() / max_verts])
+            .collect()
+    }
+}
+
+/// Vertices of the convex hull of `points`, at most `max_verts` of them.
+/// When the budget runs out first, the furthest-out points are the ones
+/// kept, so the result is a slightly shrunken hull.
+pub fn quickhull(points: &[Vec3], max_verts: usize) -> Vec<Vec3> {
+    if points.is_empty() {
+        return vec![];
+    }
+    let max_verts = max_verts.max(4);
+    let tet = match initial_tetrahedron(points) {
+        Some(tet) => tet,
+        // Flat input: GJK copes fine with a flat point cloud, so a 2D hull
+        // in its plane is all we need.
+        None => return planar_hull(points, max_verts),
+    };
+    let [a, b, c, d] = tet;
+    let mut faces = vec![];
+    for &(verts, other) in [
+        ([a, b, c], d),
+        ([a, d, b], c),
+        ([a, c, d], b),
+        ([b, d, c], a),
+    ]
+    .iter()
+    {
+        let mut face = Face::new(points, verts);
+        // Wind every face away from the vertex it doesn't use
+        if face.dist(points[other]) > 0.0 {
+            face = Face::new(points, [verts[0], verts[2], verts[1]]);
+        }
+        faces.push(face);
+    }
+    assign_outside(
+        points,
+        &mut faces,
+        (0..points.len()).filter(|i| !tet.contains(i)),
+    );
+
+    let mut hull_verts: Vec<usize> = tet.to_vec();
+    while hull_verts.len() < max_verts {
+        // Grow towards the point furthest outside any face
+        let furthest = faces
+            .iter()
+            .enumerate()
+            .flat_map(|(fi, f)| f.outside.iter().map(move |&pi| (fi, pi)))
+            .max_by(|&(fi, pi), &(fj, pj)| {
+                faces[fi]
+                    .dist(points[pi])
+                    .partial_cmp(&faces[fj].dist(points[pj]))
+                    .unwrap()
+            });
+        let p = match furthest {
+            Some((_, p)) => p,
+            None => break,
+        };
+        // Remove every face p can see, remembering the horizon around them
+        let mut horizon: Vec<(usize, usize)> = vec![];
+        let mut orphans = vec![];
+        let mut i = 0;
+        while i < faces.len() {
+            if faces[i].dist(points[p]) > HULL_EPSILON {
+                let f = faces.swap_remove(i);
+                for &(e0, e1) in [(0, 1), (1, 2), (2, 0)].iter() {
+                    let (v0, v1) = (f.verts[e0], f.verts[e1]);
+                    if let Some(j) = horizon.iter().position(|&e| e == (v1, v0)) {
+                        horizon.swap_remove(j);
+                    } else {
+                        horizon.push((v0, v1));
+                    }
+                }
+                orphans.extend(f.outside.into_iter().filter(|&o| o != p));
+            } else {
+                i += 1;
+            }
+        }
+        let first_new = faces.len();
+        for &(v0, v1) in horizon.iter() {
+            faces.push(Face::new(points, [v0, v1, p]));
+        }
+        assign_outside(points, &mut faces[first_new..], orphans);
+        hull_verts.push(p);
+    }
+    // Each point we added was the furthest along some direction, so they
+    // all lie on the true hull even if we stopped early.
+    hull_verts.into_iter().map(|i| points[i]).collect()
+}
+
+impl ConvexHull {
+    /// A hull around `points`, which are taken to be in the hull's local
+    /// space.  See [`quickhull`] for what `max_verts` does.
+    pub fn new(c: Pos3, axes: Mat3, points: &[Vec3], max_verts: usize) -> Self {
+        Self {
+            c,
+            axes,
+            points: quickhull(points, max_verts),
+        }
+    }
+}
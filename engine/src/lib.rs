@@ -1,4 +1,7 @@
 use pixels::{Pixels, SurfaceTexture};
+use rand::{rngs::StdRng, SeedableRng};
+use std::collections::VecDeque;
+use std::hash::Hasher;
 use std::path::Path;
 use winit::{
     dpi::PhysicalSize,
@@ -7,32 +10,57 @@ use winit::{
     platform::run_return::EventLoopExtRunReturn,
 };
 
+pub mod animation;
 pub mod camera;
 pub mod collision;
+pub mod debug_draw;
 pub mod events;
 pub mod geom;
+pub mod gjk;
+pub mod headless;
+pub mod hull;
 pub mod model;
+pub mod post;
 pub mod texture;
 use events::Events;
 pub mod render;
-use render::{InstanceGroups, Render};
+use render::{Background, InstanceGroups, Render, RenderSettings};
 pub mod assets;
 use assets::Assets;
 pub mod camera_control;
+mod capture;
+mod clusters;
+mod gltf_model;
+pub mod cloth;
+pub mod character;
 pub mod components;
+pub mod determinism;
 pub mod lights;
+pub mod particles;
 pub mod screen;
+pub mod terrain;
 pub mod text;
 pub mod world;
 
 pub const DT: f32 = 1.0 / 60.0;
+// How many state hashes `Engine` holds on to before dropping the oldest
+// (ten minutes' worth of updates)
+pub const MAX_STATE_HASHES: usize = 36000;
 
 pub struct Engine {
     pub frame: usize,
+    // Use this instead of `rand::thread_rng()` so runs can be replayed; see
+    // `set_deterministic`
+    pub rng: StdRng,
+    deterministic: bool,
+    state_hashes: VecDeque<u64>,
+    capture: capture::Capture,
     pub assets: Assets,
     render: Render,
     pub events: Events,
     pub pixels: (Pixels, PhysicalSize<u32>),
+    // Lines and labels drawn over the frame, for debugging
+    pub debug: debug_draw::DebugDraw,
 }
 
 impl Engine {
@@ -44,6 +72,103 @@ impl Engine {
             model,
         )
     }
+    /// Upload `hf` as chunks of `chunk_cells` by `chunk_cells` cells, textured
+    /// with `texture` (relative to the asset root).  Render each returned
+    /// model with an identity transform.
+    pub fn load_terrain(
+        &mut self,
+        name: &str,
+        hf: &terrain::Heightfield,
+        chunk_cells: usize,
+        texture: impl AsRef<Path>,
+    ) -> anyhow::Result<Vec<assets::ModelRef>> {
+        let models = hf.chunk_models(
+            &self.render.device,
+            &self.render.queue,
+            &self.render.texture_layout,
+            chunk_cells,
+            self.assets.asset_root().join(texture),
+        )?;
+        Ok(models
+            .into_iter()
+            .enumerate()
+            .map(|(i, m)| self.assets.insert_model(format!("{}#{}", name, i), m))
+            .collect())
+    }
+    /// Make a model for a rope or cloth, textured with `texture` (relative
+    /// to the asset root).  Keep it up to date with `update_soft_body` and
+    /// render it with an identity transform.
+    pub fn load_soft_body(
+        &mut self,
+        name: &str,
+        body: &cloth::SoftBody,
+        texture: impl AsRef<Path>,
+    ) -> assets::ModelRef {
+        let device = &self.render.device;
+        let diffuse = texture::Texture::load(
+            device,
+            &self.render.queue,
+            self.assets.asset_root().join(texture),
+        )
+        .unwrap();
+        let model = model::Model::new(
+            vec![model::Mesh::new_dynamic(
+                device,
+                name.to_string(),
+                &body.mesh_vertices(),
+                &body.mesh_indices(),
+                0,
+            )],
+            vec![model::Material::new(
+                device,
+                &self.render.queue,
+                &self.render.texture_layout,
+                name.to_string(),
+                diffuse,
+                None,
+            )],
+        );
+        self.assets.insert_model(name, model)
+    }
+    pub fn update_soft_body(&mut self, mr: assets::ModelRef, body: &cloth::SoftBody) {
+        if let Some(model) = self.assets.get_model_mut(mr) {
+            model.meshes[0].write_vertices(&self.render.queue, &body.mesh_vertices());
+            model.compute_bounds();
+        }
+    }
+    /// Save the next frame drawn (3D or 2D) as a PNG at `path`
+    pub fn capture_screenshot(&mut self, path: impl AsRef<Path>) {
+        self.capture.screenshot(path);
+    }
+    /// Save every `every`-th frame drawn as `dir/frame_000000.png`,
+    /// `dir/frame_000001.png`, and so on, until `stop_recording`
+    pub fn start_recording(&mut self, dir: impl AsRef<Path>, every: usize) {
+        self.capture.start_recording(dir, every);
+    }
+    pub fn stop_recording(&mut self) {
+        self.capture.stop_recording();
+    }
+    pub fn is_recording(&self) -> bool {
+        self.capture.is_recording()
+    }
+    /// Reseed `rng` and start recording a hash of the game state after every
+    /// update (see `Game::hash_state`).  Two runs with the same seed and the
+    /// same inputs on each frame should then produce the same hashes.
+    pub fn set_deterministic(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+        self.deterministic = true;
+        self.state_hashes.clear();
+    }
+    /// One hash per update since `set_deterministic` was called or the
+    /// hashes were last drained, oldest first.  Only the last
+    /// MAX_STATE_HASHES are kept.
+    pub fn state_hashes(&self) -> impl Iterator<Item = u64> + '_ {
+        self.state_hashes.iter().copied()
+    }
+    /// Take the hashes recorded so far, e.g. to send them off each tick
+    pub fn drain_state_hashes(&mut self) -> Vec<u64> {
+        self.state_hashes.drain(..).collect()
+    }
     pub fn camera_mut(&mut self) -> &mut camera::Camera {
         &mut self.render.camera
     }
@@ -53,12 +178,80 @@ impl Engine {
     pub fn set_lights(&mut self, lights: impl IntoIterator<Item = lights::Light>) {
         self.render.set_lights(lights.into_iter().collect());
     }
+    /// How wide an area around the camera target directed lights cast
+    /// shadows over.  Bigger covers more, but shadows get blurrier.
+    pub fn set_shadow_extent(&mut self, extent: f32) {
+        self.render.shadow_extent = extent;
+    }
+    pub fn render_settings(&self) -> RenderSettings {
+        self.render.settings()
+    }
+    /// Change quality settings on the fly; only what changed gets remade
+    pub fn set_render_settings(&mut self, settings: RenderSettings) {
+        self.render.set_settings(settings);
+    }
+    /// Surround PBR materials with six images from the asset root, in the
+    /// order +x, -x, +y, -y, +z, -z, instead of the default sky gradient.
+    /// They show up in reflections, ambient light and the skybox.  .hdr
+    /// files keep their full brightness.
+    pub fn set_environment(&mut self, faces: [impl AsRef<Path>; 6]) -> anyhow::Result<()> {
+        let root = self.assets.asset_root();
+        let faces: Vec<_> = faces.iter().map(|f| root.join(f)).collect();
+        self.render.load_environment(&faces)
+    }
+    /// Like `set_environment`, from one equirectangular panorama (twice as
+    /// wide as it is tall), usually a .hdr
+    pub fn set_environment_equirect(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = self.assets.asset_root().join(path);
+        self.render.load_environment_equirect(path)
+    }
+    /// Go back to a generated sky, shading from `ground` below to `horizon`
+    /// to `sky` above
+    pub fn set_sky_gradient(&mut self, sky: [u8; 3], horizon: [u8; 3], ground: [u8; 3]) {
+        self.render.set_sky_gradient(sky, horizon, ground);
+    }
+    pub fn background(&self) -> Background {
+        self.render.background()
+    }
+    pub fn set_background(&mut self, background: Background) {
+        self.render.set_background(background);
+    }
+    /// How many instances last frame's frustum culling kept and skipped
+    pub fn culling_stats(&self) -> render::CullingStats {
+        self.render.culling_stats()
+    }
+    pub fn post_settings(&self) -> post::PostSettings {
+        self.render.post_settings()
+    }
+    /// Exposure, tonemapping, bloom and gamma
+    pub fn set_post_settings(&mut self, settings: post::PostSettings) {
+        self.render.set_post_settings(settings);
+    }
+    /// Add a full-screen pass that runs on the HDR image before bloom and
+    /// tonemapping, after any added earlier.  `spirv` is a compiled
+    /// fragment shader that gets `layout(location=0) in vec2 v_uv` and, at
+    /// set 0, `texture2D` (binding 0) and `sampler` (binding 1) for the
+    /// image so far plus a uniform block (binding 2) of two vec4s: the
+    /// pass's params, then width, height, 1/width, 1/height.
+    pub fn add_post_pass(&mut self, label: &str, spirv: &[u8]) -> anyhow::Result<post::PostPassId> {
+        self.render.add_post_pass(label, spirv)
+    }
+    /// Whatever the pass's shader wants; all zeroes to begin with
+    pub fn set_post_pass_params(&mut self, id: post::PostPassId, params: [f32; 4]) {
+        self.render.set_post_pass_params(id, params);
+    }
+    pub fn remove_post_pass(&mut self, id: post::PostPassId) -> bool {
+        self.render.remove_post_pass(id)
+    }
 }
 
 pub trait Game: Sized {
     type StaticData;
     fn start(engine: &mut Engine) -> (Self, Self::StaticData);
     fn update(&mut self, engine: &mut Engine);
+    // Feed in everything the simulation depends on, for checking that runs
+    // match in deterministic mode
+    fn hash_state(&self, _h: &mut determinism::StateHasher) {}
     fn render(
         &mut self,
         igs: &mut InstanceGroups,
@@ -69,13 +262,22 @@ pub trait Game: Sized {
 pub fn run<R, G: Game<StaticData = R>>(
     window_builder: winit::window::WindowBuilder,
     asset_root: &Path,
+) {
+    run_with_settings::<R, G>(window_builder, asset_root, RenderSettings::default());
+}
+
+/// Like `run`, with the renderer starting out with `settings`
+pub fn run_with_settings<R, G: Game<StaticData = R>>(
+    window_builder: winit::window::WindowBuilder,
+    asset_root: &Path,
+    settings: RenderSettings,
 ) {
     use std::time::Instant;
     let mut event_loop = EventLoop::new();
     let window = window_builder.build(&event_loop).unwrap();
     let assets = Assets::new(asset_root);
     use futures::executor::block_on;
-    let render = block_on(Render::new(&window));
+    let render = block_on(Render::new(&window, settings));
     let events = Events::default();
 
     let window_size = window.inner_size();
@@ -94,11 +296,16 @@ pub fn run<R, G: Game<StaticData = R>>(
     );
 
     let mut engine = Engine {
+        rng: StdRng::from_entropy(),
+        deterministic: false,
+        state_hashes: VecDeque::new(),
+        capture: capture::Capture::default(),
         assets,
         render,
         events,
         frame: 0,
         pixels,
+        debug: debug_draw::DebugDraw::default(),
     };
     let (mut game, rules) = G::start(&mut engine);
     // How many unsimulated frames have we saved up?
@@ -137,13 +344,17 @@ pub fn run<R, G: Game<StaticData = R>>(
                 }
             }
             Event::RedrawRequested(_) => {
+                let capture = engine.capture.wants_frame();
                 match engine.render.render(
                     &mut game,
                     &rules,
                     &mut engine.assets,
                     &mut engine.pixels,
+                    &engine.debug,
+                    capture,
                 ) {
-                    Ok(_) => {}
+                    Ok(Some(img)) => engine.capture.save(&img),
+                    Ok(None) => {}
                     // Recreate the swap_chain if lost
                     Err(wgpu::SwapChainError::Lost) => engine.render.resize(engine.render.size),
                     // The system is out of memory, we should probably quit
@@ -162,7 +373,17 @@ pub fn run<R, G: Game<StaticData = R>>(
             // Eat up one frame worth of time
             available_time -= DT;
 
+            engine.debug.tick(DT);
             game.update(&mut engine);
+            if engine.deterministic {
+                let mut h = determinism::StateHasher::new();
+                h.write_usize(engine.frame);
+                game.hash_state(&mut h);
+                if engine.state_hashes.len() == MAX_STATE_HASHES {
+                    engine.state_hashes.pop_front();
+                }
+                engine.state_hashes.push_back(h.finish());
+            }
 
             engine.events.next_frame();
             engine.frame += 1;
@@ -10,12 +10,27 @@ use kira::manager::AudioManager;
 use kira::manager::error::SetupError;
 
 
+// How far point and spot lights reach unless told otherwise
+const DEFAULT_RANGE: f32 = 20.0;
+// Default spot cone half-angles, in radians
+const DEFAULT_INNER_CONE: f32 = 0.35;
+const DEFAULT_OUTER_CONE: f32 = 0.5;
+// Near plane for spot light shadow maps; the far plane is the light's range
+const SPOT_SHADOW_NEAR: f32 = 0.1;
+
+// `dir` points from what's lit back towards the light, for both directed
+// and spot lights.
 #[derive(Debug, Copy, Clone, bytemuck::Zeroable, bytemuck::Pod)]
 #[repr(C)]
 pub struct Light {
     pub pos: [f32; 4],
     pub dir:[f32;4],
     pub color: [f32; 4],
+    // casts shadows (0 or 1), depth bias, normal bias, and which layer of
+    // the shadow map it got (filled in by the renderer)
+    pub shadow: [f32; 4],
+    // range, cosines of the inner and outer cone angles, and intensity
+    pub falloff: [f32; 4],
 }
 impl Light {
     pub fn point(pos: Pos3, color: Vec3) -> Self {
@@ -23,6 +38,8 @@ impl Light {
             pos: [pos.x, pos.y, pos.z, 1.0],
             dir:[0.0,0.0,0.0,0.0],
             color: [color.x, color.y, color.z, 0.0],
+            shadow: [0.0; 4],
+            falloff: Self::default_falloff(),
         }
     }
     pub fn directed(dir:Vec3, color:Vec3) -> Self {
@@ -30,6 +47,8 @@ impl Light {
             pos:[0.0,0.0,0.0,0.0],
             dir:[dir.x,dir.y,dir.z,1.0],
             color:[color.x,color.y,color.z, 0.0],
+            shadow: [0.0; 4],
+            falloff: Self::default_falloff(),
         }
     }
     pub fn spot(pos:Pos3, dir:Vec3, color:Vec3) -> Self {
@@ -37,9 +56,84 @@ impl Light {
             pos:[pos.x,pos.y,pos.z,1.0],
             dir:[dir.x,dir.y,dir.z,1.0],
             color:[color.x,color.y,color.z, 0.0],
+            shadow: [0.0; 4],
+            falloff: Self::default_falloff(),
         }
     }
 
+    fn default_falloff() -> [f32; 4] {
+        [
+            DEFAULT_RANGE,
+            DEFAULT_INNER_CONE.cos(),
+            DEFAULT_OUTER_CONE.cos(),
+            1.0,
+        ]
+    }
+
+    /// Point and spot lights fade out with distance and are gone entirely
+    /// past `range`
+    pub fn with_range(mut self, range: f32) -> Self {
+        self.falloff[0] = range;
+        self
+    }
+    /// Spot lights are at full strength within `inner` radians of their
+    /// direction, and fade out to nothing at `outer`
+    pub fn with_cone(mut self, inner: f32, outer: f32) -> Self {
+        self.falloff[1] = inner.cos();
+        self.falloff[2] = outer.max(inner).cos();
+        self
+    }
+    /// Scales the color.  Since light falls off with the square of distance,
+    /// point and spot lights far from what they light need a lot of it.
+    pub fn with_intensity(mut self, intensity: f32) -> Self {
+        self.falloff[3] = intensity;
+        self
+    }
+    pub fn range(&self) -> f32 {
+        self.falloff[0]
+    }
+    pub fn intensity(&self) -> f32 {
+        self.falloff[3]
+    }
+
+    /// Make a directed or spot light cast shadows (point lights can't yet).
+    /// `bias` is taken off depths before they're compared; raise it if lit
+    /// surfaces get speckled with shadow.  `normal_bias` moves the lookup
+    /// out along the surface normal, which fixes the same thing on steep
+    /// surfaces without shadows coming loose from their casters.
+    pub fn with_shadows(mut self, bias: f32, normal_bias: f32) -> Self {
+        self.shadow = [1.0, bias, normal_bias, 0.0];
+        self
+    }
+    pub fn casts_shadows(&self) -> bool {
+        self.shadow[0] != 0.0 && self.dir[3] != 0.0
+    }
+
+    // What the light sees, for rendering its shadow map.  Directed lights
+    // cover a box `extent` across around `focus`.
+    pub(crate) fn shadow_view_proj(&self, focus: Pos3, extent: f32) -> Mat4 {
+        let dir = Vec3::new(self.dir[0], self.dir[1], self.dir[2]).normalize();
+        let up = if dir.y.abs() > 0.99 {
+            Vec3::unit_z()
+        } else {
+            Vec3::unit_y()
+        };
+        let proj = if self.pos[3] == 0.0 {
+            let eye = focus + dir * extent;
+            let view = Mat4::look_at_rh(eye, focus, up);
+            let half = extent / 2.0;
+            cgmath::ortho(-half, half, -half, half, 0.0, extent * 2.0) * view
+        } else {
+            let eye = self.position();
+            let view = Mat4::look_at_rh(eye, eye - dir, up);
+            // Just wide enough for the outer cone
+            let fovy = (2.0 * self.falloff[2].clamp(-1.0, 1.0).acos()).min(PI * 0.95);
+            let far = self.range().max(SPOT_SHADOW_NEAR * 2.0);
+            cgmath::perspective(cgmath::Rad(fovy), 1.0, SPOT_SHADOW_NEAR, far) * view
+        };
+        crate::render::OPENGL_TO_WGPU_MATRIX * proj
+    }
+
     pub fn position(&self) -> Pos3 {
         Pos3::new(self.pos[0], self.pos[1], self.pos[2])
     }
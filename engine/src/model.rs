@@ -1,8 +1,12 @@
 use anyhow::*;
+use std::collections::HashMap;
 use std::ops::Range;
 use std::path::Path;
+use std::rc::Rc;
 use wgpu::util::DeviceExt;
 
+use crate::animation::{Clip, Skeleton};
+use crate::geom::*;
 use crate::texture;
 
 pub trait Vertex {
@@ -15,6 +19,80 @@ pub struct ModelVertex {
     position: [f32; 3],
     tex_coords: [f32; 2],
     normal: [f32; 3],
+    // Which way u and v go on the surface, for normal mapping.  Left at
+    // zero the shader just uses `normal`.
+    tangent: [f32; 3],
+    bitangent: [f32; 3],
+    // Up to four joints moving this vertex, and how much each counts.  All
+    // zero weights means the vertex isn't skinned.
+    joints: [u32; 4],
+    weights: [f32; 4],
+}
+
+impl ModelVertex {
+    pub fn new(position: [f32; 3], tex_coords: [f32; 2], normal: [f32; 3]) -> Self {
+        Self {
+            position,
+            tex_coords,
+            normal,
+            tangent: [0.0; 3],
+            bitangent: [0.0; 3],
+            joints: [0; 4],
+            weights: [0.0; 4],
+        }
+    }
+    pub fn with_skin(self, joints: [u32; 4], weights: [f32; 4]) -> Self {
+        Self {
+            joints,
+            weights,
+            ..self
+        }
+    }
+}
+
+/// Fill in tangents and bitangents from the texture coordinates, averaged
+/// over the triangles sharing each vertex
+pub fn compute_tangents(vertices: &mut [ModelVertex], indices: &[u32]) {
+    let mut tangents = vec![Vec3::zero(); vertices.len()];
+    let mut bitangents = vec![Vec3::zero(); vertices.len()];
+    for tri in indices.chunks_exact(3) {
+        let [a, b, c] = [tri[0] as usize, tri[1] as usize, tri[2] as usize];
+        let p0: Vec3 = vertices[a].position.into();
+        let p1: Vec3 = vertices[b].position.into();
+        let p2: Vec3 = vertices[c].position.into();
+        let uv0: cgmath::Vector2<f32> = vertices[a].tex_coords.into();
+        let uv1: cgmath::Vector2<f32> = vertices[b].tex_coords.into();
+        let uv2: cgmath::Vector2<f32> = vertices[c].tex_coords.into();
+        let (e1, e2) = (p1 - p0, p2 - p0);
+        let (d1, d2) = (uv1 - uv0, uv2 - uv0);
+        let det = d1.x * d2.y - d1.y * d2.x;
+        // Degenerate UVs don't say anything about direction
+        if det.abs() < f32::EPSILON {
+            continue;
+        }
+        let r = 1.0 / det;
+        let tangent = (e1 * d2.y - e2 * d1.y) * r;
+        let bitangent = (e2 * d1.x - e1 * d2.x) * r;
+        for &i in &[a, b, c] {
+            tangents[i] += tangent;
+            bitangents[i] += bitangent;
+        }
+    }
+    for (v, (t, b)) in vertices
+        .iter_mut()
+        .zip(tangents.into_iter().zip(bitangents))
+    {
+        let n: Vec3 = v.normal.into();
+        // Make the tangent perpendicular to the normal; keep the bitangent
+        // as is, since only its side matters to the shader
+        let t = t - n * n.dot(t);
+        if t.magnitude2() > 0.0 {
+            v.tangent = t.normalize().into();
+        }
+        if b.magnitude2() > 0.0 {
+            v.bitangent = b.normalize().into();
+        }
+    }
 }
 
 impl Vertex for ModelVertex {
@@ -39,6 +117,122 @@ impl Vertex for ModelVertex {
                     shader_location: 2,
                     format: wgpu::VertexFormat::Float3,
                 },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 11]>() as wgpu::BufferAddress,
+                    shader_location: 4,
+                    format: wgpu::VertexFormat::Float3,
+                },
+                // 5 through 8 are taken by InstanceRaw, and 11 on
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 14]>() as wgpu::BufferAddress,
+                    shader_location: 9,
+                    format: wgpu::VertexFormat::Uint4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 18]>() as wgpu::BufferAddress,
+                    shader_location: 10,
+                    format: wgpu::VertexFormat::Float4,
+                },
+            ],
+        }
+    }
+}
+
+/// How a material reacts to light
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shading {
+    /// Blinn-Phong, from the ambient, diffuse, specular and shininess params
+    Phong,
+    /// Metallic-roughness, like glTF: `diffuse` is the base color, and the
+    /// environment map stands in for ambient light
+    Pbr,
+}
+
+/// The colors and numbers from an MTL material.  Each one is multiplied
+/// with the matching map, if there is one.
+#[derive(Clone, Copy, Debug)]
+pub struct MaterialParams {
+    pub shading: Shading,
+    pub ambient: Vec3,
+    pub diffuse: Vec3,
+    pub specular: Vec3,
+    // Specular exponent, Ns
+    pub shininess: f32,
+    // Opacity, d; 1 is opaque
+    pub dissolve: f32,
+    // Blended over what's behind and drawn after everything opaque, back to
+    // front.  Otherwise low dissolve just cuts holes.
+    pub transparent: bool,
+    pub emissive: Vec3,
+    // Only used for Pbr shading; Pm and Pr in MTL files
+    pub metallic: f32,
+    pub roughness: f32,
+}
+
+impl Default for MaterialParams {
+    // Plain lit texture, the way things looked before materials had params
+    fn default() -> Self {
+        Self {
+            shading: Shading::Phong,
+            ambient: Vec3::new(1.0, 1.0, 1.0),
+            diffuse: Vec3::new(1.0, 1.0, 1.0),
+            specular: Vec3::new(1.0, 1.0, 1.0),
+            shininess: 32.0,
+            dissolve: 1.0,
+            transparent: false,
+            emissive: Vec3::zero(),
+            metallic: 0.0,
+            roughness: 0.5,
+        }
+    }
+}
+
+/// Textures for a material; missing ones fall back to plain white (or a
+/// flat normal map), leaving just the colors in `MaterialParams`.  For Pbr
+/// shading, `specular` has roughness in green and metallic in blue, the
+/// same as glTF's metallic-roughness texture.  Textures can be shared
+/// between materials, e.g. across terrain chunks.
+#[derive(Default)]
+pub struct MaterialMaps {
+    pub diffuse: Option<Rc<texture::Texture>>,
+    pub normal: Option<Rc<texture::Texture>>,
+    pub specular: Option<Rc<texture::Texture>>,
+    pub emissive: Option<Rc<texture::Texture>>,
+}
+
+// Matches the Material block in shader.frag
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct MaterialUniforms {
+    // rgb, unused
+    ambient: [f32; 4],
+    // rgb, dissolve
+    diffuse: [f32; 4],
+    // rgb, shininess
+    specular: [f32; 4],
+    // rgb, unused
+    emissive: [f32; 4],
+    // metallic, roughness, 1 for Pbr shading, 1 if transparent
+    pbr: [f32; 4],
+}
+
+impl From<&MaterialParams> for MaterialUniforms {
+    fn from(p: &MaterialParams) -> Self {
+        Self {
+            ambient: p.ambient.extend(0.0).into(),
+            diffuse: p.diffuse.extend(p.dissolve).into(),
+            specular: p.specular.extend(p.shininess).into(),
+            emissive: p.emissive.extend(0.0).into(),
+            pbr: [
+                p.metallic,
+                p.roughness,
+                (p.shading == Shading::Pbr) as u32 as f32,
+                p.transparent as u32 as f32,
             ],
         }
     }
@@ -46,24 +240,272 @@ impl Vertex for ModelVertex {
 
 pub struct Material {
     pub name: String,
-    pub diffuse_texture: texture::Texture,
+    pub params: MaterialParams,
+    pub diffuse_texture: Rc<texture::Texture>,
+    pub normal_texture: Rc<texture::Texture>,
+    pub specular_texture: Rc<texture::Texture>,
+    pub emissive_texture: Rc<texture::Texture>,
+    uniform_buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
 }
 
+impl Material {
+    /// Just a texture and maybe a normal map, with default params.  Without
+    /// a normal map, surfaces are lit as if flat.
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        name: String,
+        diffuse_texture: impl Into<Rc<texture::Texture>>,
+        normal_texture: Option<texture::Texture>,
+    ) -> Self {
+        Self::with_params(
+            device,
+            queue,
+            layout,
+            name,
+            MaterialParams::default(),
+            MaterialMaps {
+                diffuse: Some(diffuse_texture.into()),
+                normal: normal_texture.map(Rc::new),
+                ..MaterialMaps::default()
+            },
+        )
+    }
+
+    pub fn with_params(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        name: String,
+        params: MaterialParams,
+        maps: MaterialMaps,
+    ) -> Self {
+        let white = |label| Rc::new(texture::Texture::solid(device, queue, [255; 4], label));
+        let diffuse_texture = maps.diffuse.unwrap_or_else(|| white("default_diffuse"));
+        let normal_texture = maps
+            .normal
+            .unwrap_or_else(|| Rc::new(texture::Texture::flat_normal_map(device, queue)));
+        let specular_texture = maps.specular.unwrap_or_else(|| white("default_specular"));
+        let emissive_texture = maps.emissive.unwrap_or_else(|| white("default_emissive"));
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Material Buffer", name)),
+            contents: bytemuck::cast_slice(&[MaterialUniforms::from(&params)]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&normal_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&specular_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Sampler(&specular_texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::TextureView(&emissive_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: wgpu::BindingResource::Sampler(&emissive_texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+            ],
+            label: None,
+        });
+        Self {
+            name,
+            params,
+            diffuse_texture,
+            normal_texture,
+            specular_texture,
+            emissive_texture,
+            uniform_buffer,
+            bind_group,
+        }
+    }
+
+    /// Change the colors and numbers, e.g. to fade something out
+    pub fn set_params(&mut self, queue: &wgpu::Queue, params: MaterialParams) {
+        self.params = params;
+        queue.write_buffer(
+            &self.uniform_buffer,
+            0,
+            bytemuck::cast_slice(&[MaterialUniforms::from(&params)]),
+        );
+    }
+}
+
 pub struct Mesh {
     pub name: String,
+    // Kept around on the CPU for building colliders
+    pub positions: Vec<Vec3>,
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
     pub num_elements: u32,
     pub material: usize,
 }
 
+impl Mesh {
+    /// Upload a mesh built on the CPU, e.g. by `terrain`
+    pub fn new(
+        device: &wgpu::Device,
+        name: String,
+        vertices: &[ModelVertex],
+        indices: &[u32],
+        material: usize,
+    ) -> Self {
+        Self::with_usage(
+            device,
+            name,
+            vertices,
+            indices,
+            material,
+            wgpu::BufferUsage::VERTEX,
+        )
+    }
+
+    /// Like `new`, but the vertices can be changed later with
+    /// `write_vertices`, e.g. for cloth
+    pub fn new_dynamic(
+        device: &wgpu::Device,
+        name: String,
+        vertices: &[ModelVertex],
+        indices: &[u32],
+        material: usize,
+    ) -> Self {
+        Self::with_usage(
+            device,
+            name,
+            vertices,
+            indices,
+            material,
+            wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+        )
+    }
+
+    fn with_usage(
+        device: &wgpu::Device,
+        name: String,
+        vertices: &[ModelVertex],
+        indices: &[u32],
+        material: usize,
+        usage: wgpu::BufferUsage,
+    ) -> Self {
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Vertex Buffer", name)),
+            contents: bytemuck::cast_slice(vertices),
+            usage,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Index Buffer", name)),
+            contents: bytemuck::cast_slice(indices),
+            usage: wgpu::BufferUsage::INDEX,
+        });
+        Self {
+            name,
+            positions: vertices.iter().map(|v| v.position.into()).collect(),
+            vertex_buffer,
+            index_buffer,
+            num_elements: indices.len() as u32,
+            material,
+        }
+    }
+
+    /// Replace the vertices of a mesh made with `new_dynamic`.  There have
+    /// to be as many as before, since the indices stay the same.
+    pub fn write_vertices(&mut self, queue: &wgpu::Queue, vertices: &[ModelVertex]) {
+        assert_eq!(vertices.len(), self.positions.len());
+        queue.write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(vertices));
+        for (p, v) in self.positions.iter_mut().zip(vertices) {
+            *p = v.position.into();
+        }
+    }
+}
+
 pub struct Model {
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
+    // Only glTF models with skins have these
+    pub skeleton: Option<Skeleton>,
+    pub clips: Vec<Clip>,
+    // Around every mesh's vertices in model space, for culling
+    pub aabb: AABB,
+    pub bounding_sphere: Sphere,
 }
 
 impl Model {
+    /// A model with no skeleton, with its bounds computed from `meshes`
+    pub fn new(meshes: Vec<Mesh>, materials: Vec<Material>) -> Self {
+        let mut model = Self {
+            meshes,
+            materials,
+            skeleton: None,
+            clips: vec![],
+            aabb: AABB {
+                c: Pos3::origin(),
+                half_sizes: Vec3::zero(),
+            },
+            bounding_sphere: Sphere {
+                c: Pos3::origin(),
+                r: 0.0,
+            },
+        };
+        model.compute_bounds();
+        model
+    }
+    /// Refit `aabb` and `bounding_sphere` to the meshes' current positions,
+    /// e.g. after `Mesh::write_vertices`
+    pub fn compute_bounds(&mut self) {
+        let mut points = self.meshes.iter().flat_map(|m| m.positions.iter());
+        let first = match points.next() {
+            Some(p) => *p,
+            None => return,
+        };
+        let (min, max) = points.fold((first, first), |(min, max), p| {
+            (
+                Vec3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z)),
+                Vec3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z)),
+            )
+        });
+        let c = Pos3::from_vec((min + max) / 2.0);
+        // Centering the sphere on the box and reaching for the farthest
+        // vertex is tighter than using the box's half diagonal
+        let r = self
+            .meshes
+            .iter()
+            .flat_map(|m| m.positions.iter())
+            .map(|p| p.distance(c.to_vec()))
+            .fold(0.0, f32::max);
+        self.aabb = AABB {
+            c,
+            half_sizes: (max - min) / 2.0,
+        };
+        self.bounding_sphere = Sphere { c, r };
+    }
     pub fn load<P: AsRef<Path>>(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
@@ -75,77 +517,317 @@ impl Model {
         // We're assuming that the texture files are stored with the obj file
         let containing_folder = path.as_ref().parent().context("Directory has no parent")?;
 
+        // Map statements can start with options, e.g. `map_Kd -clamp on a.png`
+        let load = |statement: &str, settings| -> Result<Option<Rc<texture::Texture>>> {
+            let (file, settings) = parse_map(statement, settings);
+            if file.is_empty() {
+                Ok(None)
+            } else {
+                Ok(Some(Rc::new(
+                    texture::Texture::load(device, queue, containing_folder.join(file))?
+                        .with_sampler(device, &settings),
+                )))
+            }
+        };
         let mut materials = Vec::new();
         for mat in obj_materials {
-            let diffuse_path = mat.diffuse_texture;
-            let diffuse_texture =
-                texture::Texture::load(device, queue, containing_folder.join(diffuse_path))?;
-
-            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            let sampler = material_sampler(&mat.unknown_param);
+            // tobj doesn't know about emission, so it ends up in unknown_param
+            let emissive = mat
+                .unknown_param
+                .get("Ke")
+                .and_then(|ke| parse_color(ke))
+                .unwrap_or_else(Vec3::zero);
+            // The PBR extension to MTL: Pr and Pm, with maps for each.  Having
+            // any of them switches the material over to PBR shading.
+            let pbr_param = |key: &str| mat.unknown_param.get(key).map(|v| v.trim());
+            let pbr = ["Pr", "Pm", "map_Pr", "map_Pm"]
+                .iter()
+                .any(|k| pbr_param(k).is_some());
+            let params = MaterialParams {
+                shading: if pbr { Shading::Pbr } else { Shading::Phong },
+                ambient: mat.ambient.into(),
+                diffuse: mat.diffuse.into(),
+                specular: mat.specular.into(),
+                shininess: mat.shininess,
+                dissolve: mat.dissolve,
+                transparent: mat.dissolve < 1.0,
+                emissive,
+                metallic: pbr_param("Pm").and_then(|v| v.parse().ok()).unwrap_or(0.0),
+                roughness: pbr_param("Pr")
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or_else(|| shininess_to_roughness(mat.shininess)),
+            };
+            let maps = MaterialMaps {
+                diffuse: load(&mat.diffuse_texture, sampler)?,
+                // map_Bump in the MTL file
+                normal: match parse_map(&mat.normal_texture, sampler) {
+                    (file, _) if file.is_empty() => None,
+                    (file, settings) => Some(Rc::new(
+                        texture::Texture::load_normal_map(
+                            device,
+                            queue,
+                            containing_folder.join(file),
+                        )?
+                        .with_sampler(device, &settings),
+                    )),
+                },
+                specular: if pbr {
+                    metallic_roughness_map(
+                        device,
+                        queue,
+                        containing_folder,
+                        pbr_param("map_Pr").map(|m| parse_map(m, sampler)),
+                        pbr_param("map_Pm").map(|m| parse_map(m, sampler)),
+                    )?
+                    .map(Rc::new)
+                } else {
+                    load(&mat.specular_texture, sampler)?
+                },
+                emissive: match mat.unknown_param.get("map_Ke") {
+                    Some(statement) => load(statement, sampler)?,
+                    None => None,
+                },
+            };
+            materials.push(Material::with_params(
+                device, queue, layout, mat.name, params, maps,
+            ));
+        }
+        // Meshes with no material still need something to draw with
+        if materials.is_empty() {
+            materials.push(Material::with_params(
+                device,
+                queue,
                 layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                    },
-                ],
-                label: None,
-            });
-
-            materials.push(Material {
-                name: mat.name,
-                diffuse_texture,
-                bind_group,
-            });
+                "default".to_string(),
+                MaterialParams::default(),
+                MaterialMaps::default(),
+            ));
         }
 
         let mut meshes = Vec::new();
         for m in obj_models {
             let mut vertices = Vec::new();
             for i in 0..m.mesh.positions.len() / 3 {
-                vertices.push(ModelVertex {
-                    position: [
+                vertices.push(ModelVertex::new(
+                    [
                         m.mesh.positions[i * 3],
                         m.mesh.positions[i * 3 + 1],
                         m.mesh.positions[i * 3 + 2],
                     ],
-                    tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]],
-                    normal: [
+                    [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]],
+                    [
                         m.mesh.normals[i * 3],
                         m.mesh.normals[i * 3 + 1],
                         m.mesh.normals[i * 3 + 2],
                     ],
-                });
+                ));
             }
+            compute_tangents(&mut vertices, &m.mesh.indices);
 
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Vertex Buffer", path.as_ref())),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsage::VERTEX,
-            });
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", path.as_ref())),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
-                usage: wgpu::BufferUsage::INDEX,
-            });
+            meshes.push(Mesh::new(
+                device,
+                m.name,
+                &vertices,
+                &m.mesh.indices,
+                m.mesh.material_id.unwrap_or(0),
+            ));
+        }
 
-            meshes.push(Mesh {
-                name: m.name,
-                vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
-                material: m.mesh.material_id.unwrap_or(0),
-            });
+        Ok(Self::new(meshes, materials))
+    }
+
+    // The MTL files an OBJ file names and the textures those name, for hot
+    // reloading.  Missing or unreadable files are just skipped.
+    pub(crate) fn obj_dependencies(path: &Path) -> Vec<std::path::PathBuf> {
+        let folder = path.parent().unwrap_or_else(|| Path::new(""));
+        let statements = |file: &Path| -> Vec<(String, String)> {
+            std::fs::read_to_string(file)
+                .unwrap_or_default()
+                .lines()
+                .filter_map(|l| {
+                    let mut tokens = l.split_whitespace();
+                    let key = tokens.next()?.to_string();
+                    Some((key, tokens.collect::<Vec<_>>().join(" ")))
+                })
+                .collect()
+        };
+        let mut deps = vec![];
+        for (key, mtls) in statements(path) {
+            if key != "mtllib" {
+                continue;
+            }
+            for mtl in mtls.split_whitespace() {
+                let mtl = folder.join(mtl);
+                for (key, statement) in statements(&mtl) {
+                    if key.starts_with("map_") || key == "bump" || key == "norm" {
+                        let (file, _) = parse_map(&statement, Default::default());
+                        deps.push(folder.join(file));
+                    }
+                }
+                deps.push(mtl);
+            }
         }
+        deps
+    }
+
+    /// One convex hull around the whole model, in model space.  Place it
+    /// by setting `c` and `axes` (which may include a scale) to match the
+    /// instance's transform.
+    pub fn convex_hull(&self, max_verts: usize) -> ConvexHull {
+        let points: Vec<Vec3> = self
+            .meshes
+            .iter()
+            .flat_map(|m| m.positions.iter().copied())
+            .collect();
+        ConvexHull::new(Pos3::origin(), Mat3::identity(), &points, max_verts)
+    }
 
-        Ok(Self { meshes, materials })
+    /// A rough convex decomposition: one hull per mesh (i.e. per `o`/`g`
+    /// group in the OBJ file), for props that aren't convex as a whole.
+    pub fn convex_pieces(&self, max_verts: usize) -> Vec<ConvexHull> {
+        self.meshes
+            .iter()
+            .filter(|m| !m.positions.is_empty())
+            .map(|m| ConvexHull::new(Pos3::origin(), Mat3::identity(), &m.positions, max_verts))
+            .collect()
     }
 }
 
+// Settings for all of a material's maps.  `sampler_wrap` (repeat, mirror or
+// clamp), `sampler_filter` (linear or nearest) and `sampler_anisotropy`
+// aren't standard MTL, but other programs skip lines they don't know.
+fn material_sampler(params: &HashMap<String, String>) -> texture::SamplerSettings {
+    let mut settings = texture::SamplerSettings::default();
+    match params.get("sampler_wrap").map(|v| v.trim()) {
+        Some("repeat") => settings = settings.with_address_mode(wgpu::AddressMode::Repeat),
+        Some("mirror") => settings = settings.with_address_mode(wgpu::AddressMode::MirrorRepeat),
+        Some("clamp") => settings = settings.with_address_mode(wgpu::AddressMode::ClampToEdge),
+        _ => {}
+    }
+    match params.get("sampler_filter").map(|v| v.trim()) {
+        Some("linear") => settings = settings.with_filter(wgpu::FilterMode::Linear),
+        Some("nearest") => settings = settings.with_filter(wgpu::FilterMode::Nearest),
+        _ => {}
+    }
+    if let Some(n) = params
+        .get("sampler_anisotropy")
+        .and_then(|v| v.trim().parse().ok())
+    {
+        settings.anisotropy = n;
+    }
+    settings
+}
+
+// Split the options off the front of a map statement, leaving the file
+// name.  Of the options, only `-clamp` matters here; the rest are skipped
+// along with their arguments.
+fn parse_map(
+    statement: &str,
+    mut settings: texture::SamplerSettings,
+) -> (String, texture::SamplerSettings) {
+    let mut tokens = statement.split_whitespace().peekable();
+    while let Some(option) = tokens.peek().filter(|t| t.starts_with('-')).copied() {
+        tokens.next();
+        match option {
+            "-clamp" => match tokens.next() {
+                Some("on") => settings = settings.with_address_mode(wgpu::AddressMode::ClampToEdge),
+                Some("off") => settings = settings.with_address_mode(wgpu::AddressMode::Repeat),
+                _ => {}
+            },
+            // Up to three numbers each
+            "-o" | "-s" | "-t" => {
+                for _ in 0..3 {
+                    if matches!(tokens.peek(), Some(t) if t.parse::<f32>().is_ok()) {
+                        tokens.next();
+                    }
+                }
+            }
+            "-mm" => {
+                tokens.next();
+                tokens.next();
+            }
+            _ => {
+                tokens.next();
+            }
+        }
+    }
+    (tokens.collect::<Vec<_>>().join(" "), settings)
+}
+
+// "r g b" from an MTL line
+fn parse_color(s: &str) -> Option<Vec3> {
+    let rgb: Vec<f32> = s
+        .split_whitespace()
+        .map(|p| p.parse().ok())
+        .collect::<Option<_>>()?;
+    match rgb[..] {
+        [r, g, b] => Some(Vec3::new(r, g, b)),
+        _ => None,
+    }
+}
+
+// The inverse of how the glTF loader picks a shininess, so MTL files
+// without Pr still get about the same highlights under PBR shading
+fn shininess_to_roughness(shininess: f32) -> f32 {
+    let alpha = (2.0 / (shininess.max(0.0) + 2.0)).sqrt();
+    alpha.sqrt().clamp(0.0, 1.0)
+}
+
+// MTL files keep roughness and metallic in separate greyscale maps, but
+// the shader wants them packed together like glTF does
+fn metallic_roughness_map(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    folder: &Path,
+    roughness: Option<(String, texture::SamplerSettings)>,
+    metallic: Option<(String, texture::SamplerSettings)>,
+) -> Result<Option<texture::Texture>> {
+    // The two maps can only have one sampler between them
+    let settings = match (&roughness, &metallic) {
+        (Some((_, s)), _) | (None, Some((_, s))) => *s,
+        (None, None) => return Ok(None),
+    };
+    let open =
+        |map: Option<(String, texture::SamplerSettings)>| -> Result<Option<image::GrayImage>> {
+            match map {
+                Some((file, _)) => Ok(Some(image::open(folder.join(file))?.to_luma8())),
+                None => Ok(None),
+            }
+        };
+    let (roughness, metallic) = (open(roughness)?, open(metallic)?);
+    let (w, h) = match (&roughness, &metallic) {
+        (Some(img), _) | (None, Some(img)) => img.dimensions(),
+        (None, None) => return Ok(None),
+    };
+    // Missing maps are white, so the Pr and Pm values come through as is
+    let fit = |img: Option<image::GrayImage>| {
+        img.map(|img| {
+            if img.dimensions() == (w, h) {
+                img
+            } else {
+                image::imageops::resize(&img, w, h, image::imageops::FilterType::Triangle)
+            }
+        })
+    };
+    let (roughness, metallic) = (fit(roughness), fit(metallic));
+    let packed = image::RgbaImage::from_fn(w, h, |x, y| {
+        let r = roughness.as_ref().map_or(255, |img| img.get_pixel(x, y)[0]);
+        let m = metallic.as_ref().map_or(255, |img| img.get_pixel(x, y)[0]);
+        image::Rgba([0, r, m, 255])
+    });
+    Ok(Some(
+        texture::Texture::from_image_with_format(
+            device,
+            queue,
+            &image::DynamicImage::ImageRgba8(packed),
+            Some("metallic_roughness"),
+            wgpu::TextureFormat::Rgba8Unorm,
+        )?
+        .with_sampler(device, &settings),
+    ))
+}
+
 pub trait DrawModel<'a, 'b>
 where
     'b: 'a,
@@ -227,8 +909,12 @@ where
         uniforms: &'b wgpu::BindGroup,
         light: &'b wgpu::BindGroup,
     ) {
+        // Transparent meshes are left for the sorted pass
         for mesh in &model.meshes {
             let material = &model.materials[mesh.material];
+            if material.params.transparent {
+                continue;
+            }
             self.draw_mesh_instanced(mesh, material, instances.clone(), uniforms, light);
         }
     }
@@ -0,0 +1,265 @@
+use rand::Rng;
+
+use crate::assets::ModelRef;
+use crate::geom::*;
+use crate::render::{InstanceGroups, InstanceRaw};
+use crate::DT;
+
+/// Things a `Curve` can blend between
+pub trait Lerp: Copy {
+    fn lerp(self, other: Self, t: f32) -> Self;
+}
+
+impl Lerp for f32 {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+}
+
+impl Lerp for Vec3 {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+}
+
+impl Lerp for [f32; 4] {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        let mut out = self;
+        for (o, b) in out.iter_mut().zip(other.iter()) {
+            *o += (b - *o) * t;
+        }
+        out
+    }
+}
+
+/// A value that changes over a particle's life.  Keys are (t, value) with t
+/// going from 0 at birth to 1 at death; in between we blend linearly.
+#[derive(Clone, Debug)]
+pub struct Curve<T: Lerp> {
+    keys: Vec<(f32, T)>,
+}
+
+impl<T: Lerp> Curve<T> {
+    pub fn constant(v: T) -> Self {
+        Self {
+            keys: vec![(0.0, v)],
+        }
+    }
+    pub fn linear(from: T, to: T) -> Self {
+        Self {
+            keys: vec![(0.0, from), (1.0, to)],
+        }
+    }
+    /// Keys don't need to be sorted.  Panics if there aren't any.
+    pub fn new(mut keys: Vec<(f32, T)>) -> Self {
+        assert!(!keys.is_empty(), "A curve needs at least one key");
+        keys.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        Self { keys }
+    }
+    pub fn sample(&self, t: f32) -> T {
+        let after = self.keys.iter().position(|(kt, _)| *kt > t);
+        match after {
+            Some(0) => self.keys[0].1,
+            None => self.keys[self.keys.len() - 1].1,
+            Some(i) => {
+                let (t0, v0) = self.keys[i - 1];
+                let (t1, v1) = self.keys[i];
+                v0.lerp(v1, (t - t0) / (t1 - t0))
+            }
+        }
+    }
+}
+
+/// Where new particles start and which way they head off
+#[derive(Clone, Copy, Debug)]
+pub enum EmitterShape {
+    /// From the emitter's position, in any direction
+    Point,
+    /// From anywhere inside the sphere, heading away from its center
+    Sphere { r: f32 },
+    /// From the emitter's position, within `angle` radians of `dir`
+    Cone { dir: Vec3, angle: f32 },
+}
+
+// Between the two ends of `range`, whichever order they're in
+fn sample_range(rng: &mut impl Rng, (a, b): (f32, f32)) -> f32 {
+    rng.gen_range(a.min(b)..=a.max(b))
+}
+
+// Uniformly random unit vector
+fn random_dir(rng: &mut impl Rng) -> Vec3 {
+    let z: f32 = rng.gen_range(-1.0..=1.0);
+    let theta: f32 = rng.gen_range(0.0..(2.0 * PI));
+    let s = (1.0 - z * z).sqrt();
+    Vec3::new(s * theta.cos(), s * theta.sin(), z)
+}
+
+impl EmitterShape {
+    // Spawn offset from the emitter and direction of travel
+    fn sample(&self, rng: &mut impl Rng) -> (Vec3, Vec3) {
+        match *self {
+            EmitterShape::Point => (Vec3::zero(), random_dir(rng)),
+            EmitterShape::Sphere { r } => {
+                let dir = random_dir(rng);
+                // cbrt so they aren't bunched up in the middle
+                let dist = r * rng.gen_range(0.0_f32..=1.0).cbrt();
+                (dir * dist, dir)
+            }
+            EmitterShape::Cone { dir, angle } => {
+                let dir = dir.normalize();
+                // Uniform over the spherical cap around dir
+                let cos_max = angle.cos();
+                let z = rng.gen_range(cos_max..=1.0);
+                let theta: f32 = rng.gen_range(0.0..(2.0 * PI));
+                let s = (1.0 - z * z).sqrt();
+                let local = Vec3::new(s * theta.cos(), s * theta.sin(), z);
+                (
+                    Vec3::zero(),
+                    Quat::between_vectors(Vec3::unit_z(), dir) * local,
+                )
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Particle {
+    pub pos: Pos3,
+    pub vel: Vec3,
+    pub age: f32,
+    pub lifetime: f32,
+    // Filled in from the emitter's curves every update
+    pub size: f32,
+    pub color: [f32; 4],
+}
+
+/// Spawns, moves, and retires a bunch of particles.  Set the public fields
+/// however you like, call `update` once per `Game::update` and `render`
+/// once per `Game::render`.
+pub struct Emitter {
+    pub pos: Pos3,
+    pub shape: EmitterShape,
+    // Particles per second; 0 for bursts only
+    pub rate: f32,
+    // Ranges new particles pick from, (min, max) or (max, min)
+    pub speed: (f32, f32),
+    pub lifetime: (f32, f32),
+    // Added to every particle's starting velocity, e.g. to leave a trail
+    // behind a moving emitter
+    pub inherit_velocity: Vec3,
+    pub gravity: Vec3,
+    // Fraction of velocity lost per second
+    pub drag: f32,
+    pub size: Curve<f32>,
+    pub color: Curve<[f32; 4]>,
+    // Particles bounce off these if there are any
+    pub planes: Vec<Plane>,
+    // How much speed is kept along the normal after a bounce
+    pub bounce: f32,
+    pub max_particles: usize,
+    particles: Vec<Particle>,
+    // Fractional particles left over from the last update
+    owed: f32,
+}
+
+impl Emitter {
+    pub fn new(pos: Pos3, shape: EmitterShape) -> Self {
+        Self {
+            pos,
+            shape,
+            rate: 10.0,
+            speed: (1.0, 2.0),
+            lifetime: (1.0, 2.0),
+            inherit_velocity: Vec3::zero(),
+            gravity: Vec3::new(0.0, -9.8, 0.0),
+            drag: 0.0,
+            size: Curve::constant(0.1),
+            color: Curve::constant([1.0, 1.0, 1.0, 1.0]),
+            planes: vec![],
+            bounce: 0.3,
+            max_particles: 1000,
+            particles: vec![],
+            owed: 0.0,
+        }
+    }
+
+    pub fn particles(&self) -> &[Particle] {
+        &self.particles
+    }
+
+    pub fn clear(&mut self) {
+        self.particles.clear();
+        self.owed = 0.0;
+    }
+
+    fn spawn(&mut self, rng: &mut impl Rng) {
+        if self.particles.len() >= self.max_particles {
+            return;
+        }
+        let (offset, dir) = self.shape.sample(rng);
+        let speed = sample_range(rng, self.speed);
+        let lifetime = sample_range(rng, self.lifetime);
+        self.particles.push(Particle {
+            pos: self.pos + offset,
+            vel: dir * speed + self.inherit_velocity,
+            age: 0.0,
+            lifetime,
+            size: self.size.sample(0.0),
+            color: self.color.sample(0.0),
+        });
+    }
+
+    /// Spawn `n` particles right now, e.g. for sparkles on a pickup
+    pub fn burst(&mut self, n: usize, rng: &mut impl Rng) {
+        for _ in 0..n {
+            self.spawn(rng);
+        }
+    }
+
+    /// Pass `engine.rng` to keep deterministic mode deterministic
+    pub fn update(&mut self, rng: &mut impl Rng) {
+        self.owed += self.rate * DT;
+        while self.owed >= 1.0 {
+            self.owed -= 1.0;
+            self.spawn(rng);
+        }
+        let drag = (1.0 - self.drag * DT).max(0.0);
+        for p in self.particles.iter_mut() {
+            p.age += DT;
+            p.vel = (p.vel + self.gravity * DT) * drag;
+            p.pos += p.vel * DT;
+            for plane in self.planes.iter() {
+                let dist = p.pos.dot(plane.n) - plane.d;
+                let into = p.vel.dot(plane.n);
+                if dist < 0.0 && into < 0.0 {
+                    p.pos += plane.n * -dist;
+                    p.vel -= plane.n * (into * (1.0 + self.bounce));
+                }
+            }
+            let t = p.age / p.lifetime;
+            p.size = self.size.sample(t);
+            p.color = self.color.sample(t);
+        }
+        // Order doesn't matter for drawing, so swap_remove is fine
+        let mut i = 0;
+        while i < self.particles.len() {
+            if self.particles[i].age >= self.particles[i].lifetime {
+                self.particles.swap_remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// One instance of `model` per particle, scaled by its size and tinted
+    /// its color
+    pub fn render(&self, igs: &mut InstanceGroups, model: ModelRef) {
+        igs.render_batch(
+            model,
+            self.particles.iter().map(|p| {
+                InstanceRaw::new(Mat4::from_translation(p.pos.to_vec()) * Mat4::from_scale(p.size))
+                    .with_tint(p.color)
+            }),
+        );
+    }
+}
@@ -0,0 +1,663 @@
+use anyhow::{bail, Result};
+use wgpu::util::DeviceExt;
+
+// Post-processing.  The main pass draws into a floating point HDR target,
+// then any passes the game added run on it (ping-ponging between two HDR
+// textures), bright parts get blurred into a chain of ever smaller bloom
+// textures, and finally it's all tonemapped into the frame.
+
+/// What the main pass and custom post passes draw into
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+// Each level is half the size of the one before, starting at half the frame
+const BLOOM_LEVELS: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tonemap {
+    /// Just clamp to 0..1
+    None,
+    Reinhard,
+    /// A fit of the ACES filmic curve; punchier than Reinhard
+    Aces,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PostSettings {
+    /// Scene brightness is multiplied by this before tonemapping
+    pub exposure: f32,
+    pub tonemap: Tonemap,
+    /// Only light brighter than this blooms; 0 blooms everything
+    pub bloom_threshold: f32,
+    /// How gradually bloom fades in below the threshold, 0..1
+    pub bloom_knee: f32,
+    /// How much bloom is added back; 0 turns it off
+    pub bloom_intensity: f32,
+    /// The display's gamma; 2.2 for most monitors
+    pub gamma: f32,
+}
+
+impl Default for PostSettings {
+    fn default() -> Self {
+        Self {
+            exposure: 1.0,
+            tonemap: Tonemap::Aces,
+            bloom_threshold: 1.0,
+            bloom_knee: 0.5,
+            bloom_intensity: 0.3,
+            gamma: 2.2,
+        }
+    }
+}
+
+/// Names a pass added with `Engine::add_post_pass`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PostPassId(usize);
+
+// The uniform block every single-texture pass gets at set 0 binding 2
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PassUniforms {
+    params: [f32; 4],
+    // Source width, height, 1/width, 1/height
+    size: [f32; 4],
+}
+
+impl PassUniforms {
+    fn new(params: [f32; 4], (w, h): (u32, u32)) -> Self {
+        let (w, h) = (w as f32, h as f32);
+        Self {
+            params,
+            size: [w, h, 1.0 / w, 1.0 / h],
+        }
+    }
+}
+
+struct RenderTarget {
+    view: wgpu::TextureView,
+    size: (u32, u32),
+}
+
+impl RenderTarget {
+    fn new(device: &wgpu::Device, (width, height): (u32, u32), label: &str) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: HDR_FORMAT,
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+        });
+        Self {
+            view: texture.create_view(&wgpu::TextureViewDescriptor::default()),
+            size: (width, height),
+        }
+    }
+}
+
+struct CustomPass {
+    id: PostPassId,
+    pipeline: wgpu::RenderPipeline,
+    params: [f32; 4],
+    uniforms: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+pub(crate) struct PostProcess {
+    settings: PostSettings,
+    // Whether the frame is sRGB, so the hardware already does most of the
+    // gamma curve
+    srgb_output: bool,
+    sampler: wgpu::Sampler,
+    pass_layout: wgpu::BindGroupLayout,
+    pass_pipeline_layout: wgpu::PipelineLayout,
+    tonemap_layout: wgpu::BindGroupLayout,
+    fullscreen: wgpu::ShaderModule,
+    down_pipeline: wgpu::RenderPipeline,
+    up_pipeline: wgpu::RenderPipeline,
+    tonemap_pipeline: wgpu::RenderPipeline,
+    // The main pass draws into the first; custom passes go back and forth
+    hdr: [RenderTarget; 2],
+    bloom: Vec<RenderTarget>,
+    // Everything below depends on the sizes above and on how many custom
+    // passes there are, and gets remade by `rebuild`
+    down_bind_groups: Vec<(wgpu::Buffer, wgpu::BindGroup)>,
+    up_bind_groups: Vec<(wgpu::Buffer, wgpu::BindGroup)>,
+    tonemap_uniforms: wgpu::Buffer,
+    tonemap_bind_group: wgpu::BindGroup,
+    custom: Vec<CustomPass>,
+    next_id: usize,
+}
+
+impl PostProcess {
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        size: (u32, u32),
+        output_format: wgpu::TextureFormat,
+    ) -> Self {
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("post_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        let pass_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("post_pass_layout"),
+            entries: &[
+                texture_entry(0),
+                sampler_entry(1),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let tonemap_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("tonemap_layout"),
+            entries: &[
+                texture_entry(0),
+                sampler_entry(1),
+                texture_entry(2),
+                sampler_entry(3),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let pass_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("post_pass_pipeline_layout"),
+            bind_group_layouts: &[&pass_layout],
+            push_constant_ranges: &[],
+        });
+        let tonemap_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("tonemap_pipeline_layout"),
+                bind_group_layouts: &[&tonemap_layout],
+                push_constant_ranges: &[],
+            });
+
+        let fullscreen = device.create_shader_module(&wgpu::include_spirv!("fullscreen.vert.spv"));
+        let down_module = device.create_shader_module(&wgpu::include_spirv!("bloom_down.frag.spv"));
+        let up_module = device.create_shader_module(&wgpu::include_spirv!("bloom_up.frag.spv"));
+        let tonemap_module = device.create_shader_module(&wgpu::include_spirv!("tonemap.frag.spv"));
+        let down_pipeline = create_pipeline(
+            device,
+            "bloom_down",
+            &pass_pipeline_layout,
+            &fullscreen,
+            &down_module,
+            HDR_FORMAT,
+            wgpu::BlendState::REPLACE,
+        );
+        // Each level up gets added onto what the downsample left there
+        let up_pipeline = create_pipeline(
+            device,
+            "bloom_up",
+            &pass_pipeline_layout,
+            &fullscreen,
+            &up_module,
+            HDR_FORMAT,
+            wgpu::BlendState {
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+        );
+        let tonemap_pipeline = create_pipeline(
+            device,
+            "tonemap",
+            &tonemap_pipeline_layout,
+            &fullscreen,
+            &tonemap_module,
+            output_format,
+            wgpu::BlendState::REPLACE,
+        );
+
+        let (hdr, bloom) = Self::create_targets(device, size);
+        let settings = PostSettings::default();
+        let srgb_output = output_format.describe().srgb;
+        let tonemap_uniforms = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("tonemap_uniforms"),
+            contents: bytemuck::cast_slice(&[tonemap_params(&settings, srgb_output)]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+        let tonemap_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("tonemap_bind_group"),
+            layout: &tonemap_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&hdr[0].view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&bloom[0].view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: tonemap_uniforms.as_entire_binding(),
+                },
+            ],
+        });
+
+        let mut post = Self {
+            settings,
+            srgb_output,
+            sampler,
+            pass_layout,
+            pass_pipeline_layout,
+            tonemap_layout,
+            fullscreen,
+            down_pipeline,
+            up_pipeline,
+            tonemap_pipeline,
+            hdr,
+            bloom,
+            down_bind_groups: vec![],
+            up_bind_groups: vec![],
+            tonemap_uniforms,
+            tonemap_bind_group,
+            custom: vec![],
+            next_id: 0,
+        };
+        post.rebuild(device);
+        post
+    }
+
+    fn create_targets(
+        device: &wgpu::Device,
+        (width, height): (u32, u32),
+    ) -> ([RenderTarget; 2], Vec<RenderTarget>) {
+        let (width, height) = (width.max(1), height.max(1));
+        let hdr = [
+            RenderTarget::new(device, (width, height), "hdr_scene"),
+            RenderTarget::new(device, (width, height), "hdr_ping"),
+        ];
+        let bloom = (1..=BLOOM_LEVELS)
+            .map(|i| {
+                let size = ((width >> i).max(1), (height >> i).max(1));
+                RenderTarget::new(device, size, "bloom")
+            })
+            .collect();
+        (hdr, bloom)
+    }
+
+    /// Where the main pass should draw (or resolve to)
+    pub(crate) fn scene_view(&self) -> &wgpu::TextureView {
+        &self.hdr[0].view
+    }
+
+    pub(crate) fn resize(&mut self, device: &wgpu::Device, size: (u32, u32)) {
+        let (hdr, bloom) = Self::create_targets(device, size);
+        self.hdr = hdr;
+        self.bloom = bloom;
+        self.rebuild(device);
+    }
+
+    pub(crate) fn settings(&self) -> PostSettings {
+        self.settings
+    }
+
+    pub(crate) fn set_settings(&mut self, queue: &wgpu::Queue, settings: PostSettings) {
+        self.settings = settings;
+        queue.write_buffer(
+            &self.tonemap_uniforms,
+            0,
+            bytemuck::cast_slice(&[tonemap_params(&settings, self.srgb_output)]),
+        );
+        let uniforms = PassUniforms::new(
+            [settings.bloom_threshold, settings.bloom_knee, 0.0, 0.0],
+            self.hdr[0].size,
+        );
+        queue.write_buffer(
+            &self.down_bind_groups[0].0,
+            0,
+            bytemuck::cast_slice(&[uniforms]),
+        );
+    }
+
+    pub(crate) fn add_pass(
+        &mut self,
+        device: &wgpu::Device,
+        label: &str,
+        spirv: &[u8],
+    ) -> Result<PostPassId> {
+        // make_spirv panics on these, and games would rather get an error
+        let words = spirv.len() / 4;
+        if words == 0 || words * 4 != spirv.len() {
+            bail!("{} isn't SPIR-V: its length isn't a multiple of 4", label);
+        }
+        if spirv[..4] != 0x0723_0203_u32.to_le_bytes() {
+            bail!("{} isn't SPIR-V: wrong magic number", label);
+        }
+        let module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some(label),
+            source: wgpu::util::make_spirv(spirv),
+            flags: wgpu::ShaderFlags::VALIDATION,
+        });
+        let pipeline = create_pipeline(
+            device,
+            label,
+            &self.pass_pipeline_layout,
+            &self.fullscreen,
+            &module,
+            HDR_FORMAT,
+            wgpu::BlendState::REPLACE,
+        );
+        let id = PostPassId(self.next_id);
+        self.next_id += 1;
+        let params = [0.0; 4];
+        // Bound to a real source by `rebuild`
+        let (uniforms, bind_group) =
+            self.create_pass_bind_group(device, &self.hdr[0], params, "post_pass");
+        self.custom.push(CustomPass {
+            id,
+            pipeline,
+            params,
+            uniforms,
+            bind_group,
+        });
+        self.rebuild(device);
+        Ok(id)
+    }
+
+    pub(crate) fn set_pass_params(
+        &mut self,
+        queue: &wgpu::Queue,
+        id: PostPassId,
+        params: [f32; 4],
+    ) {
+        let hdr = &self.hdr;
+        if let Some((i, pass)) = self.custom.iter_mut().enumerate().find(|(_, p)| p.id == id) {
+            pass.params = params;
+            let uniforms = PassUniforms::new(params, hdr[i % 2].size);
+            queue.write_buffer(&pass.uniforms, 0, bytemuck::cast_slice(&[uniforms]));
+        }
+    }
+
+    /// Returns whether there was such a pass
+    pub(crate) fn remove_pass(&mut self, device: &wgpu::Device, id: PostPassId) -> bool {
+        let before = self.custom.len();
+        self.custom.retain(|p| p.id != id);
+        if self.custom.len() == before {
+            return false;
+        }
+        self.rebuild(device);
+        true
+    }
+
+    fn create_pass_bind_group(
+        &self,
+        device: &wgpu::Device,
+        source: &RenderTarget,
+        params: [f32; 4],
+        label: &str,
+    ) -> (wgpu::Buffer, wgpu::BindGroup) {
+        let uniforms = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(label),
+            contents: bytemuck::cast_slice(&[PassUniforms::new(params, source.size)]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(label),
+            layout: &self.pass_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&source.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: uniforms.as_entire_binding(),
+                },
+            ],
+        });
+        (uniforms, bind_group)
+    }
+
+    // Custom pass i reads hdr[i % 2] and writes the other one, so which HDR
+    // texture holds the finished scene depends on how many there are
+    fn final_hdr(&self) -> &RenderTarget {
+        &self.hdr[self.custom.len() % 2]
+    }
+
+    // Remake every bind group that points at a texture
+    fn rebuild(&mut self, device: &wgpu::Device) {
+        let mut custom = std::mem::take(&mut self.custom);
+        for (i, pass) in custom.iter_mut().enumerate() {
+            let (uniforms, bind_group) =
+                self.create_pass_bind_group(device, &self.hdr[i % 2], pass.params, "post_pass");
+            pass.uniforms = uniforms;
+            pass.bind_group = bind_group;
+        }
+        self.custom = custom;
+
+        let threshold = [
+            self.settings.bloom_threshold,
+            self.settings.bloom_knee,
+            0.0,
+            0.0,
+        ];
+        let mut down =
+            vec![self.create_pass_bind_group(device, self.final_hdr(), threshold, "bloom_down")];
+        for source in self.bloom[..BLOOM_LEVELS - 1].iter() {
+            down.push(self.create_pass_bind_group(device, source, [0.0; 4], "bloom_down"));
+        }
+        // up[i] takes level i + 1 up to level i
+        let up = self.bloom[1..]
+            .iter()
+            .map(|source| {
+                self.create_pass_bind_group(device, source, [1.0, 0.0, 0.0, 0.0], "bloom_up")
+            })
+            .collect();
+        self.down_bind_groups = down;
+        self.up_bind_groups = up;
+
+        self.tonemap_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("tonemap_bind_group"),
+            layout: &self.tonemap_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&self.final_hdr().view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&self.bloom[0].view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: self.tonemap_uniforms.as_entire_binding(),
+                },
+            ],
+        });
+    }
+
+    /// Everything after the main pass, ending up in `output`
+    pub(crate) fn run(&self, encoder: &mut wgpu::CommandEncoder, output: &wgpu::TextureView) {
+        for (i, pass) in self.custom.iter().enumerate() {
+            fullscreen_pass(
+                encoder,
+                "Post Pass",
+                &self.hdr[(i + 1) % 2].view,
+                wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                &pass.pipeline,
+                &pass.bind_group,
+            );
+        }
+        if self.settings.bloom_intensity > 0.0 {
+            for (target, (_, bind_group)) in self.bloom.iter().zip(self.down_bind_groups.iter()) {
+                fullscreen_pass(
+                    encoder,
+                    "Bloom Down",
+                    &target.view,
+                    wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    &self.down_pipeline,
+                    bind_group,
+                );
+            }
+            for (target, (_, bind_group)) in self.bloom.iter().zip(self.up_bind_groups.iter()).rev()
+            {
+                fullscreen_pass(
+                    encoder,
+                    "Bloom Up",
+                    &target.view,
+                    wgpu::LoadOp::Load,
+                    &self.up_pipeline,
+                    bind_group,
+                );
+            }
+        }
+        fullscreen_pass(
+            encoder,
+            "Tonemap",
+            output,
+            wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+            &self.tonemap_pipeline,
+            &self.tonemap_bind_group,
+        );
+    }
+}
+
+// Exposure, bloom, curve and gamma exponent, laid out for tonemap.frag
+fn tonemap_params(settings: &PostSettings, srgb_output: bool) -> [f32; 4] {
+    let curve = match settings.tonemap {
+        Tonemap::None => 0.0,
+        Tonemap::Reinhard => 1.0,
+        Tonemap::Aces => 2.0,
+    };
+    let gamma = settings.gamma.max(0.01);
+    // An sRGB frame gets roughly 2.2 applied on write already
+    let exponent = if srgb_output {
+        2.2 / gamma
+    } else {
+        1.0 / gamma
+    };
+    [settings.exposure, settings.bloom_intensity, curve, exponent]
+}
+
+fn texture_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStage::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+            multisampled: false,
+            view_dimension: wgpu::TextureViewDimension::D2,
+            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+        },
+        count: None,
+    }
+}
+
+fn sampler_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStage::FRAGMENT,
+        ty: wgpu::BindingType::Sampler {
+            comparison: false,
+            filtering: true,
+        },
+        count: None,
+    }
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    label: &str,
+    layout: &wgpu::PipelineLayout,
+    vs_module: &wgpu::ShaderModule,
+    fs_module: &wgpu::ShaderModule,
+    format: wgpu::TextureFormat,
+    blend: wgpu::BlendState,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: vs_module,
+            entry_point: "main",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: fs_module,
+            entry_point: "main",
+            targets: &[wgpu::ColorTargetState {
+                format,
+                alpha_blend: blend.clone(),
+                color_blend: blend,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: wgpu::CullMode::None,
+            polygon_mode: wgpu::PolygonMode::Fill,
+        },
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+    })
+}
+
+fn fullscreen_pass(
+    encoder: &mut wgpu::CommandEncoder,
+    label: &str,
+    target: &wgpu::TextureView,
+    load: wgpu::LoadOp<wgpu::Color>,
+    pipeline: &wgpu::RenderPipeline,
+    bind_group: &wgpu::BindGroup,
+) {
+    let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some(label),
+        color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+            attachment: target,
+            resolve_target: None,
+            ops: wgpu::Operations { load, store: true },
+        }],
+        depth_stencil_attachment: None,
+    });
+    pass.set_pipeline(pipeline);
+    pass.set_bind_group(0, bind_group, &[]);
+    pass.draw(0..3, 0..1);
+}
@@ -1,22 +1,118 @@
+use crate::animation::MAX_JOINTS;
 use crate::assets::{Assets, ModelRef};
 use crate::camera::Camera;
+use crate::clusters::{ClusterUniforms, Clusters, CLUSTER_COUNT};
+use crate::debug_draw::{DebugDraw, DebugVertex};
+use crate::geom::{Frustum, Mat4, Pos3, Sphere, Vec3};
 use crate::model::*;
+use crate::post::{self, PostPassId, PostProcess, PostSettings};
 use crate::texture;
 use crate::Game;
-use cgmath::SquareMatrix;
+use cgmath::{InnerSpace, SquareMatrix, Transform};
 use pixels::Pixels;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use wgpu::util::DeviceExt;
 
+use anyhow::{bail, Context};
 use winit::{dpi::PhysicalSize, window::Window};
+
+// Has to match the size of u_shadow_view_proj in shader.frag
+const MAX_SHADOWS: usize = 4;
+// Each skinned draw gets this much of the joint buffer, picked out with a
+// dynamic offset
+const JOINT_SLOT: wgpu::BufferAddress =
+    (MAX_JOINTS * std::mem::size_of::<[[f32; 4]; 4]>()) as wgpu::BufferAddress;
+
+// Where frames end up
+enum Target {
+    Window {
+        surface: wgpu::Surface,
+        sc_desc: wgpu::SwapChainDescriptor,
+        swap_chain: wgpu::SwapChain,
+    },
+    // A texture we can copy back to the CPU, for running without a display
+    Offscreen {
+        texture: wgpu::Texture,
+        view: wgpu::TextureView,
+    },
+}
+
+/// Quality knobs, e.g. for a graphics options menu.  Set at startup with
+/// `run_with_settings` and changed later with `Engine::set_render_settings`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RenderSettings {
+    /// Samples per pixel for antialiasing: 1 (off), 2, 4 or 8
+    pub msaa_samples: u32,
+    /// Fifo waits for vsync; Immediate and Mailbox don't
+    pub present_mode: wgpu::PresentMode,
+    /// Width and height of each light's shadow map
+    pub shadow_map_size: u32,
+    /// Lights past this many in `set_lights` are left out
+    pub max_lights: usize,
+    /// Skip instances whose bounding spheres are outside the camera's view
+    pub frustum_culling: bool,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            msaa_samples: 4,
+            present_mode: wgpu::PresentMode::Fifo,
+            shadow_map_size: 2048,
+            max_lights: 1024,
+            frustum_culling: true,
+        }
+    }
+}
+
+impl RenderSettings {
+    // Sample counts have to be powers of two, and shadow maps can't be empty
+    fn validated(self) -> Self {
+        Self {
+            msaa_samples: self.msaa_samples.clamp(1, 8).next_power_of_two(),
+            shadow_map_size: self.shadow_map_size.max(1),
+            ..self
+        }
+    }
+}
+
+/// What shows where nothing was drawn
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Background {
+    /// A flat linear colour
+    Color([f32; 3]),
+    /// The environment cube map, which also lights PBR materials: a sky
+    /// gradient until the game loads one
+    Skybox,
+}
+
 pub(crate) struct Render {
-    surface: wgpu::Surface,
+    target: Target,
+    format: wgpu::TextureFormat,
+    // Frames to be captured from a window get drawn a second time into
+    // this, since swap chain frames can't be read back
+    capture_target: Option<(wgpu::Texture, wgpu::TextureView)>,
     pub(crate) device: wgpu::Device,
     pub(crate) queue: wgpu::Queue,
-    sc_desc: wgpu::SwapChainDescriptor,
-    swap_chain: wgpu::SwapChain,
     pub(crate) size: winit::dpi::PhysicalSize<u32>,
+    settings: RenderSettings,
+    render_pipeline_layout: wgpu::PipelineLayout,
     render_pipeline: wgpu::RenderPipeline,
+    // Like render_pipeline, but blended and without depth writes
+    transparent_pipeline: wgpu::RenderPipeline,
+    // This frame's transparent meshes, farthest first
+    transparent: Vec<TransparentDraw>,
+    sky_pipeline_layout: wgpu::PipelineLayout,
+    sky_pipeline: wgpu::RenderPipeline,
+    background: Background,
+    // DebugDraw's lines, drawn straight onto the frame after post-processing
+    debug_pipeline: wgpu::RenderPipeline,
+    debug_buffer: GrowableBuffer,
+    debug_vertex_count: u32,
+    // Where the main pass draws when multisampling, before it's resolved
+    // into the HDR target
+    msaa_target: Option<wgpu::TextureView>,
+    post: PostProcess,
     pub(crate) texture_layout: wgpu::BindGroupLayout,
     pub(crate) camera: Camera,
     uniforms: Uniforms,
@@ -25,14 +121,53 @@ pub(crate) struct Render {
     pub(crate) ambient: f32,
     light_ambient_buffer: wgpu::Buffer,
     lights: Vec<crate::lights::Light>,
-    light_buffer: wgpu::Buffer,
+    clusters: Clusters,
+    light_buffer: GrowableBuffer,
+    cluster_uniform_buffer: wgpu::Buffer,
+    cluster_buffer: wgpu::Buffer,
+    light_index_buffer: GrowableBuffer,
+    light_bind_group_layout: wgpu::BindGroupLayout,
     light_bind_group: wgpu::BindGroup,
+    environment: texture::Texture,
+    shadow_pipeline: wgpu::RenderPipeline,
+    // Per layer: a view to render into, and the light's view-projection for
+    // the shadow pass
+    shadow_layers: Vec<(wgpu::TextureView, wgpu::Buffer, wgpu::BindGroup)>,
+    // All the layers' view-projections, for the main pass
+    shadow_buffer: wgpu::Buffer,
+    shadow_view: wgpu::TextureView,
+    shadow_sampler: wgpu::Sampler,
+    // Group 3 of the main pipeline: the shadow maps, plus the joint palette
+    // for skinned draws
+    shadow_bind_group_layout: wgpu::BindGroupLayout,
+    shadow_bind_group: wgpu::BindGroup,
+    // Every skinned instance's joint matrices, one JOINT_SLOT each; the
+    // first slot is for everything drawn unskinned
+    joint_buffer: GrowableBuffer,
+    // Just the joint palette, for the shadow pipeline
+    joint_bind_group_layout: wgpu::BindGroupLayout,
+    joint_bind_group: wgpu::BindGroup,
+    // How many layers are in use this frame
+    shadow_count: usize,
+    // How wide an area around the camera target directed lights shadow
+    pub(crate) shadow_extent: f32,
     depth_texture: texture::Texture,
     instance_groups: InstanceGroups,
+    culling_stats: CullingStats,
+}
+
+/// How many instances the last frame drew, after frustum culling.  Skinned
+/// instances are never culled, since their poses can reach past their
+/// models' bounds.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct CullingStats {
+    pub instances: usize,
+    pub visible: usize,
+    pub culled: usize,
 }
 
 impl Render {
-    pub(crate) async fn new(window: &Window) -> Self {
+    pub(crate) async fn new(window: &Window, settings: RenderSettings) -> Self {
         let size = window.inner_size();
 
         // The instance is a handle to our GPU
@@ -63,11 +198,101 @@ impl Render {
             format: adapter.get_swap_chain_preferred_format(&surface),
             width: size.width,
             height: size.height,
-            present_mode: wgpu::PresentMode::Fifo,
+            present_mode: settings.present_mode,
         };
 
         let swap_chain = device.create_swap_chain(&surface, &sc_desc);
+        let format = sc_desc.format;
+        Self::with_target(
+            device,
+            queue,
+            size,
+            format,
+            Target::Window {
+                surface,
+                sc_desc,
+                swap_chain,
+            },
+            settings,
+        )
+    }
+
+    /// Draws into an offscreen texture instead of a window, so this works
+    /// without a display.  Software adapters (llvmpipe, WARP, ...) are
+    /// preferred when there is one, since they give the same pixels on any
+    /// machine.
+    pub(crate) async fn headless(width: u32, height: u32) -> anyhow::Result<Self> {
+        let backends = wgpu::BackendBit::all();
+        let instance = wgpu::Instance::new(backends);
+        let software = instance
+            .enumerate_adapters(backends)
+            .find(|a| a.get_info().device_type == wgpu::DeviceType::Cpu);
+        let adapter = match software {
+            Some(adapter) => adapter,
+            None => instance
+                .request_adapter(&wgpu::RequestAdapterOptions {
+                    power_preference: wgpu::PowerPreference::default(),
+                    compatible_surface: None,
+                })
+                .await
+                .context("No graphics adapter available")?,
+        };
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: None,
+                    features: wgpu::Features::empty(),
+                    limits: wgpu::Limits::default(),
+                },
+                None, // Trace path
+            )
+            .await?;
+        let format = Self::OFFSCREEN_FORMAT;
+        let (texture, view) = Self::create_offscreen(&device, width, height, format);
+        Ok(Self::with_target(
+            device,
+            queue,
+            PhysicalSize::new(width, height),
+            format,
+            Target::Offscreen { texture, view },
+            RenderSettings::default(),
+        ))
+    }
+
+    const OFFSCREEN_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+    fn create_offscreen(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        format: wgpu::TextureFormat,
+    ) -> (wgpu::Texture, wgpu::TextureView) {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("offscreen_target"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::COPY_SRC,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        (texture, view)
+    }
 
+    fn with_target(
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        size: PhysicalSize<u32>,
+        format: wgpu::TextureFormat,
+        target: Target,
+        settings: RenderSettings,
+    ) -> Self {
+        let settings = settings.validated();
         let texture_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 entries: &[
@@ -77,7 +302,7 @@ impl Render {
                         ty: wgpu::BindingType::Texture {
                             multisampled: false,
                             view_dimension: wgpu::TextureViewDimension::D2,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
                         },
                         count: None,
                     },
@@ -90,6 +315,77 @@ impl Render {
                         },
                         count: None,
                     },
+                    // Normal map
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler {
+                            comparison: false,
+                            filtering: true,
+                        },
+                        count: None,
+                    },
+                    // Specular map
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler {
+                            comparison: false,
+                            filtering: true,
+                        },
+                        count: None,
+                    },
+                    // Emissive map
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 6,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 7,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler {
+                            comparison: false,
+                            filtering: true,
+                        },
+                        count: None,
+                    },
+                    // MaterialParams
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 8,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
                 ],
                 label: Some("texture_bind_group_layout"),
             });
@@ -98,7 +394,7 @@ impl Render {
             eye: (0.0, 5.0, -10.0).into(),
             target: (0.0, 0.0, 0.0).into(),
             up: cgmath::Vector3::unit_y(),
-            aspect: sc_desc.width as f32 / sc_desc.height as f32,
+            aspect: size.width as f32 / size.height as f32,
             fovy: 45.0,
             znear: 0.1,
             zfar: 200.0,
@@ -136,19 +432,30 @@ impl Render {
             }],
             label: Some("uniform_bind_group"),
         });
-        use crate::geom::*;
-        let lights = vec![crate::lights::Light::point(
-            Pos3::new(0.0, 10.0, 0.0),
-            Vec3::new(1.0, 1.0, 1.0),
-        )];
-        let light_uniform_size =
-            (10 * std::mem::size_of::<crate::lights::Light>()) as wgpu::BufferAddress;
-        let light_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Lights buffer"),
-            size: light_uniform_size,
-            usage: wgpu::BufferUsage::UNIFORM
-                | wgpu::BufferUsage::COPY_SRC
-                | wgpu::BufferUsage::COPY_DST,
+        // Lights live in storage buffers so there can be any number of
+        // them; see clusters.rs for how they get to the fragment shader
+        let lights = vec![];
+        let clusters = Clusters::new();
+        let light_buffer = GrowableBuffer::new(
+            &device,
+            "Lights buffer",
+            (64 * std::mem::size_of::<crate::lights::Light>()) as wgpu::BufferAddress,
+        );
+        let light_index_buffer = GrowableBuffer::new(
+            &device,
+            "Light index buffer",
+            (4096 * std::mem::size_of::<u32>()) as wgpu::BufferAddress,
+        );
+        let cluster_uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cluster uniform buffer"),
+            size: std::mem::size_of::<ClusterUniforms>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let cluster_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cluster buffer"),
+            size: (CLUSTER_COUNT * std::mem::size_of::<[u32; 2]>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
             mapped_at_creation: false,
         });
         let light_bind_group_layout =
@@ -156,14 +463,11 @@ impl Render {
                 entries: &[
                     wgpu::BindGroupLayoutEntry {
                         binding: 0,
-                        visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
                         ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Uniform,
+                            ty: wgpu::BufferBindingType::Storage { read_only: true },
                             has_dynamic_offset: false,
-                            min_binding_size: wgpu::BufferSize::new(
-                                10 * std::mem::size_of::<crate::lights::Light>()
-                                    as wgpu::BufferAddress,
-                            ),
+                            min_binding_size: None,
                         },
                         count: None,
                     },
@@ -179,6 +483,56 @@ impl Render {
                         },
                         count: None,
                     },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // Environment map, for PBR materials' ambient light
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::Cube,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 6,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler {
+                            comparison: false,
+                            filtering: true,
+                        },
+                        count: None,
+                    },
                 ],
                 label: Some("light_bind_group_layout"),
             });
@@ -193,71 +547,182 @@ impl Render {
                 | wgpu::BufferUsage::COPY_DST,
         });
 
-        let light_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &light_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: light_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: light_ambient_buffer.as_entire_binding(),
-                },
+        // A plain sky until the game sets a real environment
+        let environment = texture::Texture::gradient_cube(
+            &device,
+            &queue,
+            [120, 160, 220],
+            [220, 225, 230],
+            [70, 65, 60],
+        );
+        let light_bind_group = Self::create_light_bind_group(
+            &device,
+            &light_bind_group_layout,
+            [
+                &light_buffer.buffer,
+                &light_ambient_buffer,
+                &cluster_uniform_buffer,
+                &cluster_buffer,
+                &light_index_buffer.buffer,
             ],
-            label: Some("light_bind_group"),
-        });
+            &environment,
+        );
 
-        let depth_texture =
-            texture::Texture::create_depth_texture(&device, &sc_desc, "depth_texture");
+        let depth_texture = texture::Texture::create_depth_texture(
+            &device,
+            size.width,
+            size.height,
+            settings.msaa_samples,
+            "depth_texture",
+        );
+        let msaa_target =
+            Self::create_msaa_target(&device, size, post::HDR_FORMAT, settings.msaa_samples);
+        let post = PostProcess::new(&device, (size.width, size.height), format);
 
-        let render_pipeline_layout =
-            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Render Pipeline Layout"),
-                bind_group_layouts: &[
-                    &texture_bind_group_layout,
-                    &uniform_bind_group_layout,
-                    &light_bind_group_layout,
+        let (shadow_view, shadow_layer_views) =
+            Self::create_shadow_maps(&device, settings.shadow_map_size);
+        let shadow_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("shadow_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+        let shadow_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Shadows buffer"),
+            size: std::mem::size_of::<ShadowUniforms>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let shadow_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2Array,
+                            sample_type: wgpu::TextureSampleType::Depth,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler {
+                            comparison: true,
+                            filtering: true,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    Self::joint_layout_entry(3),
                 ],
-                push_constant_ranges: &[],
+                label: Some("shadow_bind_group_layout"),
+            });
+        let joint_buffer = GrowableBuffer::new(&device, "Joint buffer", JOINT_SLOT);
+        let shadow_bind_group = Self::create_shadow_bind_group(
+            &device,
+            &shadow_bind_group_layout,
+            &shadow_view,
+            &shadow_sampler,
+            &shadow_buffer,
+            &joint_buffer.buffer,
+        );
+        let joint_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[Self::joint_layout_entry(0)],
+                label: Some("joint_bind_group_layout"),
             });
+        let joint_bind_group =
+            Self::create_joint_bind_group(&device, &joint_bind_group_layout, &joint_buffer.buffer);
 
-        let vs_module = device.create_shader_module(&wgpu::include_spirv!("shader.vert.spv"));
-        let fs_module = device.create_shader_module(&wgpu::include_spirv!("shader.frag.spv"));
+        let shadow_pass_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+                label: Some("shadow_pass_layout"),
+            });
+        let shadow_layers = shadow_layer_views
+            .into_iter()
+            .map(|view| {
+                let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Shadow pass buffer"),
+                    size: std::mem::size_of::<[[f32; 4]; 4]>() as wgpu::BufferAddress,
+                    usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                    mapped_at_creation: false,
+                });
+                let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    layout: &shadow_pass_layout,
+                    entries: &[wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: buffer.as_entire_binding(),
+                    }],
+                    label: Some("shadow_pass_bind_group"),
+                });
+                (view, buffer, bind_group)
+            })
+            .collect();
 
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
+        let shadow_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Shadow Pipeline Layout"),
+                bind_group_layouts: &[&shadow_pass_layout, &joint_bind_group_layout],
+                push_constant_ranges: &[],
+            });
+        let shadow_vs_module =
+            device.create_shader_module(&wgpu::include_spirv!("shadow.vert.spv"));
+        let shadow_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Shadow Pipeline"),
+            layout: Some(&shadow_pipeline_layout),
             vertex: wgpu::VertexState {
-                module: &vs_module,
+                module: &shadow_vs_module,
                 entry_point: "main",
                 buffers: &[ModelVertex::desc(), InstanceRaw::desc()],
             },
-            fragment: Some(wgpu::FragmentState {
-                module: &fs_module,
-                entry_point: "main",
-                targets: &[wgpu::ColorTargetState {
-                    format: sc_desc.format,
-                    alpha_blend: wgpu::BlendState::REPLACE,
-                    color_blend: wgpu::BlendState::REPLACE,
-                    write_mask: wgpu::ColorWrite::ALL,
-                }],
-            }),
+            // Depth only
+            fragment: None,
             primitive: wgpu::PrimitiveState {
                 topology: wgpu::PrimitiveTopology::TriangleList,
                 strip_index_format: None,
                 front_face: wgpu::FrontFace::Ccw,
                 cull_mode: wgpu::CullMode::Back,
-                // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
                 polygon_mode: wgpu::PolygonMode::Fill,
             },
             depth_stencil: Some(wgpu::DepthStencilState {
                 format: texture::Texture::DEPTH_FORMAT,
                 depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
+                depth_compare: wgpu::CompareFunction::LessEqual,
                 stencil: wgpu::StencilState::default(),
-                bias: wgpu::DepthBiasState::default(),
-                // Setting this to true requires Features::DEPTH_CLAMPING
+                // A little slope-scaled bias on top of each light's own, so
+                // surfaces at grazing angles don't shadow themselves
+                bias: wgpu::DepthBiasState {
+                    constant: 2,
+                    slope_scale: 2.0,
+                    clamp: 0.0,
+                },
                 clamp_depth: false,
             }),
             multisample: wgpu::MultisampleState {
@@ -267,14 +732,69 @@ impl Render {
             },
         });
 
+        let render_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Render Pipeline Layout"),
+                bind_group_layouts: &[
+                    &texture_bind_group_layout,
+                    &uniform_bind_group_layout,
+                    &light_bind_group_layout,
+                    &shadow_bind_group_layout,
+                ],
+                push_constant_ranges: &[],
+            });
+
+        let render_pipeline = Self::create_render_pipeline(
+            &device,
+            &render_pipeline_layout,
+            post::HDR_FORMAT,
+            settings.msaa_samples,
+            false,
+        );
+        let transparent_pipeline = Self::create_render_pipeline(
+            &device,
+            &render_pipeline_layout,
+            post::HDR_FORMAT,
+            settings.msaa_samples,
+            true,
+        );
+
+        let sky_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Sky Pipeline Layout"),
+            bind_group_layouts: &[&uniform_bind_group_layout, &light_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let sky_pipeline =
+            Self::create_sky_pipeline(&device, &sky_pipeline_layout, settings.msaa_samples);
+        let debug_pipeline =
+            Self::create_debug_pipeline(&device, &uniform_bind_group_layout, format);
+        let debug_buffer = GrowableBuffer::with_usage(
+            &device,
+            "Debug lines",
+            1024,
+            wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+        );
+
         Self {
-            surface,
+            target,
+            format,
+            capture_target: None,
             device,
             queue,
-            sc_desc,
-            swap_chain,
             size,
+            settings,
+            render_pipeline_layout,
             render_pipeline,
+            transparent_pipeline,
+            transparent: vec![],
+            sky_pipeline_layout,
+            sky_pipeline,
+            background: Background::Skybox,
+            debug_pipeline,
+            debug_buffer,
+            debug_vertex_count: 0,
+            msaa_target,
+            post,
             camera,
             uniform_buffer,
             uniform_bind_group,
@@ -282,25 +802,631 @@ impl Render {
             ambient,
             light_ambient_buffer,
             lights,
+            clusters,
             light_buffer,
+            cluster_uniform_buffer,
+            cluster_buffer,
+            light_index_buffer,
+            light_bind_group_layout,
             light_bind_group,
+            environment,
+            shadow_pipeline,
+            shadow_layers,
+            shadow_buffer,
+            shadow_view,
+            shadow_sampler,
+            shadow_bind_group_layout,
+            shadow_bind_group,
+            joint_buffer,
+            joint_bind_group_layout,
+            joint_bind_group,
+            shadow_count: 0,
+            shadow_extent: 40.0,
             texture_layout: texture_bind_group_layout,
             depth_texture,
             instance_groups: InstanceGroups::new(),
+            culling_stats: CullingStats::default(),
         }
     }
 
-    pub(crate) fn set_ambient(&mut self, amb: f32) {
-        self.ambient = amb;
+    // Buffers in binding order: lights, ambient, cluster uniforms, clusters,
+    // light indices; then the environment map
+    fn create_light_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        buffers: [&wgpu::Buffer; 5],
+        environment: &texture::Texture,
+    ) -> wgpu::BindGroup {
+        let mut entries: Vec<_> = buffers
+            .iter()
+            .enumerate()
+            .map(|(i, buf)| wgpu::BindGroupEntry {
+                binding: i as u32,
+                resource: buf.as_entire_binding(),
+            })
+            .collect();
+        entries.push(wgpu::BindGroupEntry {
+            binding: 5,
+            resource: wgpu::BindingResource::TextureView(&environment.view),
+        });
+        entries.push(wgpu::BindGroupEntry {
+            binding: 6,
+            resource: wgpu::BindingResource::Sampler(&environment.sampler),
+        });
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &entries,
+            label: Some("light_bind_group"),
+        })
+    }
+
+    fn create_render_pipeline(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        format: wgpu::TextureFormat,
+        samples: u32,
+        transparent: bool,
+    ) -> wgpu::RenderPipeline {
+        let vs_module = device.create_shader_module(&wgpu::include_spirv!("shader.vert.spv"));
+        // Transparent surfaces are drawn over what's behind them, and don't
+        // hide what's drawn after
+        let (color_blend, alpha_blend) = if transparent {
+            (
+                wgpu::BlendState {
+                    src_factor: wgpu::BlendFactor::SrcAlpha,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                wgpu::BlendState {
+                    src_factor: wgpu::BlendFactor::One,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+            )
+        } else {
+            (wgpu::BlendState::REPLACE, wgpu::BlendState::REPLACE)
+        };
+        let fs_module = device.create_shader_module(&wgpu::include_spirv!("shader.frag.spv"));
+
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(if transparent {
+                "Transparent Pipeline"
+            } else {
+                "Render Pipeline"
+            }),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: &vs_module,
+                entry_point: "main",
+                buffers: &[ModelVertex::desc(), InstanceRaw::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &fs_module,
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format,
+                    alpha_blend,
+                    color_blend,
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::Back,
+                // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
+                polygon_mode: wgpu::PolygonMode::Fill,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: texture::Texture::DEPTH_FORMAT,
+                depth_write_enabled: !transparent,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+                // Setting this to true requires Features::DEPTH_CLAMPING
+                clamp_depth: false,
+            }),
+            multisample: wgpu::MultisampleState {
+                count: samples,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+        })
+    }
+
+    // Only draws where the depth buffer is still clear, and doesn't write it
+    fn create_sky_pipeline(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        samples: u32,
+    ) -> wgpu::RenderPipeline {
+        let vs_module = device.create_shader_module(&wgpu::include_spirv!("sky.vert.spv"));
+        let fs_module = device.create_shader_module(&wgpu::include_spirv!("sky.frag.spv"));
+
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Sky Pipeline"),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: &vs_module,
+                entry_point: "main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &fs_module,
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format: post::HDR_FORMAT,
+                    alpha_blend: wgpu::BlendState::REPLACE,
+                    color_blend: wgpu::BlendState::REPLACE,
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: texture::Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+                clamp_depth: false,
+            }),
+            multisample: wgpu::MultisampleState {
+                count: samples,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+        })
+    }
+
+    // Single sampled and without depth, since it draws on the frame itself
+    fn create_debug_pipeline(
+        device: &wgpu::Device,
+        uniform_layout: &wgpu::BindGroupLayout,
+        format: wgpu::TextureFormat,
+    ) -> wgpu::RenderPipeline {
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Debug Pipeline Layout"),
+            bind_group_layouts: &[uniform_layout],
+            push_constant_ranges: &[],
+        });
+        let vs_module = device.create_shader_module(&wgpu::include_spirv!("debug.vert.spv"));
+        let fs_module = device.create_shader_module(&wgpu::include_spirv!("debug.frag.spv"));
+        let blend = wgpu::BlendState {
+            src_factor: wgpu::BlendFactor::SrcAlpha,
+            dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+            operation: wgpu::BlendOperation::Add,
+        };
+
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Debug Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &vs_module,
+                entry_point: "main",
+                buffers: &[DebugVertex::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &fs_module,
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format,
+                    alpha_blend: blend.clone(),
+                    color_blend: blend,
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::LineList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+        })
+    }
+
+    fn create_msaa_target(
+        device: &wgpu::Device,
+        size: PhysicalSize<u32>,
+        format: wgpu::TextureFormat,
+        samples: u32,
+    ) -> Option<wgpu::TextureView> {
+        if samples <= 1 {
+            return None;
+        }
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("msaa_target"),
+            size: wgpu::Extent3d {
+                width: size.width,
+                height: size.height,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: samples,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
+        });
+        Some(texture.create_view(&wgpu::TextureViewDescriptor::default()))
+    }
+
+    // Shadow maps are square, one layer per shadow casting light.  Gives a
+    // view of all the layers for sampling, and one per layer to draw into.
+    fn create_shadow_maps(
+        device: &wgpu::Device,
+        size: u32,
+    ) -> (wgpu::TextureView, Vec<wgpu::TextureView>) {
+        let shadow_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("shadow_texture"),
+            size: wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth: MAX_SHADOWS as u32,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: texture::Texture::DEPTH_FORMAT,
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+        });
+        let view = shadow_texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("shadow_view"),
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let layers = (0..MAX_SHADOWS as u32)
+            .map(|layer| {
+                shadow_texture.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some("shadow_layer_view"),
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_array_layer: layer,
+                    array_layer_count: std::num::NonZeroU32::new(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        (view, layers)
+    }
+
+    pub(crate) fn settings(&self) -> RenderSettings {
+        self.settings
+    }
+
+    /// Apply new settings, remaking whatever they affect
+    pub(crate) fn set_settings(&mut self, settings: RenderSettings) {
+        let settings = settings.validated();
+        let old = std::mem::replace(&mut self.settings, settings);
+        if settings.msaa_samples != old.msaa_samples {
+            self.render_pipeline = Self::create_render_pipeline(
+                &self.device,
+                &self.render_pipeline_layout,
+                post::HDR_FORMAT,
+                settings.msaa_samples,
+                false,
+            );
+            self.transparent_pipeline = Self::create_render_pipeline(
+                &self.device,
+                &self.render_pipeline_layout,
+                post::HDR_FORMAT,
+                settings.msaa_samples,
+                true,
+            );
+            self.sky_pipeline = Self::create_sky_pipeline(
+                &self.device,
+                &self.sky_pipeline_layout,
+                settings.msaa_samples,
+            );
+            self.create_attachments();
+        }
+        if settings.present_mode != old.present_mode {
+            if let Target::Window {
+                surface,
+                sc_desc,
+                swap_chain,
+            } = &mut self.target
+            {
+                sc_desc.present_mode = settings.present_mode;
+                *swap_chain = self.device.create_swap_chain(surface, sc_desc);
+            }
+        }
+        if settings.shadow_map_size != old.shadow_map_size {
+            let (view, layers) = Self::create_shadow_maps(&self.device, settings.shadow_map_size);
+            for (layer, new_view) in self.shadow_layers.iter_mut().zip(layers) {
+                layer.0 = new_view;
+            }
+            self.shadow_view = view;
+            self.shadow_bind_group = Self::create_shadow_bind_group(
+                &self.device,
+                &self.shadow_bind_group_layout,
+                &self.shadow_view,
+                &self.shadow_sampler,
+                &self.shadow_buffer,
+                &self.joint_buffer.buffer,
+            );
+        }
+        if settings.max_lights != old.max_lights {
+            // Cut down the lights already set, redoing their shadow layers in
+            // case a caster was dropped
+            let lights = std::mem::take(&mut self.lights);
+            self.set_lights(lights);
+        }
+    }
+
+    pub(crate) fn post_settings(&self) -> PostSettings {
+        self.post.settings()
+    }
+
+    pub(crate) fn set_post_settings(&mut self, settings: PostSettings) {
+        self.post.set_settings(&self.queue, settings);
+    }
+
+    pub(crate) fn add_post_pass(
+        &mut self,
+        label: &str,
+        spirv: &[u8],
+    ) -> anyhow::Result<PostPassId> {
+        self.post.add_pass(&self.device, label, spirv)
+    }
+
+    pub(crate) fn set_post_pass_params(&mut self, id: PostPassId, params: [f32; 4]) {
+        self.post.set_pass_params(&self.queue, id, params);
+    }
+
+    pub(crate) fn remove_post_pass(&mut self, id: PostPassId) -> bool {
+        self.post.remove_pass(&self.device, id)
+    }
+
+    // The depth buffer and MSAA target, which depend on the frame size and
+    // sample count
+    fn create_attachments(&mut self) {
+        self.depth_texture = texture::Texture::create_depth_texture(
+            &self.device,
+            self.size.width,
+            self.size.height,
+            self.settings.msaa_samples,
+            "depth_texture",
+        );
+        self.msaa_target = Self::create_msaa_target(
+            &self.device,
+            self.size,
+            post::HDR_FORMAT,
+            self.settings.msaa_samples,
+        );
+    }
+
+    // The joint palette is a slice of one big buffer, bound at a different
+    // offset for each skinned draw
+    fn joint_layout_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+        wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::VERTEX,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: true,
+                min_binding_size: wgpu::BufferSize::new(JOINT_SLOT),
+            },
+            count: None,
+        }
+    }
+
+    fn joint_binding(joints: &wgpu::Buffer) -> wgpu::BindingResource<'_> {
+        wgpu::BindingResource::Buffer {
+            buffer: joints,
+            offset: 0,
+            size: wgpu::BufferSize::new(JOINT_SLOT),
+        }
+    }
+
+    fn create_shadow_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        view: &wgpu::TextureView,
+        sampler: &wgpu::Sampler,
+        shadows: &wgpu::Buffer,
+        joints: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: shadows.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: Self::joint_binding(joints),
+                },
+            ],
+            label: Some("shadow_bind_group"),
+        })
+    }
+
+    fn create_joint_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        joints: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: Self::joint_binding(joints),
+            }],
+            label: Some("joint_bind_group"),
+        })
+    }
+
+    pub(crate) fn set_ambient(&mut self, amb: f32) {
+        self.ambient = amb;
         self.queue
             .write_buffer(&self.light_ambient_buffer, 0, bytemuck::cast_slice(&[amb]));
     }
 
-    pub(crate) fn set_lights(&mut self, ls: Vec<crate::lights::Light>) {
-        assert!(ls.len() < 10);
+    pub(crate) fn set_lights(&mut self, mut ls: Vec<crate::lights::Light>) {
+        ls.truncate(self.settings.max_lights);
+        // Hand out shadow map layers; lights past the first MAX_SHADOWS
+        // casters just don't get shadows
+        self.shadow_count = 0;
+        for l in ls.iter_mut() {
+            if l.casts_shadows() && self.shadow_count < MAX_SHADOWS {
+                l.shadow[3] = self.shadow_count as f32;
+                self.shadow_count += 1;
+            } else {
+                l.shadow[0] = 0.0;
+            }
+        }
         self.lights = ls;
+    }
+
+    fn write_uniforms(&mut self) {
+        self.uniforms.update_view_proj(&self.camera);
+        self.queue.write_buffer(
+            &self.uniform_buffer,
+            0,
+            bytemuck::cast_slice(&[self.uniforms]),
+        );
+        // Directed lights' shadows follow the camera around
+        let mut shadows = ShadowUniforms::new();
+        for l in self.lights.iter().filter(|l| l.shadow[0] != 0.0) {
+            let layer = l.shadow[3] as usize;
+            let view_proj = l
+                .shadow_view_proj(self.camera.target, self.shadow_extent)
+                .into();
+            shadows.view_proj[layer] = view_proj;
+            self.queue.write_buffer(
+                &self.shadow_layers[layer].1,
+                0,
+                bytemuck::cast_slice(&[view_proj]),
+            );
+        }
         self.queue
-            .write_buffer(&self.light_buffer, 0, bytemuck::cast_slice(&self.lights));
+            .write_buffer(&self.shadow_buffer, 0, bytemuck::cast_slice(&[shadows]));
+
+        // Which lights reach which clusters depends on the camera, so this
+        // is redone every frame
+        self.clusters.assign(
+            &self.lights,
+            &self.camera,
+            self.size.width,
+            self.size.height,
+        );
+        let grew_lights = self.light_buffer.write(
+            &self.device,
+            &self.queue,
+            bytemuck::cast_slice(&self.lights),
+        );
+        let grew_indices = self.light_index_buffer.write(
+            &self.device,
+            &self.queue,
+            bytemuck::cast_slice(&self.clusters.indices),
+        );
+        self.queue.write_buffer(
+            &self.cluster_buffer,
+            0,
+            bytemuck::cast_slice(&self.clusters.ranges),
+        );
+        self.queue.write_buffer(
+            &self.cluster_uniform_buffer,
+            0,
+            bytemuck::cast_slice(&[self.clusters.uniforms]),
+        );
+        if grew_lights || grew_indices {
+            self.recreate_light_bind_group();
+        }
+    }
+
+    fn recreate_light_bind_group(&mut self) {
+        self.light_bind_group = Self::create_light_bind_group(
+            &self.device,
+            &self.light_bind_group_layout,
+            [
+                &self.light_buffer.buffer,
+                &self.light_ambient_buffer,
+                &self.cluster_uniform_buffer,
+                &self.cluster_buffer,
+                &self.light_index_buffer.buffer,
+            ],
+            &self.environment,
+        );
+    }
+
+    /// Light PBR materials' surroundings with a cube map made from six
+    /// images, in the order +x, -x, +y, -y, +z, -z.  .hdr files keep their
+    /// full range.
+    pub(crate) fn load_environment<P: AsRef<std::path::Path>>(
+        &mut self,
+        faces: &[P],
+    ) -> anyhow::Result<()> {
+        let faces = faces
+            .iter()
+            .map(|p| {
+                texture::load_hdr(p)
+                    .with_context(|| format!("Couldn't load {}", p.as_ref().display()))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        self.environment = texture::Texture::hdr_cube_from_faces(
+            &self.device,
+            &self.queue,
+            &faces,
+            Some("environment"),
+        )?;
+        self.recreate_light_bind_group();
+        Ok(())
+    }
+
+    /// Like `load_environment`, from one equirectangular panorama
+    pub(crate) fn load_environment_equirect(
+        &mut self,
+        path: impl AsRef<std::path::Path>,
+    ) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let img =
+            texture::load_hdr(path).with_context(|| format!("Couldn't load {}", path.display()))?;
+        self.environment = texture::Texture::cube_from_equirect(
+            &self.device,
+            &self.queue,
+            &img,
+            Some("environment"),
+        )?;
+        self.recreate_light_bind_group();
+        Ok(())
+    }
+
+    /// Replace the environment with a gradient from `ground` below to
+    /// `horizon` to `sky` above, in sRGB
+    pub(crate) fn set_sky_gradient(&mut self, sky: [u8; 3], horizon: [u8; 3], ground: [u8; 3]) {
+        self.environment =
+            texture::Texture::gradient_cube(&self.device, &self.queue, sky, horizon, ground);
+        self.recreate_light_bind_group();
+    }
+
+    pub(crate) fn culling_stats(&self) -> CullingStats {
+        self.culling_stats
+    }
+
+    pub(crate) fn background(&self) -> Background {
+        self.background
+    }
+
+    pub(crate) fn set_background(&mut self, background: Background) {
+        self.background = background;
     }
 
     pub(crate) fn update_buffers<R, G: Game<StaticData = R>>(
@@ -310,28 +1436,126 @@ impl Render {
         assets: &mut Assets,
         pixels: &mut (Pixels, PhysicalSize<u32>),
     ) -> bool {
-        self.uniforms.update_view_proj(&self.camera);
-        self.queue.write_buffer(
-            &self.uniform_buffer,
-            0,
-            bytemuck::cast_slice(&[self.uniforms]),
-        );
+        self.write_uniforms();
         self.instance_groups.clear();
         let two_d = game.render(&mut self.instance_groups, pixels);
+        self.update_instances(assets);
+
+        two_d
+    }
+
+    fn update_instances(&mut self, assets: &Assets) {
+        let frustum = if self.settings.frustum_culling {
+            Some(self.camera.frustum())
+        } else {
+            None
+        };
+        self.culling_stats = self.instance_groups.cull(frustum.as_ref(), assets);
         self.instance_groups
             .update_buffers(&self.queue, &self.device, assets);
+        let grew = self.joint_buffer.write(
+            &self.device,
+            &self.queue,
+            bytemuck::cast_slice(&self.instance_groups.joints),
+        );
+        if grew {
+            self.shadow_bind_group = Self::create_shadow_bind_group(
+                &self.device,
+                &self.shadow_bind_group_layout,
+                &self.shadow_view,
+                &self.shadow_sampler,
+                &self.shadow_buffer,
+                &self.joint_buffer.buffer,
+            );
+            self.joint_bind_group = Self::create_joint_bind_group(
+                &self.device,
+                &self.joint_bind_group_layout,
+                &self.joint_buffer.buffer,
+            );
+        }
+        self.sort_transparent(assets);
+    }
 
-        two_d
+    // Gather every transparent mesh of every instance and order them far to
+    // near, going by where each instance is
+    fn sort_transparent(&mut self, assets: &Assets) {
+        let eye = self.camera.eye;
+        let groups = &self.instance_groups;
+        let mut draws = std::mem::take(&mut self.transparent);
+        draws.clear();
+        let mut add = |mr: ModelRef, ir: &InstanceRaw, instance: usize, skinned: bool| {
+            let model = assets.get_model(mr).unwrap();
+            let [x, y, z, _] = ir.model[3];
+            let distance = (Pos3::new(x, y, z) - eye).magnitude2();
+            for (mesh, m) in model.meshes.iter().enumerate() {
+                if model.materials[m.material].params.transparent {
+                    draws.push(TransparentDraw {
+                        model: mr,
+                        mesh,
+                        instance: instance as u32,
+                        skinned,
+                        distance,
+                    });
+                }
+            }
+        };
+        let has_transparent = |mr: ModelRef| {
+            let model = assets.get_model(mr).unwrap();
+            model.materials.iter().any(|m| m.params.transparent)
+        };
+        for (mr, (irs, _buf, _cap, visible)) in groups.groups.iter() {
+            if has_transparent(*mr) {
+                for (i, ir) in irs[..*visible].iter().enumerate() {
+                    add(*mr, ir, i, false);
+                }
+            }
+        }
+        for (i, (mr, ir)) in groups
+            .skinned
+            .iter()
+            .zip(groups.skinned_instances.iter())
+            .enumerate()
+        {
+            if has_transparent(*mr) {
+                add(*mr, ir, i, true);
+            }
+        }
+        draws.sort_by(|a, b| {
+            b.distance
+                .partial_cmp(&a.distance)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        self.transparent = draws;
     }
 
     pub(crate) fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         self.size = new_size;
-        self.sc_desc.width = new_size.width;
-        self.sc_desc.height = new_size.height;
-        self.camera.aspect = self.sc_desc.width as f32 / self.sc_desc.height as f32;
-        self.swap_chain = self.device.create_swap_chain(&self.surface, &self.sc_desc);
-        self.depth_texture =
-            texture::Texture::create_depth_texture(&self.device, &self.sc_desc, "depth_texture");
+        self.camera.aspect = new_size.width as f32 / new_size.height as f32;
+        match &mut self.target {
+            Target::Window {
+                surface,
+                sc_desc,
+                swap_chain,
+            } => {
+                sc_desc.width = new_size.width;
+                sc_desc.height = new_size.height;
+                *swap_chain = self.device.create_swap_chain(surface, sc_desc);
+            }
+            Target::Offscreen { texture, view } => {
+                let (t, v) = Self::create_offscreen(
+                    &self.device,
+                    new_size.width,
+                    new_size.height,
+                    self.format,
+                );
+                *texture = t;
+                *view = v;
+            }
+        }
+        self.capture_target = None;
+        self.create_attachments();
+        self.post
+            .resize(&self.device, (new_size.width, new_size.height));
     }
 
     pub(crate) fn render<R, G: Game<StaticData = R>>(
@@ -340,83 +1564,485 @@ impl Render {
         rules: &R,
         assets: &mut Assets,
         pixels: &mut (Pixels, PhysicalSize<u32>),
-    ) -> Result<(), wgpu::SwapChainError> {
+        debug: &DebugDraw,
+        capture: bool,
+    ) -> Result<Option<image::RgbaImage>, wgpu::SwapChainError> {
         let two_d = self.update_buffers(game, rules, assets, pixels);
+        self.update_debug(debug);
+        if two_d {
+            if !capture {
+                return Ok(None);
+            }
+            let (w, h) = (pixels.1.width, pixels.1.height);
+            return Ok(image::RgbaImage::from_raw(
+                w,
+                h,
+                pixels.0.get_frame().to_vec(),
+            ));
+        }
+        self.draw_to_target(assets)?;
+        if !capture {
+            return Ok(None);
+        }
+        let captured = match &self.target {
+            Target::Offscreen { texture, .. } => self.read_texture(texture),
+            Target::Window { .. } => {
+                if self.capture_target.is_none() {
+                    self.capture_target = Some(Self::create_offscreen(
+                        &self.device,
+                        self.size.width,
+                        self.size.height,
+                        self.format,
+                    ));
+                }
+                let (texture, view) = self.capture_target.as_ref().unwrap();
+                self.draw(view, assets);
+                self.read_texture(texture)
+            }
+        };
+        match captured {
+            Ok(img) => Ok(Some(img)),
+            Err(e) => {
+                eprintln!("Couldn't capture frame: {:?}", e);
+                Ok(None)
+            }
+        }
+    }
 
-        match two_d {
-            false => {
-                let frame = self.swap_chain.get_current_frame()?.output;
-
-                let mut encoder =
-                    self.device
-                        .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                            label: Some("Render Encoder"),
-                        });
-
-                {
-                    let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                        label: Some("Render Pass"),
-                        color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                            attachment: &frame.view,
-                            resolve_target: None,
-                            ops: wgpu::Operations {
-                                load: wgpu::LoadOp::Clear(wgpu::Color {
-                                    r: 0.1,
-                                    g: 0.2,
-                                    b: 0.3,
-                                    a: 1.0,
-                                }),
-                                store: true,
-                            },
-                        }],
-                        depth_stencil_attachment: Some(
-                            wgpu::RenderPassDepthStencilAttachmentDescriptor {
-                                attachment: &self.depth_texture.view,
-                                depth_ops: Some(wgpu::Operations {
-                                    load: wgpu::LoadOp::Clear(1.0),
-                                    store: true,
-                                }),
-                                stencil_ops: None,
-                            },
-                        ),
-                    });
+    /// Like `render`, but the instances come from `f` instead of a `Game`
+    pub(crate) fn render_instances(
+        &mut self,
+        assets: &mut Assets,
+        debug: &DebugDraw,
+        f: impl FnOnce(&mut InstanceGroups),
+    ) -> Result<(), wgpu::SwapChainError> {
+        self.write_uniforms();
+        self.instance_groups.clear();
+        f(&mut self.instance_groups);
+        self.update_instances(assets);
+        self.update_debug(debug);
+        self.draw_to_target(assets)
+    }
 
-                    render_pass.set_pipeline(&self.render_pipeline);
-                    for (mr, (irs, buf, _cap)) in self.instance_groups.groups.iter() {
-                        render_pass.set_vertex_buffer(1, buf.as_ref().unwrap().slice(..));
-                        render_pass.draw_model_instanced(
-                            assets.get_model(*mr).unwrap(),
-                            0..irs.len() as u32,
-                            &self.uniform_bind_group,
-                            &self.light_bind_group,
-                        );
-                    }
-                }
+    fn update_debug(&mut self, debug: &DebugDraw) {
+        let forward = (self.camera.target - self.camera.eye).normalize();
+        let right = forward.cross(self.camera.up).normalize();
+        let up: Vec3 = right.cross(forward);
+        let vertices = debug.vertices(right, up);
+        self.debug_buffer
+            .write(&self.device, &self.queue, bytemuck::cast_slice(&vertices));
+        self.debug_vertex_count = vertices.len() as u32;
+    }
 
-                self.queue.submit(std::iter::once(encoder.finish()));
+    fn draw_to_target(&self, assets: &Assets) -> Result<(), wgpu::SwapChainError> {
+        match &self.target {
+            Target::Window { swap_chain, .. } => {
+                // Has to stay alive until the frame is submitted
+                let frame = swap_chain.get_current_frame()?.output;
+                self.draw(&frame.view, assets);
             }
-            true => {}
+            Target::Offscreen { view, .. } => self.draw(view, assets),
         }
         Ok(())
     }
+
+    fn draw(&self, view: &wgpu::TextureView, assets: &Assets) {
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Render Encoder"),
+            });
+
+        for (view, _buf, bind_group) in self.shadow_layers.iter().take(self.shadow_count) {
+            let mut shadow_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Shadow Pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                    attachment: view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            shadow_pass.set_pipeline(&self.shadow_pipeline);
+            shadow_pass.set_bind_group(0, bind_group, &[]);
+            shadow_pass.set_bind_group(1, &self.joint_bind_group, &[0]);
+            // Culled instances still cast shadows into view, so these draw
+            // everything
+            for (mr, (irs, buf, _cap, _visible)) in self.instance_groups.groups.iter() {
+                if irs.is_empty() || !self.instance_groups.casts_shadows(*mr) {
+                    continue;
+                }
+                shadow_pass.set_vertex_buffer(1, buf.as_ref().unwrap().slice(..));
+                for mesh in assets.get_model(*mr).unwrap().meshes.iter() {
+                    shadow_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                    shadow_pass
+                        .set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                    shadow_pass.draw_indexed(0..mesh.num_elements, 0, 0..irs.len() as u32);
+                }
+            }
+            if let Some(buf) = &self.instance_groups.skinned_buffer {
+                shadow_pass.set_vertex_buffer(1, buf.slice(..));
+            }
+            for (i, mr) in self.instance_groups.skinned.iter().enumerate() {
+                if !self.instance_groups.casts_shadows(*mr) {
+                    continue;
+                }
+                shadow_pass.set_bind_group(1, &self.joint_bind_group, &[joint_offset(i)]);
+                for mesh in assets.get_model(*mr).unwrap().meshes.iter() {
+                    shadow_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                    shadow_pass
+                        .set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                    shadow_pass.draw_indexed(0..mesh.num_elements, 0, i as u32..i as u32 + 1);
+                }
+            }
+        }
+
+        let scene = self.post.scene_view();
+        // The skybox covers everything, so the clear colour only matters
+        // for a flat background
+        let clear = match self.background {
+            Background::Color([r, g, b]) => wgpu::Color {
+                r: r as f64,
+                g: g as f64,
+                b: b as f64,
+                a: 1.0,
+            },
+            Background::Skybox => wgpu::Color::BLACK,
+        };
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Render Pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: self.msaa_target.as_ref().unwrap_or(scene),
+                    resolve_target: self.msaa_target.as_ref().map(|_| scene),
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(clear),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                    attachment: &self.depth_texture.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+
+            render_pass.set_pipeline(&self.render_pipeline);
+            render_pass.set_bind_group(3, &self.shadow_bind_group, &[0]);
+            for (mr, (_irs, buf, _cap, visible)) in self.instance_groups.groups.iter() {
+                if *visible == 0 {
+                    continue;
+                }
+                render_pass.set_vertex_buffer(1, buf.as_ref().unwrap().slice(..));
+                render_pass.draw_model_instanced(
+                    assets.get_model(*mr).unwrap(),
+                    0..*visible as u32,
+                    &self.uniform_bind_group,
+                    &self.light_bind_group,
+                );
+            }
+            // Skinned instances go one at a time, each with its own joints
+            if let Some(buf) = &self.instance_groups.skinned_buffer {
+                render_pass.set_vertex_buffer(1, buf.slice(..));
+            }
+            for (i, mr) in self.instance_groups.skinned.iter().enumerate() {
+                render_pass.set_bind_group(3, &self.shadow_bind_group, &[joint_offset(i)]);
+                render_pass.draw_model_instanced(
+                    assets.get_model(*mr).unwrap(),
+                    i as u32..i as u32 + 1,
+                    &self.uniform_bind_group,
+                    &self.light_bind_group,
+                );
+            }
+            if let Background::Skybox = self.background {
+                render_pass.set_pipeline(&self.sky_pipeline);
+                render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+                render_pass.set_bind_group(1, &self.light_bind_group, &[]);
+                render_pass.draw(0..3, 0..1);
+            }
+            // Last, so whatever's behind is already there to blend over
+            render_pass.set_pipeline(&self.transparent_pipeline);
+            for draw in self.transparent.iter() {
+                let model = assets.get_model(draw.model).unwrap();
+                let mesh = &model.meshes[draw.mesh];
+                let (instances, offset) = if draw.skinned {
+                    (
+                        self.instance_groups.skinned_buffer.as_ref().unwrap(),
+                        joint_offset(draw.instance as usize),
+                    )
+                } else {
+                    (
+                        self.instance_groups.groups[&draw.model].1.as_ref().unwrap(),
+                        0,
+                    )
+                };
+                render_pass.set_vertex_buffer(1, instances.slice(..));
+                render_pass.set_bind_group(3, &self.shadow_bind_group, &[offset]);
+                render_pass.draw_mesh_instanced(
+                    mesh,
+                    &model.materials[mesh.material],
+                    draw.instance..draw.instance + 1,
+                    &self.uniform_bind_group,
+                    &self.light_bind_group,
+                );
+            }
+        }
+        self.post.run(&mut encoder, view);
+
+        if self.debug_vertex_count > 0 {
+            let mut debug_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Debug Pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            debug_pass.set_pipeline(&self.debug_pipeline);
+            debug_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+            debug_pass.set_vertex_buffer(0, self.debug_buffer.buffer.slice(..));
+            debug_pass.draw(0..self.debug_vertex_count, 0..1);
+        }
+
+        self.queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    /// Copy the last frame back from the GPU.  Only works for a headless
+    /// `Render`, since swap chain frames can't be read.
+    pub(crate) fn read_pixels(&self) -> anyhow::Result<image::RgbaImage> {
+        match &self.target {
+            Target::Offscreen { texture, .. } => self.read_texture(texture),
+            Target::Window { .. } => bail!("Can only read pixels back from a headless Render"),
+        }
+    }
+
+    // Read back a texture the size of the frame, in `self.format`
+    fn read_texture(&self, texture: &wgpu::Texture) -> anyhow::Result<image::RgbaImage> {
+        let (width, height) = (self.size.width, self.size.height);
+        // Rows in the buffer have to be padded out to a multiple of 256 bytes
+        let unpadded = 4 * width;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded = unpadded + (align - unpadded % align) % align;
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback_buffer"),
+            size: (padded * height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Readback Encoder"),
+            });
+        encoder.copy_texture_to_buffer(
+            wgpu::TextureCopyView {
+                texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::BufferCopyView {
+                buffer: &buffer,
+                layout: wgpu::TextureDataLayout {
+                    offset: 0,
+                    bytes_per_row: padded,
+                    rows_per_image: height,
+                },
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth: 1,
+            },
+        );
+        self.queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let mapping = slice.map_async(wgpu::MapMode::Read);
+        self.device.poll(wgpu::Maintain::Wait);
+        futures::executor::block_on(mapping)?;
+        let data = slice.get_mapped_range();
+        let mut pixels = Vec::with_capacity((unpadded * height) as usize);
+        for row in data.chunks(padded as usize) {
+            pixels.extend_from_slice(&row[..unpadded as usize]);
+        }
+        drop(data);
+        buffer.unmap();
+        // Windows usually want BGRA
+        if let wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb = self.format {
+            for px in pixels.chunks_mut(4) {
+                px.swap(0, 2);
+            }
+        }
+        image::RgbaImage::from_raw(width, height, pixels).context("Readback was the wrong size")
+    }
+}
+
+// One mesh of one instance, for the transparent pass
+struct TransparentDraw {
+    model: ModelRef,
+    mesh: usize,
+    // Into the model's instances, or the skinned ones
+    instance: u32,
+    skinned: bool,
+    // Squared, from the camera
+    distance: f32,
+}
+
+// Where the `i`th skinned instance's joints start; slot 0 is for everything
+// unskinned
+fn joint_offset(i: usize) -> wgpu::DynamicOffset {
+    ((i as wgpu::BufferAddress + 1) * JOINT_SLOT) as wgpu::DynamicOffset
+}
+
+// A storage buffer that gets remade bigger when it runs out of room
+struct GrowableBuffer {
+    buffer: wgpu::Buffer,
+    capacity: wgpu::BufferAddress,
+    label: &'static str,
+    usage: wgpu::BufferUsage,
+}
+
+impl GrowableBuffer {
+    fn new(device: &wgpu::Device, label: &'static str, capacity: wgpu::BufferAddress) -> Self {
+        Self::with_usage(
+            device,
+            label,
+            capacity,
+            wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+        )
+    }
+
+    fn with_usage(
+        device: &wgpu::Device,
+        label: &'static str,
+        capacity: wgpu::BufferAddress,
+        usage: wgpu::BufferUsage,
+    ) -> Self {
+        Self {
+            buffer: Self::create(device, label, capacity, usage),
+            capacity,
+            label,
+            usage,
+        }
+    }
+
+    fn create(
+        device: &wgpu::Device,
+        label: &'static str,
+        size: wgpu::BufferAddress,
+        usage: wgpu::BufferUsage,
+    ) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size,
+            usage,
+            mapped_at_creation: false,
+        })
+    }
+
+    // True if the buffer had to be remade, in which case any bind groups
+    // using it need remaking too
+    fn write(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, data: &[u8]) -> bool {
+        let len = data.len() as wgpu::BufferAddress;
+        let grew = len > self.capacity;
+        if grew {
+            self.capacity = len.next_power_of_two();
+            self.buffer = Self::create(device, self.label, self.capacity, self.usage);
+        }
+        if len > 0 {
+            queue.write_buffer(&self.buffer, 0, data);
+        }
+        grew
+    }
 }
 
 pub struct InstanceGroups {
-    groups: BTreeMap<ModelRef, (Vec<InstanceRaw>, Option<wgpu::Buffer>, usize)>,
+    // Instances, their buffer, its capacity, and how many instances at the
+    // front of it survived culling
+    groups: BTreeMap<ModelRef, (Vec<InstanceRaw>, Option<wgpu::Buffer>, usize, usize)>,
+    // Models left out of shadow passes.  Unlike the instances, this isn't
+    // cleared between frames.
+    no_shadows: BTreeSet<ModelRef>,
+    // Skinned instances can't be batched, since each has its own joints
+    skinned: Vec<ModelRef>,
+    skinned_instances: Vec<InstanceRaw>,
+    skinned_buffer: Option<wgpu::Buffer>,
+    skinned_cap: usize,
+    // MAX_JOINTS matrices per skinned instance, after a slot of identities
+    // so skinned models drawn with `render` just show their bind pose
+    joints: Vec<[[f32; 4]; 4]>,
 }
 impl InstanceGroups {
     fn new() -> Self {
         Self {
             groups: BTreeMap::new(),
+            no_shadows: BTreeSet::new(),
+            skinned: vec![],
+            skinned_instances: vec![],
+            skinned_buffer: None,
+            skinned_cap: 0,
+            joints: vec![Mat4::identity().into(); MAX_JOINTS],
+        }
+    }
+    /// Whether instances of `mr` cast shadows (they do by default).  Stays
+    /// set until it's changed again.
+    pub fn set_casts_shadows(&mut self, mr: ModelRef, casts: bool) {
+        if casts {
+            self.no_shadows.remove(&mr);
+        } else {
+            self.no_shadows.insert(mr);
         }
     }
+    pub fn casts_shadows(&self, mr: ModelRef) -> bool {
+        !self.no_shadows.contains(&mr)
+    }
     fn clear(&mut self) {
-        for (_mr, (irs, _buf, _cap)) in self.groups.iter_mut() {
+        for (_mr, (irs, _buf, _cap, visible)) in self.groups.iter_mut() {
             irs.clear();
+            *visible = 0;
         }
+        self.skinned.clear();
+        self.skinned_instances.clear();
+        self.joints.truncate(MAX_JOINTS);
+    }
+    // Move each group's instances that might be on screen to the front, so
+    // the main pass can draw just those
+    fn cull(&mut self, frustum: Option<&Frustum>, assets: &Assets) -> CullingStats {
+        let mut stats = CullingStats {
+            instances: self.skinned_instances.len(),
+            visible: self.skinned_instances.len(),
+            culled: 0,
+        };
+        for (mr, (irs, _buf, _cap, visible)) in self.groups.iter_mut() {
+            stats.instances += irs.len();
+            *visible = irs.len();
+            if let Some(frustum) = frustum {
+                let sphere = assets.get_model(*mr).unwrap().bounding_sphere;
+                *visible = 0;
+                for i in 0..irs.len() {
+                    if frustum.touching_sphere(&instance_sphere(&irs[i], sphere)) {
+                        irs.swap(i, *visible);
+                        *visible += 1;
+                    }
+                }
+            }
+            stats.visible += *visible;
+        }
+        stats.culled = stats.instances - stats.visible;
+        stats
     }
     fn update_buffers(&mut self, queue: &wgpu::Queue, device: &wgpu::Device, assets: &Assets) {
-        for (mr, (irs, buf, cap)) in self.groups.iter_mut() {
+        for (mr, (irs, buf, cap, _visible)) in self.groups.iter_mut() {
             if buf.is_none() || *cap < irs.len() {
                 buf.replace(
                     device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -430,6 +2056,25 @@ impl InstanceGroups {
                 queue.write_buffer(buf.as_ref().unwrap(), 0, bytemuck::cast_slice(irs));
             }
         }
+        if self.skinned_instances.is_empty() {
+            return;
+        }
+        let data = bytemuck::cast_slice(&self.skinned_instances);
+        match &self.skinned_buffer {
+            Some(buf) if self.skinned_cap >= self.skinned_instances.len() => {
+                queue.write_buffer(buf, 0, data);
+            }
+            _ => {
+                self.skinned_buffer = Some(device.create_buffer_init(
+                    &wgpu::util::BufferInitDescriptor {
+                        label: Some("Skinned instances"),
+                        usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+                        contents: data,
+                    },
+                ));
+                self.skinned_cap = self.skinned_instances.len();
+            }
+        }
     }
     pub fn render(&mut self, mr: ModelRef, ir: InstanceRaw) {
         self.render_batch(mr, std::iter::once(ir));
@@ -438,20 +2083,94 @@ impl InstanceGroups {
         let ref mut groups = self.groups;
         groups
             .entry(mr)
-            .or_insert((vec![], None, 0))
+            .or_insert((vec![], None, 0, 0))
             .0
             .extend(ir.into_iter())
     }
+    /// Draw one instance of a skinned model, posed by `joints` (see
+    /// `animation::Pose::joint_matrices`).  Only MAX_JOINTS joints fit;
+    /// `Model::load_gltf` won't load skins with more.
+    pub fn render_skinned(&mut self, mr: ModelRef, ir: InstanceRaw, joints: &[Mat4]) {
+        self.skinned.push(mr);
+        self.skinned_instances.push(ir);
+        let start = self.joints.len();
+        self.joints.extend(
+            joints
+                .iter()
+                .take(MAX_JOINTS)
+                .map(|&m| -> [[f32; 4]; 4] { m.into() }),
+        );
+        self.joints
+            .resize(start + MAX_JOINTS, Mat4::identity().into());
+    }
+}
+
+// A model-space bounding sphere moved into place by an instance's
+// transform, growing to cover its largest scale
+fn instance_sphere(ir: &InstanceRaw, s: Sphere) -> Sphere {
+    let m = Mat4::from(ir.model);
+    let scale =
+        m.x.truncate()
+            .magnitude()
+            .max(m.y.truncate().magnitude())
+            .max(m.z.truncate().magnitude());
+    Sphere {
+        c: m.transform_point(s.c),
+        r: s.r * scale,
+    }
 }
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct InstanceRaw {
-    #[allow(dead_code)]
     pub model: [[f32; 4]; 4],
+    // Multiplies the material's diffuse color and alpha.  Alpha only blends
+    // for transparent materials.
+    pub tint: [f32; 4],
+    // Glow added on top in rgb, then how much of the material's own
+    // emission to keep
+    pub emissive: [f32; 4],
+    // Texture coordinates are scaled by zw, then offset by xy
+    pub uv: [f32; 4],
 }
 
 impl InstanceRaw {
+    /// Just a transform, drawn the way the material says
+    pub fn new(model: Mat4) -> Self {
+        Self {
+            model: model.into(),
+            tint: [1.0, 1.0, 1.0, 1.0],
+            emissive: [0.0, 0.0, 0.0, 1.0],
+            uv: [0.0, 0.0, 1.0, 1.0],
+        }
+    }
+    pub fn with_tint(self, rgba: [f32; 4]) -> Self {
+        Self { tint: rgba, ..self }
+    }
+    /// Make it glow this color, on top of whatever the material emits
+    pub fn with_emissive(self, rgb: [f32; 3]) -> Self {
+        let [r, g, b] = rgb;
+        Self {
+            emissive: [r, g, b, self.emissive[3]],
+            ..self
+        }
+    }
+    /// Scale the material's own emission, e.g. to 0 to switch a light off
+    pub fn with_emissive_strength(self, strength: f32) -> Self {
+        let [r, g, b, _] = self.emissive;
+        Self {
+            emissive: [r, g, b, strength],
+            ..self
+        }
+    }
+    /// Shift and stretch the textures, e.g. to scroll them or to pick one
+    /// frame out of a sprite sheet
+    pub fn with_uv(self, offset: [f32; 2], scale: [f32; 2]) -> Self {
+        Self {
+            uv: [offset[0], offset[1], scale[0], scale[1]],
+            ..self
+        }
+    }
     fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         use std::mem;
         wgpu::VertexBufferLayout {
@@ -485,6 +2204,22 @@ impl InstanceRaw {
                     shader_location: 8,
                     format: wgpu::VertexFormat::Float4,
                 },
+                // 9 and 10 are the vertices' joints and weights
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
+                    shader_location: 11,
+                    format: wgpu::VertexFormat::Float4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 20]>() as wgpu::BufferAddress,
+                    shader_location: 12,
+                    format: wgpu::VertexFormat::Float4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 24]>() as wgpu::BufferAddress,
+                    shader_location: 13,
+                    format: wgpu::VertexFormat::Float4,
+                },
             ],
         }
     }
@@ -523,6 +2258,20 @@ impl Uniforms {
     }
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ShadowUniforms {
+    view_proj: [[[f32; 4]; 4]; MAX_SHADOWS],
+}
+
+impl ShadowUniforms {
+    fn new() -> Self {
+        Self {
+            view_proj: [cgmath::Matrix4::identity().into(); MAX_SHADOWS],
+        }
+    }
+}
+
 //Types
 #[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
 pub struct Rect {
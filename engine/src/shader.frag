@@ -3,11 +3,35 @@
 layout(location=0) in vec2 v_tex_coords;
 layout(location=1) in vec3 v_normal;
 layout(location=2) in vec3 v_position;
+layout(location=3) in vec3 v_tangent;
+layout(location=4) in vec3 v_bitangent;
+// Per instance: multiplies the diffuse color, and rgb glow plus how much of
+// the material's own emission to keep
+layout(location=5) in vec4 v_tint;
+layout(location=6) in vec4 v_emissive;
 
 layout(location=0) out vec4 f_color;
 
 layout(set = 0, binding = 0) uniform texture2D t_diffuse;
 layout(set = 0, binding = 1) uniform sampler s_diffuse;
+layout(set = 0, binding = 2) uniform texture2D t_normal;
+layout(set = 0, binding = 3) uniform sampler s_normal;
+layout(set = 0, binding = 4) uniform texture2D t_specular;
+layout(set = 0, binding = 5) uniform sampler s_specular;
+layout(set = 0, binding = 6) uniform texture2D t_emissive;
+layout(set = 0, binding = 7) uniform sampler s_emissive;
+// From the MTL file; see MaterialParams
+layout(set = 0, binding = 8)
+uniform Material {
+    vec4 m_ambient;
+    // rgb, dissolve
+    vec4 m_diffuse;
+    // rgb, shininess
+    vec4 m_specular;
+    vec4 m_emissive;
+    // metallic, roughness, 1 for PBR shading, 1 if transparent
+    vec4 m_pbr;
+};
 layout(set=1, binding=0)
 uniform Uniforms {
     vec3 u_view_position; // unused
@@ -19,54 +43,270 @@ struct Light {
   vec4 pos;
   vec4 dir;
   vec4 color;
+  // casts shadows, depth bias, normal bias, shadow map layer
+  vec4 shadow;
+  // range, cos of inner cone angle, cos of outer cone angle, intensity
+  vec4 falloff;
 };
 
 layout(set=2, binding=0)
-uniform Lights {
-    Light lights[10];
+readonly buffer Lights {
+    Light lights[];
 };
 layout(set=2, binding=1)
 uniform LightsAmbient {
     float ambient;
 };
+// See clusters.rs
+layout(set=2, binding=2)
+uniform Clusters {
+    // clusters across, down, deep, and how many directed lights there are
+    uvec4 u_cluster_dims;
+    // near, far, log(far / near)
+    vec4 u_cluster_depth;
+    // width and height in pixels
+    vec4 u_screen_size;
+};
+// Offset into light_indices and count, per cluster
+layout(set=2, binding=3)
+readonly buffer ClusterLights {
+    uvec2 cluster_lights[];
+};
+// Directed lights first, then each cluster's lights
+layout(set=2, binding=4)
+readonly buffer LightIndices {
+    uint light_indices[];
+};
 
+// What PBR materials reflect, and their ambient light
+layout(set=2, binding=5) uniform textureCube t_environment;
+layout(set=2, binding=6) uniform sampler s_environment;
 
-void main() {
+layout(set=3, binding=0) uniform texture2DArray t_shadow;
+layout(set=3, binding=1) uniform samplerShadow s_shadow;
+layout(set=3, binding=2)
+uniform Shadows {
+    mat4 u_shadow_view_proj[4];
+};
+
+// 1 if fully lit by light i, 0 if fully in its shadow
+float shadow_factor(uint i) {
+  // The bias goes along the real surface normal, not the normal mapped one
   vec3 normal = normalize(v_normal);
-  vec4 object_color = texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords);
-  vec3 view_dir = normalize(u_view_position - v_position);
-  float bug = 0.0;
-  vec3 result = ambient*object_color.xyz;
-  for (int i = 0; i < 10; i++) {
-    float light_ambient = 0.1;
-    // Point-light specific; change if directional lights, spotlights are used
-    // to branch on e.g. position.w == 0 (directional) or direction.w == 0 (point) or else spot
-    if(lights[i].dir.w == 0) {
-        vec3 light_color = lights[i].color.xyz;
-        vec3 light_position = lights[i].pos.xyz;
-        vec3 light_dir = normalize(light_position - v_position);
-        float diffuse_strength = max(dot(normal, light_dir), 0.0);
-        vec3 diffuse_color = light_color * diffuse_strength;
-        vec3 ambient_color = light_color * light_ambient;
-        vec3 half_dir = normalize(view_dir + light_dir);
-        float specular_strength = pow(max(dot(normal, half_dir), 0.0), 32);
-        vec3 specular_color = specular_strength * light_color;
-        result += (ambient_color + diffuse_color + specular_color) * object_color.xyz;
-    } else {
-        vec3 light_color = lights[i].color.xyz;
-        vec3 light_position = lights[i].pos.xyz;
-        vec3 light_dir = lights[i].dir.xyz;
-        float diffuse_strength = max(dot(normal, light_dir), 0.0);
-        //float diffuse_strength = 0.05;
-        vec3 diffuse_color = light_color * diffuse_strength;
-        vec3 ambient_color = light_color * light_ambient;
-        vec3 half_dir = normalize(view_dir + light_dir);
-        float specular_strength = pow(max(dot(normal, half_dir), 0.0), 32);
-        vec3 specular_color = specular_strength * light_color;
-        result += (ambient_color + diffuse_color + specular_color) * object_color.xyz;
+  vec4 shadow = lights[i].shadow;
+  if (shadow.x == 0.0) {
+    return 1.0;
+  }
+  int layer = int(shadow.w);
+  vec4 clip = u_shadow_view_proj[layer] * vec4(v_position + normal * shadow.z, 1.0);
+  if (clip.w <= 0.0) {
+    return 1.0;
+  }
+  vec3 ndc = clip.xyz / clip.w;
+  // Anything the shadow map doesn't cover is lit
+  if (ndc.z > 1.0 || abs(ndc.x) > 1.0 || abs(ndc.y) > 1.0) {
+    return 1.0;
+  }
+  vec2 uv = ndc.xy * vec2(0.5, -0.5) + 0.5;
+  float depth = ndc.z - shadow.y;
+  // 3x3 PCF; each tap is also bilinearly filtered by the comparison sampler
+  vec2 texel = 1.0 / vec2(textureSize(sampler2DArrayShadow(t_shadow, s_shadow), 0).xy);
+  float lit = 0.0;
+  for (int x = -1; x <= 1; x++) {
+    for (int y = -1; y <= 1; y++) {
+      vec2 offset = vec2(x, y) * texel;
+      lit += texture(sampler2DArrayShadow(t_shadow, s_shadow), vec4(uv + offset, layer, depth));
     }
   }
-  if(object_color.a < 0.1) {
+  return lit / 9.0;
+}
+
+// Inverse square, windowed so it reaches exactly 0 at `range` instead of
+// going on forever.  The +1 keeps it finite right next to the light.
+float range_attenuation(float dist, float range) {
+  float window = clamp(1.0 - pow(dist / range, 4.0), 0.0, 1.0);
+  return window * window / (dist * dist + 1.0);
+}
+
+// Which cluster this fragment falls in
+uint cluster_index() {
+  uvec3 dims = u_cluster_dims.xyz;
+  uint x = min(uint(gl_FragCoord.x / u_screen_size.x * dims.x), dims.x - 1);
+  uint y = min(uint(gl_FragCoord.y / u_screen_size.y * dims.y), dims.y - 1);
+  float depth = -(u_view * vec4(v_position, 1.0)).z;
+  float slice = log(max(depth, u_cluster_depth.x) / u_cluster_depth.x) / u_cluster_depth.z * dims.z;
+  uint z = min(uint(slice), dims.z - 1);
+  return (z * dims.y + y) * dims.x + x;
+}
+
+// Which way lights[i] is and how bright it is here, before shadows
+vec3 incoming(uint i, out vec3 light_dir) {
+  float attenuation = 1.0;
+  if (lights[i].pos.w == 0.0) {
+    // Directed, so no position or falloff
+    light_dir = normalize(lights[i].dir.xyz);
+  } else {
+    vec3 to_light = lights[i].pos.xyz - v_position;
+    float dist = length(to_light);
+    light_dir = to_light / dist;
+    attenuation = range_attenuation(dist, lights[i].falloff.x);
+    if (lights[i].dir.w != 0.0) {
+      // Spot: full strength inside the inner cone, fading to nothing at
+      // the outer one
+      float cos_angle = dot(light_dir, normalize(lights[i].dir.xyz));
+      attenuation *= smoothstep(lights[i].falloff.z, lights[i].falloff.y, cos_angle);
+    }
+  }
+  return lights[i].color.xyz * lights[i].falloff.w * attenuation;
+}
+
+// Everything the two lighting models need to know about the surface
+struct Surface {
+  vec3 normal;
+  vec3 view_dir;
+  vec3 albedo;
+  // Phong
+  vec3 specular;
+  float shininess;
+  // PBR
+  float metallic;
+  float roughness;
+  // How much is reflected head on
+  vec3 f0;
+};
+
+const float PI = 3.14159265;
+
+// Trowbridge-Reitz GGX: how many microfacets line up with the half vector
+float distribution_ggx(float n_dot_h, float roughness) {
+  float a = roughness * roughness;
+  float a2 = a * a;
+  float d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+  return a2 / (PI * d * d);
+}
+
+// Smith-Schlick: how many microfacets aren't hidden by others, going in
+// and coming out
+float geometry_smith(float n_dot_v, float n_dot_l, float roughness) {
+  float k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+  float gv = n_dot_v / (n_dot_v * (1.0 - k) + k);
+  float gl = n_dot_l / (n_dot_l * (1.0 - k) + k);
+  return gv * gl;
+}
+
+vec3 fresnel_schlick(float cos_theta, vec3 f0) {
+  return f0 + (1.0 - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// Diffuse and specular light reflected from lights[i]
+vec3 shade(uint i, Surface s, bool pbr) {
+  vec3 light_dir;
+  vec3 light_color = incoming(i, light_dir);
+  if (light_color == vec3(0.0)) {
+    return vec3(0.0);
+  }
+  vec3 half_dir = normalize(s.view_dir + light_dir);
+  float n_dot_l = max(dot(s.normal, light_dir), 0.0);
+  float n_dot_h = max(dot(s.normal, half_dir), 0.0);
+  vec3 reflected;
+  if (pbr) {
+    // Cook-Torrance
+    float n_dot_v = max(dot(s.normal, s.view_dir), 1e-4);
+    vec3 f = fresnel_schlick(max(dot(half_dir, s.view_dir), 0.0), s.f0);
+    float d = distribution_ggx(n_dot_h, s.roughness);
+    float g = geometry_smith(n_dot_v, n_dot_l, s.roughness);
+    vec3 specular = d * g * f / (4.0 * n_dot_v * n_dot_l + 1e-4);
+    // Metals don't have a diffuse part
+    vec3 kd = (1.0 - f) * (1.0 - s.metallic);
+    // Light colors are scaled for the Phong path, which has no 1/pi on
+    // diffuse, so scale by pi to make the two match
+    reflected = (kd * s.albedo + specular * PI) * light_color * n_dot_l;
+  } else {
+    vec3 diffuse_color = light_color * n_dot_l * s.albedo;
+    vec3 specular_color = pow(n_dot_h, s.shininess) * light_color * s.specular;
+    reflected = diffuse_color + specular_color;
+  }
+  return shadow_factor(i) * reflected;
+}
+
+// Karis' fit to the split sum environment BRDF, which saves baking a
+// lookup texture.  Gives a scale and bias for f0.
+vec2 environment_brdf(float roughness, float n_dot_v) {
+  const vec4 c0 = vec4(-1.0, -0.0275, -0.572, 0.022);
+  const vec4 c1 = vec4(1.0, 0.0425, 1.04, -0.04);
+  vec4 r = roughness * c0 + c1;
+  float a004 = min(r.x * r.x, exp2(-9.28 * n_dot_v)) * r.x + r.y;
+  return vec2(-1.04, 1.04) * a004 + r.zw;
+}
+
+// Ambient light for PBR materials, from the environment map.  Its
+// smallest mip stands in for diffuse irradiance, and rougher surfaces
+// reflect blurrier mips.
+vec3 environment_light(Surface s) {
+  float n_dot_v = max(dot(s.normal, s.view_dir), 0.0);
+  float top_mip = float(textureQueryLevels(samplerCube(t_environment, s_environment)) - 1);
+  vec3 irradiance = textureLod(samplerCube(t_environment, s_environment), s.normal, top_mip).rgb;
+  vec3 r = reflect(-s.view_dir, s.normal);
+  vec3 prefiltered = textureLod(samplerCube(t_environment, s_environment), r, s.roughness * top_mip).rgb;
+  vec2 brdf = environment_brdf(s.roughness, n_dot_v);
+  vec3 specular = prefiltered * (s.f0 * brdf.x + brdf.y);
+  vec3 kd = (1.0 - fresnel_schlick(n_dot_v, s.f0)) * (1.0 - s.metallic);
+  return kd * irradiance * s.albedo + specular;
+}
+
+// The vertex normal, bent by the normal map
+vec3 surface_normal() {
+  vec3 n = normalize(v_normal);
+  vec3 t = v_tangent - n * dot(n, v_tangent);
+  // Generated meshes may not have tangents
+  if (dot(t, t) < 1e-8) {
+    return n;
+  }
+  t = normalize(t);
+  vec3 b = cross(n, t);
+  // Mirrored texture coordinates flip the bitangent
+  if (dot(b, v_bitangent) < 0.0) {
+    b = -b;
+  }
+  vec3 tangent_normal = texture(sampler2D(t_normal, s_normal), v_tex_coords).xyz * 2.0 - 1.0;
+  return normalize(mat3(t, b, n) * tangent_normal);
+}
+
+void main() {
+  vec4 object_color = texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords) * m_diffuse * v_tint;
+  vec3 maps = texture(sampler2D(t_specular, s_specular), v_tex_coords).xyz;
+  bool pbr = m_pbr.z != 0.0;
+  Surface s;
+  s.normal = surface_normal();
+  s.view_dir = normalize(u_view_position - v_position);
+  s.albedo = object_color.xyz;
+  s.specular = maps * m_specular.xyz;
+  s.shininess = m_specular.w;
+  // PBR materials keep roughness in green and metallic in blue, like glTF
+  s.metallic = clamp(m_pbr.x * maps.b, 0.0, 1.0);
+  s.roughness = clamp(m_pbr.y * maps.g, 0.04, 1.0);
+  s.f0 = mix(vec3(0.04), s.albedo, s.metallic);
+
+  vec3 result;
+  if (pbr) {
+    result = ambient * m_ambient.xyz * environment_light(s);
+  } else {
+    result = ambient * m_ambient.xyz * s.albedo;
+  }
+  // Directed lights reach everywhere
+  for (uint k = 0; k < u_cluster_dims.w; k++) {
+    result += shade(light_indices[k], s, pbr);
+  }
+  uvec2 cluster = cluster_lights[cluster_index()];
+  for (uint k = 0; k < cluster.y; k++) {
+    result += shade(light_indices[cluster.x + k], s, pbr);
+  }
+  result += texture(sampler2D(t_emissive, s_emissive), v_tex_coords).xyz * m_emissive.xyz * v_emissive.w;
+  result += v_emissive.rgb;
+  // Opaque materials cut out nearly clear bits; transparent ones only
+  // skip what wouldn't show at all
+  float cutoff = m_pbr.w != 0.0 ? 0.004 : 0.1;
+  if(object_color.a < cutoff) {
     discard;
   }
   f_color = vec4(result, object_color.a);
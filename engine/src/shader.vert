@@ -3,15 +3,27 @@
 layout(location=0) in vec3 a_position;
 layout(location=1) in vec2 a_tex_coords;
 layout(location=2) in vec3 a_normal;
+layout(location=3) in vec3 a_tangent;
+layout(location=4) in vec3 a_bitangent;
+layout(location=9) in uvec4 a_joints;
+layout(location=10) in vec4 a_weights;
 
 layout(location=0) out vec2 v_tex_coords;
 layout(location=1) out vec3 v_normal;
 layout(location=2) out vec3 v_position;
+layout(location=3) out vec3 v_tangent;
+layout(location=4) out vec3 v_bitangent;
+layout(location=5) out vec4 v_tint;
+layout(location=6) out vec4 v_emissive;
 
 layout(location=5) in vec4 model_matrix_0;
 layout(location=6) in vec4 model_matrix_1;
 layout(location=7) in vec4 model_matrix_2;
 layout(location=8) in vec4 model_matrix_3;
+layout(location=11) in vec4 i_tint;
+layout(location=12) in vec4 i_emissive;
+// u and v offset, then u and v scale
+layout(location=13) in vec4 i_uv;
 
 layout(set=1, binding=0)
 uniform Uniforms {
@@ -20,6 +32,13 @@ uniform Uniforms {
     mat4 u_proj;
 };
 
+// Has to match MAX_JOINTS in animation.rs
+#define MAX_JOINTS 64
+layout(set=3, binding=3)
+readonly buffer Joints {
+    mat4 u_joints[MAX_JOINTS];
+};
+
 void main() {
     mat4 model_matrix = mat4(
         model_matrix_0,
@@ -27,10 +46,24 @@ void main() {
         model_matrix_2,
         model_matrix_3
     );
+    // Vertices with no weights aren't skinned
+    if (dot(a_weights, vec4(1.0)) > 0.0) {
+        model_matrix = model_matrix * (
+            a_weights.x * u_joints[a_joints.x] +
+            a_weights.y * u_joints[a_joints.y] +
+            a_weights.z * u_joints[a_joints.z] +
+            a_weights.w * u_joints[a_joints.w]
+        );
+    }
     mat3 normal_matrix = mat3(transpose(inverse(model_matrix)));
 
     v_normal = normal_matrix * a_normal.xyz;
-    v_tex_coords = a_tex_coords;
+    // These run along the surface, so they transform like positions do
+    v_tangent = mat3(model_matrix) * a_tangent;
+    v_bitangent = mat3(model_matrix) * a_bitangent;
+    v_tex_coords = a_tex_coords * i_uv.zw + i_uv.xy;
+    v_tint = i_tint;
+    v_emissive = i_emissive;
     vec4 model_space = model_matrix * vec4(a_position.xyz, 1.0);
     v_position = model_space.xyz;
     gl_Position = u_proj * u_view * model_space;
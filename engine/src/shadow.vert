@@ -0,0 +1,42 @@
+#version 450
+
+// Depth only, from a light's point of view
+
+layout(location=0) in vec3 a_position;
+layout(location=9) in uvec4 a_joints;
+layout(location=10) in vec4 a_weights;
+
+layout(location=5) in vec4 model_matrix_0;
+layout(location=6) in vec4 model_matrix_1;
+layout(location=7) in vec4 model_matrix_2;
+layout(location=8) in vec4 model_matrix_3;
+
+layout(set=0, binding=0)
+uniform ShadowPass {
+    mat4 u_light_view_proj;
+};
+
+// Has to match MAX_JOINTS in animation.rs
+#define MAX_JOINTS 64
+layout(set=1, binding=0)
+readonly buffer Joints {
+    mat4 u_joints[MAX_JOINTS];
+};
+
+void main() {
+    mat4 model_matrix = mat4(
+        model_matrix_0,
+        model_matrix_1,
+        model_matrix_2,
+        model_matrix_3
+    );
+    if (dot(a_weights, vec4(1.0)) > 0.0) {
+        model_matrix = model_matrix * (
+            a_weights.x * u_joints[a_joints.x] +
+            a_weights.y * u_joints[a_joints.y] +
+            a_weights.z * u_joints[a_joints.z] +
+            a_weights.w * u_joints[a_joints.w]
+        );
+    }
+    gl_Position = u_light_view_proj * model_matrix * vec4(a_position, 1.0);
+}
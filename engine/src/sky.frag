@@ -0,0 +1,13 @@
+#version 450
+
+layout(location=0) in vec3 v_direction;
+layout(location=0) out vec4 f_color;
+
+// The same environment map shader.frag lights PBR materials with
+layout(set=1, binding=5) uniform textureCube t_environment;
+layout(set=1, binding=6) uniform sampler s_environment;
+
+void main() {
+    vec3 sky = textureLod(samplerCube(t_environment, s_environment), v_direction, 0.0).rgb;
+    f_color = vec4(sky, 1.0);
+}
@@ -0,0 +1,23 @@
+#version 450
+
+// The background: one triangle over the whole screen at the far plane,
+// drawn after everything opaque so it only fills what's left
+
+layout(location=0) out vec3 v_direction;
+
+layout(set=0, binding=0)
+uniform Uniforms {
+    vec4 u_view_pos;
+    mat4 u_view;
+    mat4 u_proj;
+};
+
+void main() {
+    vec2 uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+    vec2 clip = uv * vec2(2.0, -2.0) + vec2(-1.0, 1.0);
+    // Back through the projection to view space, then rotated into the
+    // world; the view's rotation is orthonormal so transposing inverts it
+    vec4 view_dir = inverse(u_proj) * vec4(clip, 1.0, 1.0);
+    v_direction = transpose(mat3(u_view)) * (view_dir.xyz / view_dir.w);
+    gl_Position = vec4(clip, 1.0, 1.0);
+}
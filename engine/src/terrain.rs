@@ -0,0 +1,470 @@
+use anyhow::*;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::geom::*;
+use crate::model::{Material, Mesh, Model, ModelVertex};
+use crate::texture;
+
+/// A grid of heights, spaced evenly along x and z.  Sample `(i, j)` sits
+/// at `origin + (i * spacing, height, j * spacing)`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Heightfield {
+    // The corner with the smallest x and z; heights are relative to its y
+    pub origin: Pos3,
+    pub spacing: f32,
+    // Number of samples along x and z
+    pub width: usize,
+    pub depth: usize,
+    // Row by row, one row of `width` samples per z
+    pub heights: Vec<f32>,
+}
+
+impl Shape for Heightfield {
+    fn translate(&mut self, v: Vec3) {
+        self.origin += v;
+    }
+    fn type_of(&self) -> &'static str {
+        "Heightfield"
+    }
+}
+
+impl Heightfield {
+    pub fn new(origin: Pos3, spacing: f32, width: usize, depth: usize, heights: Vec<f32>) -> Self {
+        assert!(
+            width >= 2 && depth >= 2,
+            "Heightfield needs at least 2x2 samples"
+        );
+        assert_eq!(heights.len(), width * depth);
+        Self {
+            origin,
+            spacing,
+            width,
+            depth,
+            heights,
+        }
+    }
+
+    /// One sample per pixel; black is `origin.y` and white is `max_height`
+    /// above it.  Colour images are converted to grayscale, and 16-bit
+    /// images keep their precision.
+    pub fn load(
+        path: impl AsRef<Path>,
+        origin: Pos3,
+        spacing: f32,
+        max_height: f32,
+    ) -> Result<Self> {
+        let img = image::open(path.as_ref())
+            .with_context(|| format!("Couldn't load heightmap {:?}", path.as_ref()))?
+            .into_luma16();
+        let (width, depth) = img.dimensions();
+        if width < 2 || depth < 2 {
+            bail!("Heightmap {:?} is smaller than 2x2", path.as_ref());
+        }
+        let heights = img
+            .pixels()
+            .map(|p| p.0[0] as f32 / u16::MAX as f32 * max_height)
+            .collect();
+        Ok(Self::new(
+            origin,
+            spacing,
+            width as usize,
+            depth as usize,
+            heights,
+        ))
+    }
+
+    pub fn height(&self, i: usize, j: usize) -> f32 {
+        self.heights[j * self.width + i]
+    }
+
+    pub fn point(&self, i: usize, j: usize) -> Pos3 {
+        self.origin
+            + Vec3::new(
+                i as f32 * self.spacing,
+                self.height(i, j),
+                j as f32 * self.spacing,
+            )
+    }
+
+    // Which cell (x, z) falls in, and how far across it, if it's on the grid
+    fn cell_at(&self, x: f32, z: f32) -> Option<(usize, usize, f32, f32)> {
+        let u = (x - self.origin.x) / self.spacing;
+        let v = (z - self.origin.z) / self.spacing;
+        let (max_u, max_v) = ((self.width - 1) as f32, (self.depth - 1) as f32);
+        if !(0.0..=max_u).contains(&u) || !(0.0..=max_v).contains(&v) {
+            return None;
+        }
+        // Points on the far edges belong to the last cell
+        let i = (u.floor() as usize).min(self.width - 2);
+        let j = (v.floor() as usize).min(self.depth - 2);
+        Some((i, j, u - i as f32, v - j as f32))
+    }
+
+    /// World-space height of the surface at (x, z), following the same
+    /// triangles as the collider and the mesh
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        let (i, j, fu, fv) = self.cell_at(x, z)?;
+        let h00 = self.height(i, j);
+        let h10 = self.height(i + 1, j);
+        let h01 = self.height(i, j + 1);
+        let h11 = self.height(i + 1, j + 1);
+        let h = if fv >= fu {
+            h00 + fv * (h01 - h00) + fu * (h11 - h01)
+        } else {
+            h00 + fu * (h10 - h00) + fv * (h11 - h10)
+        };
+        Some(self.origin.y + h)
+    }
+
+    /// Smoothed surface normal at sample (i, j), from its neighbours
+    pub fn normal(&self, i: usize, j: usize) -> Vec3 {
+        let (il, ir) = (i.saturating_sub(1), (i + 1).min(self.width - 1));
+        let (jl, jr) = (j.saturating_sub(1), (j + 1).min(self.depth - 1));
+        let dx = (self.height(ir, j) - self.height(il, j)) / ((ir - il) as f32 * self.spacing);
+        let dz = (self.height(i, jr) - self.height(i, jl)) / ((jr - jl) as f32 * self.spacing);
+        Vec3::new(-dx, 1.0, -dz).normalize()
+    }
+
+    // The two triangles of cell (i, j), wound counter-clockwise from above
+    fn triangles(&self, i: usize, j: usize) -> [[Pos3; 3]; 2] {
+        let p00 = self.point(i, j);
+        let p10 = self.point(i + 1, j);
+        let p01 = self.point(i, j + 1);
+        let p11 = self.point(i + 1, j + 1);
+        [[p00, p01, p11], [p00, p11, p10]]
+    }
+
+    // Every triangle whose cell overlaps the xz range [min, max]
+    fn triangles_near(&self, min: Pos3, max: Pos3) -> impl Iterator<Item = [Pos3; 3]> + '_ {
+        let to_cell = |w: f32, o: f32, n: usize| {
+            ((w - o) / self.spacing)
+                .floor()
+                .max(0.0)
+                .min((n - 2) as f32) as usize
+        };
+        let (i0, i1) = (
+            to_cell(min.x, self.origin.x, self.width),
+            to_cell(max.x, self.origin.x, self.width),
+        );
+        let (j0, j1) = (
+            to_cell(min.z, self.origin.z, self.depth),
+            to_cell(max.z, self.origin.z, self.depth),
+        );
+        // Nothing to do if the range misses the grid entirely
+        let x_max = self.origin.x + (self.width - 1) as f32 * self.spacing;
+        let z_max = self.origin.z + (self.depth - 1) as f32 * self.spacing;
+        let hit =
+            max.x >= self.origin.x && min.x <= x_max && max.z >= self.origin.z && min.z <= z_max;
+        let (i1, j1) = if hit { (i1 + 1, j1 + 1) } else { (i0, j0) };
+        (j0..j1)
+            .flat_map(move |j| (i0..i1).map(move |i| (i, j)))
+            .flat_map(move |(i, j)| self.triangles(i, j).to_vec())
+    }
+
+    // The deepest push out of any nearby triangle for a sphere at c
+    fn sphere_push(&self, c: Pos3, r: f32) -> Option<Vec3> {
+        let reach = Vec3::new(r, 0.0, r);
+        self.triangles_near(c - reach, c + reach)
+            .filter_map(|tri| triangle_push(&tri, c, r))
+            .max_by(|a, b| a.magnitude2().partial_cmp(&b.magnitude2()).unwrap())
+    }
+
+    /// Split into square chunks of `chunk_cells` cells on a side, one mesh
+    /// each.  Vertices are in world space, so render the chunks with an
+    /// identity transform.  UVs run from 0 to 1 across the whole field.
+    pub fn chunk_meshes(&self, chunk_cells: usize) -> Vec<(Vec<ModelVertex>, Vec<u32>)> {
+        let chunk_cells = chunk_cells.max(1);
+        let mut chunks = vec![];
+        for cj in (0..self.depth - 1).step_by(chunk_cells) {
+            for ci in (0..self.width - 1).step_by(chunk_cells) {
+                let i_end = (ci + chunk_cells).min(self.width - 1);
+                let j_end = (cj + chunk_cells).min(self.depth - 1);
+                let row = (i_end - ci + 1) as u32;
+                let mut vertices = vec![];
+                for j in cj..=j_end {
+                    for i in ci..=i_end {
+                        vertices.push(ModelVertex::new(
+                            self.point(i, j).into(),
+                            [
+                                i as f32 / (self.width - 1) as f32,
+                                j as f32 / (self.depth - 1) as f32,
+                            ],
+                            self.normal(i, j).into(),
+                        ));
+                    }
+                }
+                let mut indices = vec![];
+                for j in 0..(j_end - cj) as u32 {
+                    for i in 0..(i_end - ci) as u32 {
+                        let v00 = j * row + i;
+                        let (v10, v01, v11) = (v00 + 1, v00 + row, v00 + row + 1);
+                        // Same split as `triangles`
+                        indices.extend_from_slice(&[v00, v01, v11, v00, v11, v10]);
+                    }
+                }
+                chunks.push((vertices, indices));
+            }
+        }
+        chunks
+    }
+
+    /// Build one `Model` per chunk (see `chunk_meshes`), all textured with
+    /// `texture`
+    pub fn chunk_models(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        chunk_cells: usize,
+        texture: impl AsRef<Path>,
+    ) -> Result<Vec<Model>> {
+        let img = image::open(texture.as_ref())?;
+        // Uploaded once; each chunk's material just points at it
+        let diffuse = Rc::new(texture::Texture::from_image(
+            device,
+            queue,
+            &img,
+            texture.as_ref().to_str(),
+        )?);
+        Ok(self
+            .chunk_meshes(chunk_cells)
+            .into_iter()
+            .enumerate()
+            .map(|(n, (vertices, indices))| {
+                let name = format!("terrain chunk {}", n);
+                Model::new(
+                    vec![Mesh::new(device, name.clone(), &vertices, &indices, 0)],
+                    vec![Material::new(
+                        device,
+                        queue,
+                        layout,
+                        name,
+                        diffuse.clone(),
+                        None,
+                    )],
+                )
+            })
+            .collect())
+    }
+}
+
+// Closest point to p on triangle abc.  From Real-Time Collision Detection,
+// section 5.1.5.
+fn closest_on_triangle(p: Pos3, [a, b, c]: &[Pos3; 3]) -> Pos3 {
+    let (a, b, c) = (*a, *b, *c);
+    let ab = b - a;
+    let ac = c - a;
+    let ap = p - a;
+    let d1 = ab.dot(ap);
+    let d2 = ac.dot(ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a;
+    }
+    let bp = p - b;
+    let d3 = ab.dot(bp);
+    let d4 = ac.dot(bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return b;
+    }
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + ab * (d1 / (d1 - d3));
+    }
+    let cp = p - c;
+    let d5 = ab.dot(cp);
+    let d6 = ac.dot(cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return c;
+    }
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + ac * (d2 / (d2 - d6));
+    }
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+    let denom = 1.0 / (va + vb + vc);
+    a + ab * (vb * denom) + ac * (vc * denom)
+}
+
+fn triangle_normal([a, b, c]: &[Pos3; 3]) -> Vec3 {
+    (*b - *a).cross(*c - *a).normalize()
+}
+
+// Push for a sphere at c out of one triangle.  Unlike a lone triangle, the
+// terrain has an underside, so a center below the surface goes up.
+fn triangle_push(tri: &[Pos3; 3], c: Pos3, r: f32) -> Option<Vec3> {
+    let n = triangle_normal(tri);
+    let q = closest_on_triangle(c, tri);
+    let d = c - q;
+    let above = d.dot(n);
+    if above < 0.0 {
+        // Only if we're right under this triangle; otherwise a neighbour
+        // has a better idea of which way is out
+        if (d - n * above).magnitude2() > 0.0001 * 0.0001 {
+            return None;
+        }
+        return Some(n * (r - above));
+    }
+    let dist = d.magnitude();
+    if dist >= r {
+        None
+    } else if dist > 0.0 {
+        Some(d * ((r - dist) / dist))
+    } else {
+        Some(n * r)
+    }
+}
+
+// The point on segment ab that's nearest to p
+fn closest_on_segment(p: Pos3, a: Pos3, b: Pos3) -> Pos3 {
+    let ab = b - a;
+    let len2 = ab.magnitude2();
+    if len2 == 0.0 {
+        return a;
+    }
+    a + ab * ((p - a).dot(ab) / len2).clamp(0.0, 1.0)
+}
+
+// Like Sphere-Plane, these give the push for the sphere or capsule
+impl Collide<Heightfield> for Sphere {
+    fn disp(&self, h: &Heightfield) -> Option<Vec3> {
+        h.sphere_push(self.c, self.r)
+    }
+}
+
+impl Collide<Heightfield> for Capsule {
+    fn disp(&self, h: &Heightfield) -> Option<Vec3> {
+        let reach = Vec3::new(self.r, 0.0, self.r);
+        let lo = Pos3::new(self.a.x.min(self.b.x), 0.0, self.a.z.min(self.b.z)) - reach;
+        let hi = Pos3::new(self.a.x.max(self.b.x), 0.0, self.a.z.max(self.b.z)) + reach;
+        let axis = self.b - self.a;
+        // For each triangle, test the sphere on the capsule's segment that's
+        // closest to it: find where the segment's line meets the triangle's
+        // plane, pull that onto the triangle, then back onto the segment.
+        let reference = |tri: &[Pos3; 3]| {
+            let n = triangle_normal(tri);
+            let denom = n.dot(axis);
+            let on_plane = if denom.abs() < f32::EPSILON {
+                self.a
+            } else {
+                self.a + axis * (n.dot(tri[0] - self.a) / denom)
+            };
+            closest_on_segment(closest_on_triangle(on_plane, tri), self.a, self.b)
+        };
+        h.triangles_near(lo, hi)
+            .filter_map(|tri| triangle_push(&tri, reference(&tri), self.r))
+            .max_by(|a, b| a.magnitude2().partial_cmp(&b.magnitude2()).unwrap())
+    }
+}
+
+// Ray-triangle intersection, Möller-Trumbore style
+fn ray_triangle(r: &Ray, [a, b, c]: &[Pos3; 3]) -> Option<f32> {
+    let e1 = *b - *a;
+    let e2 = *c - *a;
+    let p = r.dir.cross(e2);
+    let det = e1.dot(p);
+    if det.abs() < f32::EPSILON {
+        return None;
+    }
+    let inv = 1.0 / det;
+    let s = r.p - *a;
+    let u = s.dot(p) * inv;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = s.cross(e1);
+    let v = r.dir.dot(q) * inv;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = e2.dot(q) * inv;
+    if t >= 0.0 {
+        Some(t)
+    } else {
+        None
+    }
+}
+
+impl Cast<Heightfield> for Ray {
+    fn cast(&self, h: &Heightfield) -> CastHit {
+        // Clip the ray to the grid's footprint on the xz plane
+        let size = [
+            (h.width - 1) as f32 * h.spacing,
+            (h.depth - 1) as f32 * h.spacing,
+        ];
+        let (start, dir) = ([self.p.x, self.p.z], [self.dir.x, self.dir.z]);
+        let origin = [h.origin.x, h.origin.z];
+        let (mut tmin, mut tmax) = (0.0_f32, f32::MAX);
+        for k in 0..2 {
+            if dir[k].abs() < f32::EPSILON {
+                if start[k] < origin[k] || start[k] > origin[k] + size[k] {
+                    return None;
+                }
+                continue;
+            }
+            let t1 = (origin[k] - start[k]) / dir[k];
+            let t2 = (origin[k] + size[k] - start[k]) / dir[k];
+            tmin = tmin.max(t1.min(t2));
+            tmax = tmax.min(t1.max(t2));
+        }
+        if tmin > tmax {
+            return None;
+        }
+        // Then walk the cells it passes over in order, checking each one's
+        // triangles (Amanatides & Woo)
+        let entry = self.p + self.dir * tmin;
+        let (mut i, mut j, _, _) = h.cell_at(
+            entry.x.max(h.origin.x).min(h.origin.x + size[0]),
+            entry.z.max(h.origin.z).min(h.origin.z + size[1]),
+        )?;
+        let step = |d: f32| if d > 0.0 { 1 } else { -1 };
+        let (step_i, step_j) = (step(self.dir.x), step(self.dir.z));
+        // Ray t at which we cross into the next cell along each axis
+        let next_t = |cell: usize, s: i32, o: f32, p: f32, d: f32| {
+            if d.abs() < f32::EPSILON {
+                f32::MAX
+            } else {
+                let edge = o + (cell as f32 + if s > 0 { 1.0 } else { 0.0 }) * h.spacing;
+                (edge - p) / d
+            }
+        };
+        let delta = |d: f32| {
+            if d.abs() < f32::EPSILON {
+                f32::MAX
+            } else {
+                h.spacing / d.abs()
+            }
+        };
+        let mut t_i = next_t(i, step_i, h.origin.x, self.p.x, self.dir.x);
+        let mut t_j = next_t(j, step_j, h.origin.z, self.p.z, self.dir.z);
+        let (dt_i, dt_j) = (delta(self.dir.x), delta(self.dir.z));
+        loop {
+            let hit = h
+                .triangles(i, j)
+                .iter()
+                .filter_map(|tri| ray_triangle(self, tri))
+                .min_by(|a, b| a.partial_cmp(b).unwrap());
+            if let Some(t) = hit {
+                return Some((self.p + self.dir * t, t));
+            }
+            if t_i < t_j {
+                let ni = i as i32 + step_i;
+                if ni < 0 || ni as usize >= h.width - 1 {
+                    return None;
+                }
+                i = ni as usize;
+                t_i += dt_i;
+            } else {
+                let nj = j as i32 + step_j;
+                if nj < 0 || nj as usize >= h.depth - 1 {
+                    return None;
+                }
+                j = nj as usize;
+                t_j += dt_j;
+            }
+        }
+    }
+}
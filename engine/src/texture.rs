@@ -4,6 +4,81 @@ use std::path::Path;
 
 use crate::render::Rect;
 
+/// Linear colour that can go past 1, e.g. from a .hdr file
+pub type HdrImage = image::ImageBuffer<image::Rgba<f32>, Vec<f32>>;
+
+/// How a texture gets sampled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SamplerSettings {
+    pub address_mode_u: wgpu::AddressMode,
+    pub address_mode_v: wgpu::AddressMode,
+    pub mag_filter: wgpu::FilterMode,
+    pub min_filter: wgpu::FilterMode,
+    pub mipmap_filter: wgpu::FilterMode,
+    // Most samples to take at steep angles, from 1 to 16.  Only applies if
+    // all the filters are Linear, and quietly does nothing on adapters that
+    // can't do it.
+    pub anisotropy: u8,
+}
+
+impl Default for SamplerSettings {
+    // Tiling, and as smooth as it gets
+    fn default() -> Self {
+        Self {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            anisotropy: 16,
+        }
+    }
+}
+
+impl SamplerSettings {
+    pub fn with_address_mode(self, mode: wgpu::AddressMode) -> Self {
+        Self {
+            address_mode_u: mode,
+            address_mode_v: mode,
+            ..self
+        }
+    }
+    pub fn with_filter(self, filter: wgpu::FilterMode) -> Self {
+        Self {
+            mag_filter: filter,
+            min_filter: filter,
+            mipmap_filter: filter,
+            ..self
+        }
+    }
+
+    fn create_sampler(&self, device: &wgpu::Device, label: Option<&str>) -> wgpu::Sampler {
+        let linear = [self.mag_filter, self.min_filter, self.mipmap_filter]
+            .iter()
+            .all(|f| *f == wgpu::FilterMode::Linear);
+        // Rounded down to a power of two, which it has to be
+        let anisotropy = match self.anisotropy {
+            0..=1 => 1,
+            n => (n.min(16) + 1).next_power_of_two() / 2,
+        };
+        device.create_sampler(&wgpu::SamplerDescriptor {
+            label,
+            address_mode_u: self.address_mode_u,
+            address_mode_v: self.address_mode_v,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: self.mag_filter,
+            min_filter: self.min_filter,
+            mipmap_filter: self.mipmap_filter,
+            anisotropy_clamp: if linear && anisotropy > 1 {
+                std::num::NonZeroU8::new(anisotropy)
+            } else {
+                None
+            },
+            ..Default::default()
+        })
+    }
+}
+
 pub struct Texture {
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
@@ -27,21 +102,205 @@ impl Texture {
         Self::from_image(device, queue, &img, label)
     }
 
+    /// Like `load`, but the texels are directions rather than colors, so
+    /// they aren't gamma corrected
+    pub fn load_normal_map<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: P,
+    ) -> Result<Self> {
+        let path_copy = path.as_ref().to_path_buf();
+        let label = path_copy.to_str();
+
+        let img = image::open(path)?;
+        Self::from_image_with_format(device, queue, &img, label, wgpu::TextureFormat::Rgba8Unorm)
+    }
+
+    /// A 1x1 normal map that leaves normals alone
+    pub fn flat_normal_map(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        Self::solid(device, queue, [128, 128, 255, 255], "flat_normal_map")
+    }
+
+    /// A 1x1 texture of one color, taken as is without gamma correction
+    pub fn solid(device: &wgpu::Device, queue: &wgpu::Queue, rgba: [u8; 4], label: &str) -> Self {
+        let img =
+            image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba(rgba)));
+        Self::from_image_with_format(
+            device,
+            queue,
+            &img,
+            Some(label),
+            wgpu::TextureFormat::Rgba8Unorm,
+        )
+        .unwrap()
+    }
+
+    /// A cube map from six square faces of the same size, in the order +x,
+    /// -x, +y, -y, +z, -z, with the mip chain filled in.  Meant for
+    /// environment lighting, where rougher surfaces sample blurrier mips.
+    pub fn cube_from_faces(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        faces: &[image::RgbaImage],
+        label: Option<&str>,
+    ) -> Result<Self> {
+        ensure!(
+            faces.len() == 6,
+            "a cube map needs 6 faces, not {}",
+            faces.len()
+        );
+        let (w, h) = faces[0].dimensions();
+        ensure!(
+            w == h && faces.iter().all(|f| f.dimensions() == (w, h)),
+            "cube map faces have to be square and all the same size"
+        );
+        let mip_level_count = mip_levels(w);
+        let texture = create_cube(
+            device,
+            w,
+            mip_level_count,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            label,
+        );
+        for (layer, face) in faces.iter().enumerate() {
+            write_mips(queue, &texture, layer as u32, face, mip_level_count);
+        }
+        Ok(Self::from_cube(device, texture, w, label))
+    }
+
+    /// Like `cube_from_faces`, but keeps colours brighter than white
+    pub fn hdr_cube_from_faces(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        faces: &[HdrImage],
+        label: Option<&str>,
+    ) -> Result<Self> {
+        ensure!(
+            faces.len() == 6,
+            "a cube map needs 6 faces, not {}",
+            faces.len()
+        );
+        let (w, h) = faces[0].dimensions();
+        ensure!(
+            w == h && faces.iter().all(|f| f.dimensions() == (w, h)),
+            "cube map faces have to be square and all the same size"
+        );
+        let mip_level_count = mip_levels(w);
+        let texture = create_cube(
+            device,
+            w,
+            mip_level_count,
+            wgpu::TextureFormat::Rgba16Float,
+            label,
+        );
+        for (layer, face) in faces.iter().enumerate() {
+            write_hdr_mips(queue, &texture, layer as u32, face, mip_level_count);
+        }
+        Ok(Self::from_cube(device, texture, w, label))
+    }
+
+    /// An HDR cube map from a panorama spanning 360 degrees across and 180
+    /// up and down, the usual layout for downloaded environment maps
+    pub fn cube_from_equirect(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &HdrImage,
+        label: Option<&str>,
+    ) -> Result<Self> {
+        let (w, h) = img.dimensions();
+        ensure!(w > 0 && h > 0, "environment panorama is empty");
+        // About as many texels around the equator as the panorama has
+        let size = (w / 4).max(1).next_power_of_two().min(2048);
+        let faces: Vec<HdrImage> = (0..6)
+            .map(|face| {
+                HdrImage::from_fn(size, size, |x, y| {
+                    let [dx, dy, dz] = cube_direction(face, x, y, size);
+                    let len = (dx * dx + dy * dy + dz * dz).sqrt();
+                    let u = 0.5 + dz.atan2(dx) / (2.0 * std::f32::consts::PI);
+                    let v = (dy / len).acos() / std::f32::consts::PI;
+                    sample_bilinear(img, u, v)
+                })
+            })
+            .collect();
+        Self::hdr_cube_from_faces(device, queue, &faces, label)
+    }
+
+    // Views and sampler for a cube made by `create_cube`
+    fn from_cube(
+        device: &wgpu::Device,
+        texture: wgpu::Texture,
+        size: u32,
+        label: Option<&str>,
+    ) -> Self {
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            label,
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        Self {
+            texture,
+            view,
+            sampler,
+            size: (size as usize, size as usize),
+        }
+    }
+
+    /// A cube map shading from `ground` below to `horizon` to `sky` above,
+    /// for when there's no real environment to light things with
+    pub fn gradient_cube(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        sky: [u8; 3],
+        horizon: [u8; 3],
+        ground: [u8; 3],
+    ) -> Self {
+        const SIZE: u32 = 32;
+        let mix = |a: [u8; 3], b: [u8; 3], t: f32| {
+            let c = |i: usize| (a[i] as f32 + (b[i] as f32 - a[i] as f32) * t) as u8;
+            image::Rgba([c(0), c(1), c(2), 255])
+        };
+        let faces: Vec<image::RgbaImage> = (0..6)
+            .map(|face| {
+                image::RgbaImage::from_fn(SIZE, SIZE, |x, y| {
+                    let dir = cube_direction(face, x, y, SIZE);
+                    let up = dir[1] / (dir[0] * dir[0] + dir[1] * dir[1] + dir[2] * dir[2]).sqrt();
+                    if up >= 0.0 {
+                        mix(horizon, sky, up.sqrt())
+                    } else {
+                        mix(horizon, ground, (-up * 4.0).min(1.0))
+                    }
+                })
+            })
+            .collect();
+        Self::cube_from_faces(device, queue, &faces, Some("gradient_cube")).unwrap()
+    }
+
     pub fn create_depth_texture(
         device: &wgpu::Device,
-        sc_desc: &wgpu::SwapChainDescriptor,
+        width: u32,
+        height: u32,
+        sample_count: u32,
         label: &str,
     ) -> Self {
         let size = wgpu::Extent3d {
-            width: sc_desc.width,
-            height: sc_desc.height,
+            width,
+            height,
             depth: 1,
         };
         let desc = wgpu::TextureDescriptor {
             label: Some(label),
             size,
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count,
             dimension: wgpu::TextureDimension::D2,
             format: Self::DEPTH_FORMAT,
             usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
@@ -65,7 +324,7 @@ impl Texture {
             texture,
             view,
             sampler,
-            size: (sc_desc.width as usize, sc_desc.height as usize),
+            size: (width as usize, height as usize),
         }
     }
 
@@ -85,6 +344,22 @@ impl Texture {
         queue: &wgpu::Queue,
         img: &image::DynamicImage,
         label: Option<&str>,
+    ) -> Result<Self> {
+        Self::from_image_with_format(
+            device,
+            queue,
+            img,
+            label,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+        )
+    }
+
+    pub(crate) fn from_image_with_format(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &image::DynamicImage,
+        label: Option<&str>,
+        format: wgpu::TextureFormat,
     ) -> Result<Self> {
         let dimensions = img.dimensions();
         let rgba = img.to_rgba8();
@@ -94,41 +369,20 @@ impl Texture {
             height: dimensions.1,
             depth: 1,
         };
+        let mip_level_count = mip_levels(dimensions.0.max(dimensions.1));
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label,
             size,
-            mip_level_count: 1,
+            mip_level_count,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            format,
             usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
         });
-
-        queue.write_texture(
-            wgpu::TextureCopyView {
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-            },
-            &rgba,
-            wgpu::TextureDataLayout {
-                offset: 0,
-                bytes_per_row: 4 * dimensions.0,
-                rows_per_image: dimensions.1,
-            },
-            size,
-        );
+        write_mips(queue, &texture, 0, &rgba, mip_level_count);
 
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
-            ..Default::default()
-        });
+        let sampler = SamplerSettings::default().create_sampler(device, label);
 
         Ok(Self {
             texture,
@@ -138,6 +392,14 @@ impl Texture {
         })
     }
 
+    /// Swap in a sampler made from `settings`
+    pub fn with_sampler(self, device: &wgpu::Device, settings: &SamplerSettings) -> Self {
+        Self {
+            sampler: settings.create_sampler(device, None),
+            ..self
+        }
+    }
+
     pub fn size(&self) -> (usize, usize) {
         self.size
     }
@@ -149,3 +411,222 @@ impl Texture {
             && (frame.y + frame.h as i32) <= (self.size.1 as i32)
     }
 }
+
+/// Load an image as linear colour: .hdr files as they are, anything else
+/// decoded from sRGB
+pub fn load_hdr(path: impl AsRef<Path>) -> Result<HdrImage> {
+    let path = path.as_ref();
+    let is_hdr = match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => ext.eq_ignore_ascii_case("hdr"),
+        None => false,
+    };
+    if is_hdr {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let decoder = image::codecs::hdr::HdrDecoder::new(file)?;
+        let meta = decoder.metadata();
+        let data: Vec<f32> = decoder
+            .read_image_hdr()?
+            .into_iter()
+            .flat_map(|p| vec![p[0], p[1], p[2], 1.0])
+            .collect();
+        return HdrImage::from_raw(meta.width, meta.height, data)
+            .context("HDR image is the wrong size");
+    }
+    let img = image::open(path)?.to_rgba8();
+    let linear = |c: u8| {
+        let c = c as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    Ok(HdrImage::from_fn(img.width(), img.height(), |x, y| {
+        let p = img.get_pixel(x, y);
+        image::Rgba([
+            linear(p[0]),
+            linear(p[1]),
+            linear(p[2]),
+            p[3] as f32 / 255.0,
+        ])
+    }))
+}
+
+// Which way texel (x, y) of a cube face points, in wgpu's face order.  Not
+// normalized.
+fn cube_direction(face: u32, x: u32, y: u32, size: u32) -> [f32; 3] {
+    // Texel centers from -1 to 1 across the face
+    let u = (x as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+    let v = (y as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+    match face {
+        0 => [1.0, -v, -u],
+        1 => [-1.0, -v, u],
+        2 => [u, 1.0, v],
+        3 => [u, -1.0, -v],
+        4 => [u, -v, 1.0],
+        _ => [-u, -v, -1.0],
+    }
+}
+
+// `u` wraps around, `v` is clamped
+fn sample_bilinear(img: &HdrImage, u: f32, v: f32) -> image::Rgba<f32> {
+    let (w, h) = img.dimensions();
+    let x = u * w as f32 - 0.5;
+    let y = (v * h as f32 - 0.5).max(0.0).min(h as f32 - 1.0);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (x - x0, y - y0);
+    let px = |x: f32, y: f32| {
+        let x = (x as i64).rem_euclid(w as i64) as u32;
+        let y = (y as u32).min(h - 1);
+        img.get_pixel(x, y).0
+    };
+    let (a, b) = (px(x0, y0), px(x0 + 1.0, y0));
+    let (c, d) = (px(x0, y0 + 1.0), px(x0 + 1.0, y0 + 1.0));
+    let mut out = [0.0; 4];
+    for i in 0..4 {
+        let top = a[i] + (b[i] - a[i]) * tx;
+        let bottom = c[i] + (d[i] - c[i]) * tx;
+        out[i] = top + (bottom - top) * ty;
+    }
+    image::Rgba(out)
+}
+
+fn create_cube(
+    device: &wgpu::Device,
+    size: u32,
+    mip_level_count: u32,
+    format: wgpu::TextureFormat,
+    label: Option<&str>,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label,
+        size: wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth: 6,
+        },
+        mip_level_count,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+    })
+}
+
+// Enough mip levels to get down to 1x1
+fn mip_levels(size: u32) -> u32 {
+    32 - size.max(1).leading_zeros()
+}
+
+// Fill in `levels` mips of one layer of `texture`, shrinking `img` on the
+// CPU for each.  Done in whatever space the texels are in, which is a bit
+// dark for sRGB, but close enough.
+fn write_mips(
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+    layer: u32,
+    img: &image::RgbaImage,
+    levels: u32,
+) {
+    let (w, h) = img.dimensions();
+    for level in 0..levels {
+        let (mw, mh) = ((w >> level).max(1), (h >> level).max(1));
+        let resized;
+        let mip = if level == 0 {
+            img
+        } else {
+            resized = image::imageops::resize(img, mw, mh, image::imageops::FilterType::Triangle);
+            &resized
+        };
+        queue.write_texture(
+            wgpu::TextureCopyView {
+                texture,
+                mip_level: level,
+                origin: wgpu::Origin3d {
+                    x: 0,
+                    y: 0,
+                    z: layer,
+                },
+            },
+            mip,
+            wgpu::TextureDataLayout {
+                offset: 0,
+                bytes_per_row: 4 * mw,
+                rows_per_image: mh,
+            },
+            wgpu::Extent3d {
+                width: mw,
+                height: mh,
+                depth: 1,
+            },
+        );
+    }
+}
+
+// Like `write_mips`, for Rgba16Float textures
+fn write_hdr_mips(
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+    layer: u32,
+    img: &HdrImage,
+    levels: u32,
+) {
+    let (w, h) = img.dimensions();
+    for level in 0..levels {
+        let (mw, mh) = ((w >> level).max(1), (h >> level).max(1));
+        let resized;
+        let mip = if level == 0 {
+            img
+        } else {
+            resized = image::imageops::resize(img, mw, mh, image::imageops::FilterType::Triangle);
+            &resized
+        };
+        let halves: Vec<u16> = mip.as_raw().iter().map(|&c| f16_bits(c)).collect();
+        queue.write_texture(
+            wgpu::TextureCopyView {
+                texture,
+                mip_level: level,
+                origin: wgpu::Origin3d {
+                    x: 0,
+                    y: 0,
+                    z: layer,
+                },
+            },
+            bytemuck::cast_slice(&halves),
+            wgpu::TextureDataLayout {
+                offset: 0,
+                bytes_per_row: 8 * mw,
+                rows_per_image: mh,
+            },
+            wgpu::Extent3d {
+                width: mw,
+                height: mh,
+                depth: 1,
+            },
+        );
+    }
+}
+
+// The nearest half float, with anything too big for one going to infinity
+fn f16_bits(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    if x.is_nan() {
+        return sign | 0x7e00;
+    }
+    let exp = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x7f_ffff;
+    if exp >= 31 {
+        return sign | 0x7c00;
+    }
+    if exp <= 0 {
+        // Too small for a normal half; shift the implicit 1 down instead
+        if exp < -10 {
+            return sign;
+        }
+        let m = (mantissa | 0x80_0000) >> (1 - exp);
+        return sign | ((m + 0x1000) >> 13) as u16;
+    }
+    // Rounding can carry into the exponent, which is what we want
+    sign | ((((exp as u32) << 10) + ((mantissa + 0x1000) >> 13)) as u16)
+}
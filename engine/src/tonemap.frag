@@ -0,0 +1,37 @@
+#version 450
+
+// Last post pass: adds bloom, exposes, squashes HDR down to the 0..1 the
+// display takes, and adjusts gamma
+
+layout(location=0) in vec2 v_uv;
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_scene;
+layout(set=0, binding=1) uniform sampler s_scene;
+layout(set=0, binding=2) uniform texture2D t_bloom;
+layout(set=0, binding=3) uniform sampler s_bloom;
+layout(set=0, binding=4)
+uniform Tonemap {
+    // exposure, bloom intensity, curve (0 none, 1 Reinhard, 2 ACES), gamma
+    // exponent
+    vec4 u_tonemap;
+};
+
+// Narkowicz's fit of the ACES filmic curve
+vec3 aces(vec3 x) {
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+}
+
+void main() {
+    vec3 scene = texture(sampler2D(t_scene, s_scene), v_uv).rgb;
+    vec3 bloom = texture(sampler2D(t_bloom, s_bloom), v_uv).rgb;
+    vec3 color = (scene + bloom * u_tonemap.y) * u_tonemap.x;
+    if (u_tonemap.z > 1.5) {
+        color = aces(color);
+    } else if (u_tonemap.z > 0.5) {
+        color = color / (1.0 + color);
+    } else {
+        color = clamp(color, 0.0, 1.0);
+    }
+    f_color = vec4(pow(color, vec3(u_tonemap.w)), 1.0);
+}
@@ -1,7 +1,9 @@
 use crate::components::*;
+use crate::determinism::{StateHash, StateHasher};
 use std::{
     cell::{RefCell, RefMut},
-    collections::HashMap,
+    collections::BTreeMap,
+    hash::Hasher,
 };
 
 // Implementation based on:
@@ -17,7 +19,7 @@ extern crate savefile_derive;
 pub struct World {
     num_entities: usize,
     components: Vec<Box<dyn ComponentStorage>>, // VECS ONLY
-    components_sparse: Vec<Box<dyn ComponentStorage>>, // HASHMAPS ONLY
+    components_sparse: Vec<Box<dyn ComponentStorage>>, // MAPS ONLY
 }
 
 impl World {
@@ -52,7 +54,7 @@ impl World {
                 for component_map in self.components_sparse.iter_mut() {
                     if let Some(component_map) = component_map
                         .as_any_mut()
-                        .downcast_mut::<RefCell<HashMap<usize, ComponentType>>>()
+                        .downcast_mut::<RefCell<BTreeMap<usize, ComponentType>>>()
                     {
                         component_map.get_mut().insert(id, c);
                         return;
@@ -60,7 +62,7 @@ impl World {
                 }
 
                 // if component map doesn't exist, create it
-                let mut new_component_map: HashMap<usize, ComponentType> = HashMap::new();
+                let mut new_component_map: BTreeMap<usize, ComponentType> = BTreeMap::new();
                 new_component_map.insert(id, c);
                 self.components_sparse
                     .push(Box::new(RefCell::new(new_component_map)));
@@ -103,7 +105,7 @@ impl World {
         for component_map in self.components_sparse.iter_mut() {
             if let Some(component_map) = component_map
                 .as_any_mut()
-                .downcast_mut::<RefCell<HashMap<usize, ComponentType>>>()
+                .downcast_mut::<RefCell<BTreeMap<usize, ComponentType>>>()
             {
                 component_map.get_mut().remove(&id);
             }
@@ -129,11 +131,11 @@ impl World {
     // get a component map
     pub fn borrow_components_sparse_mut<ComponentType: 'static>(
         &self,
-    ) -> Option<RefMut<HashMap<usize, ComponentType>>> {
+    ) -> Option<RefMut<BTreeMap<usize, ComponentType>>> {
         for component_map in self.components_sparse.iter() {
             if let Some(component_map) = component_map
                 .as_any()
-                .downcast_ref::<RefCell<HashMap<usize, ComponentType>>>()
+                .downcast_ref::<RefCell<BTreeMap<usize, ComponentType>>>()
             {
                 return Some(component_map.borrow_mut());
             }
@@ -148,3 +150,17 @@ impl World {
         self.num_entities = 0;
     }
 }
+
+// Storages are hashed in the order they were created, which is the same
+// every run as long as entities are set up the same way
+impl StateHash for World {
+    fn hash_state(&self, h: &mut StateHasher) {
+        h.write_usize(self.num_entities);
+        for storage in self.components.iter() {
+            storage.hash_state(h);
+        }
+        for storage in self.components_sparse.iter() {
+            storage.hash_state(h);
+        }
+    }
+}
@@ -6,6 +6,7 @@ use engine3d::{
     camera_control::CameraController,
     collision,
     components::Component,
+    determinism::{StateHash, StateHasher},
     events::*,
     geom::*,
     lights::Light,
@@ -36,6 +37,9 @@ const PLANE_ROT_SPEED: f32 = 0.6;
 const NUM_MARBLES: usize = 10;
 
 const DEPTH: usize = 4;
+// How high the spotlight hangs over the target marble, and how bright it is
+const SPOT_HEIGHT: f32 = 4.0;
+const SPOT_INTENSITY: f32 = 20.0;
 
 
 // All components that are "sparse" are stored in hashmaps
@@ -45,12 +49,18 @@ impl Component for BodyPlane {
     fn is_sparse(&self) -> bool {
         false
     }
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.0.hash_state(h);
+    }
 }
 pub struct BodySphere(Sphere);
 impl Component for BodySphere {
     fn is_sparse(&self) -> bool {
         true
     }
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.0.hash_state(h);
+    }
 }
 
 pub struct EndSphere(Sphere);
@@ -58,6 +68,9 @@ impl Component for EndSphere {
     fn is_sparse(&self) -> bool {
         true
     }
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.0.hash_state(h);
+    }
 }
 
 pub struct Velocity(Vec3);
@@ -65,12 +78,18 @@ impl Component for Velocity {
     fn is_sparse(&self) -> bool {
         true
     }
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.0.hash_state(h);
+    }
 }
 pub struct Rot(Quaternion<f32>);
 impl Component for Rot {
     fn is_sparse(&self) -> bool {
         false
     }
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.0.hash_state(h);
+    }
 }
 
 pub struct Acceleration(Vec3);
@@ -78,6 +97,9 @@ impl Component for Acceleration {
     fn is_sparse(&self) -> bool {
         true
     }
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.0.hash_state(h);
+    }
 }
 
 pub struct Omega(Vec3);
@@ -85,6 +107,9 @@ impl Component for Omega {
     fn is_sparse(&self) -> bool {
         false
     }
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.0.hash_state(h);
+    }
 }
 
 pub struct Control((i8, i8));
@@ -92,6 +117,9 @@ impl Component for Control {
     fn is_sparse(&self) -> bool {
         false
     }
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.0.hash_state(h);
+    }
 }
 
 pub struct Model(engine3d::assets::ModelRef);
@@ -106,12 +134,18 @@ impl Component for LinearMomentum {
     fn is_sparse(&self) -> bool {
         true
     }
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.0.hash_state(h);
+    }
 }
 pub struct Mass(f32);
 impl Component for Mass {
     fn is_sparse(&self) -> bool {
         true
     }
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.0.hash_state(h);
+    }
 }
 
 
@@ -227,8 +261,13 @@ impl engine3d::Game for Game {
         let player_model = engine.load_model("sphere.obj");
         let end_model = engine.load_model("sphere_white.obj");
 
+        // Set GAME_SEED to get the same marbles (and the same run, given the
+        // same inputs) every time
+        if let Some(seed) = std::env::var("GAME_SEED").ok().and_then(|s| s.parse().ok()) {
+            engine.set_deterministic(seed);
+        }
         use rand::Rng;
-        let mut rng = rand::thread_rng();
+        let rng = &mut engine.rng;
         let mut target = 0;
         for _ in 0..NUM_MARBLES {
             let end_sphere = world.add_entity();
@@ -257,7 +296,7 @@ impl engine3d::Game for Game {
         world.add_component(player, Model(player_model));
 
 
-        engine.set_ambient(0.05);
+        engine.set_ambient(0.15);
         let light = Light::spot(Pos3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Vec3::new(1.0, 1.0, 1.0));
 
         let game_save = GameSave {
@@ -295,6 +334,17 @@ impl engine3d::Game for Game {
         )
     }
 
+    fn hash_state(&self, h: &mut StateHasher) {
+        self.gamesave.world.hash_state(h);
+        self.gamesave.target.hash_state(h);
+        match self.mode {
+            Mode::Title => 0_u8.hash_state(h),
+            Mode::Play(live) => (1_u8, live).hash_state(h),
+            Mode::Options => 2_u8.hash_state(h),
+            Mode::EndGame => 3_u8.hash_state(h),
+        }
+    }
+
     // Returns true if rendering in 2d, false otherwise
     fn render(
         &mut self,
@@ -475,27 +525,30 @@ impl engine3d::Game for Game {
                 // render spheres
                 for (id, body) in spheres.iter() {
                     if let Some(rot) = &rots[*id] {
-                        let ir = engine3d::render::InstanceRaw {
-                            model: (Mat4::from_translation(body.0.c.to_vec())
+                        let ir = engine3d::render::InstanceRaw::new(
+                            Mat4::from_translation(body.0.c.to_vec())
                                 * Mat4::from_scale(body.0.r)
-                                * Mat4::from(rot.0))
-                            .into(),
-                        };
+                                * Mat4::from(rot.0),
+                        );
                         if let Some(model) = &models[*id] {
                             igs.render(model.0, ir);
                         }
                     }
                 }
 
-                // end spheres
+                // end spheres; the target glows green
                 for (id, body) in end_objects.iter() {
                     if let Some(rot) = &rots[*id] {
-                        let ir = engine3d::render::InstanceRaw {
-                            model: (Mat4::from_translation(body.0.c.to_vec())
+                        let mut ir = engine3d::render::InstanceRaw::new(
+                            Mat4::from_translation(body.0.c.to_vec())
                                 * Mat4::from_scale(body.0.r)
-                                * Mat4::from(rot.0))
-                            .into(),
-                        };
+                                * Mat4::from(rot.0),
+                        );
+                        if *id == self.gamesave.target {
+                            ir = ir
+                                .with_tint([0.3, 1.0, 0.3, 1.0])
+                                .with_emissive([0.0, 0.4, 0.0]);
+                        }
                         if let Some(model) = &models[*id] {
                             igs.render(model.0, ir);
                         }
@@ -506,14 +559,13 @@ impl engine3d::Game for Game {
                 for (id, body) in planes.iter().enumerate() {
                     if let Some(body) = body {
 
-                        let ir = engine3d::render::InstanceRaw {
-                            model: (Mat4::from_translation(body.0.n * body.0.d) * Mat4::from(cgmath::Quaternion::between_vectors(
+                        let ir = engine3d::render::InstanceRaw::new(
+                            Mat4::from_translation(body.0.n * body.0.d) * Mat4::from(cgmath::Quaternion::between_vectors(
                                 Vec3::new(0.0, 1.0, 0.0),
                                 body.0.n,
                             ))
-                                * Mat4::from_nonuniform_scale(0.5, 0.05, 0.5))
-                            .into(),
-                        };
+                                * Mat4::from_nonuniform_scale(0.5, 0.05, 0.5),
+                        );
                         if let Some(model) = &models[id] {
                             igs.render(model.0, ir);
                         }
@@ -678,13 +730,12 @@ impl engine3d::Game for Game {
                 }
                 collision::gather_contacts_ab(&pb, &target, &mut self.pe);
                 use rand::Rng;
-                let mut rng = rand::thread_rng();
                 if self.pe.len() > 0 {
                     end_spheres.remove(&self.gamesave.target);
                     if end_ids.len() == 0 {
                         self.mode = Mode::EndGame;
                     } else {
-                        self.gamesave.target = end_ids[rng.gen_range(0..end_ids.len())];
+                        self.gamesave.target = end_ids[engine.rng.gen_range(0..end_ids.len())];
                     }
                     target.clear();
                 } else {
@@ -788,7 +839,7 @@ impl engine3d::Game for Game {
                     Mode::Play(_live) => {
                         let target_r = end_spheres.get_mut(&self.gamesave.target).unwrap().0.r;
                     let target_pos = end_spheres.get_mut(&self.gamesave.target).unwrap().0.c;
-                    let light_pos = Pos3::new(target_pos.x, target_pos.y + target_r + 0.5, target_pos.z);
+                    let light_pos = Pos3::new(target_pos.x, target_pos.y + target_r + SPOT_HEIGHT, target_pos.z);
                     let light_pos = if engine.events.key_held(KeyCode::A) {
                         Quat::from(cgmath::Euler::new(
                             cgmath::Deg(0.0),
@@ -806,8 +857,15 @@ impl engine3d::Game for Game {
                     } else {
                         light_pos
                     };
-                    self.gamesave.light = Light::point(light_pos, self.gamesave.light.color());
-                    engine.set_lights(vec![self.gamesave.light]);
+                    // Shining straight down on the target
+                    self.gamesave.light = Light::spot(light_pos, Vec3::unit_y(), self.gamesave.light.color())
+                        .with_cone(0.2, 0.35)
+                        .with_range(SPOT_HEIGHT * 3.0)
+                        .with_intensity(SPOT_INTENSITY);
+                    // A dim sun overhead, so marbles sit on their shadows
+                    let sun = Light::directed(Vec3::new(0.3, 1.0, 0.2), Vec3::new(0.3, 0.3, 0.3))
+                        .with_shadows(0.0005, 0.05);
+                    engine.set_lights(vec![self.gamesave.light, sun]);
                     },
                     _ => (),
                 }